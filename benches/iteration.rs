@@ -0,0 +1,69 @@
+//! Compare external iteration with `next` to the internal iteration of `fold`.
+//!
+//! Walks a generated tree, or the directory given as the first argument, several times and
+//! reports the fastest and the median run of each way. Run with `cargo bench`.
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{env, fs, process};
+
+use sprint_dir::WalkDir;
+
+const RUNS: usize = 25;
+const DIRS: usize = 100;
+const FILES: usize = 1000;
+
+fn main() {
+    // `cargo bench` passes `--bench` to the benchmark.
+    let dir = env::args_os().skip(1).find(|arg| arg != "--bench");
+    let (dir, generated) = match dir {
+        Some(dir) => (PathBuf::from(dir), false),
+        None => (generate(), true),
+    };
+
+    let expected = WalkDir::new(&dir).into_iter().count();
+    println!("{} entries in {}, {} runs each", expected, dir.display(), RUNS);
+
+    report("next", &dir, expected, |dir| {
+        let mut count = 0;
+        for _ in WalkDir::new(dir) {
+            count += 1;
+        }
+        count
+    });
+    report("fold", &dir, expected, |dir| WalkDir::new(dir).into_iter().count());
+    report("for_each", &dir, expected, |dir| {
+        let mut count = 0;
+        WalkDir::new(dir).into_iter().for_each(|_| count += 1);
+        count
+    });
+    report("walkdir", &dir, expected, |dir| walkdir::WalkDir::new(dir).into_iter().count());
+
+    if generated {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+/// A tree of plain files in a new temporary directory.
+fn generate() -> PathBuf {
+    let root = env::temp_dir().join(format!("sprint-dir-bench-{}", process::id()));
+    for dir in 0..DIRS {
+        let dir = root.join(format!("dir{}", dir));
+        fs::create_dir_all(&dir).unwrap();
+        for file in 0..FILES {
+            fs::File::create(dir.join(format!("file{}", file))).unwrap();
+        }
+    }
+    root
+}
+
+fn report(name: &str, dir: &Path, expected: usize, walk: impl Fn(&Path) -> usize) {
+    let mut times: Vec<Duration> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            assert_eq!(walk(dir), expected);
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    println!("{:>8}: min {:?}, median {:?}", name, times[0], times[RUNS / 2]);
+}
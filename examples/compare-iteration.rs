@@ -2,14 +2,26 @@ use std::time::Instant;
 use sprint_dir::WalkDir;
 
 fn main() {
-    let start = Instant::now();
     let dir = std::env::args_os().nth(1).unwrap();
+
+    // External iteration, one call to `next` per entry.
+    let start = Instant::now();
     let mut walk = WalkDir::new(&dir).into_iter();
-    println!("{}", walk.by_ref().count());
-    eprintln!("{:?} {:?}", start.elapsed(), walk.stats());
+    let mut count = 0;
+    for _ in walk.by_ref() {
+        count += 1;
+    }
+    println!("{}", count);
+    eprintln!("next: {:?} {:?}", start.elapsed(), walk.stats());
+
+    // Internal iteration, `count` is implemented with `fold`.
+    let start = Instant::now();
+    let walk = WalkDir::new(&dir).into_iter();
+    println!("{}", walk.count());
+    eprintln!("fold: {:?}", start.elapsed());
 
     let start = Instant::now();
     let mut walk = walkdir::WalkDir::new(dir).into_iter();
     println!("{}", walk.by_ref().count());
-    eprintln!("{:?}", start.elapsed());
+    eprintln!("walkdir: {:?}", start.elapsed());
 }
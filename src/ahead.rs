@@ -0,0 +1,95 @@
+//! Reading directories ahead of the walk, on a helper thread.
+//!
+//! The walk hands over a spare buffer and the descriptor of an open directory, the helper fills
+//! it while the entries of the current buffer are yielded. The walk only waits for the result once
+//! it needs more entries, so latency-bound file systems read while the consumer works.
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A helper thread that runs jobs in order, started with the first of them.
+///
+/// Shared between all walks started from the same configuration. The thread ends once the last of
+/// them is gone.
+#[derive(Default)]
+pub(crate) struct Worker {
+    jobs: Mutex<Option<mpsc::Sender<Job>>>,
+}
+
+/// The result of a job that may still be running.
+pub(crate) struct Pending<T> {
+    slot: Arc<Slot<T>>,
+    /// The result, once it was waited for.
+    done: Option<T>,
+}
+
+struct Slot<T> {
+    result: Mutex<Option<T>>,
+    ready: Condvar,
+}
+
+impl Worker {
+    /// Run a job on the helper thread.
+    ///
+    /// If the thread can not be started the job runs right away, on the calling thread.
+    pub(crate) fn run<T, F>(&self, job: F) -> Pending<T> where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let slot = Arc::new(Slot {
+            result: Mutex::new(None),
+            ready: Condvar::new(),
+        });
+
+        let done = Arc::clone(&slot);
+        let job: Job = Box::new(move || {
+            let result = job();
+            *done.result.lock().unwrap() = Some(result);
+            done.ready.notify_one();
+        });
+
+        let mut jobs = self.jobs.lock().unwrap_or_else(|err| err.into_inner());
+        if jobs.is_none() {
+            let (sender, receiver) = mpsc::channel::<Job>();
+            let spawned = thread::Builder::new()
+                .name("sprint-dir read-ahead".into())
+                .spawn(move || receiver.into_iter().for_each(|job| job()));
+            *jobs = spawned.ok().map(|_| sender);
+        }
+
+        let unsent = match jobs.as_ref() {
+            Some(sender) => sender.send(job).err().map(|mpsc::SendError(job)| job),
+            None => Some(job),
+        };
+
+        // Without a thread the job is done before it is waited for.
+        drop(jobs);
+        if let Some(job) = unsent {
+            job();
+        }
+
+        Pending { slot, done: None }
+    }
+}
+
+impl<T> Pending<T> {
+    /// The result, waiting for the job to finish.
+    pub(crate) fn get(&mut self) -> &mut T {
+        if self.done.is_none() {
+            let mut result = self.slot.result.lock().unwrap();
+            while result.is_none() {
+                result = self.slot.ready.wait(result).unwrap();
+            }
+            self.done = result.take();
+        }
+
+        self.done.as_mut().unwrap()
+    }
+
+    /// Wait for the job to finish and take its result.
+    pub(crate) fn wait(mut self) -> T {
+        self.get();
+        self.done.take().unwrap()
+    }
+}
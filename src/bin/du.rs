@@ -0,0 +1,162 @@
+//! Estimate file space usage, like `du`.
+//!
+//! Sizes are aggregated per directory while walking and printed with children before their
+//! parents, that is in post-order.
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use sprint_dir::WalkDir;
+
+struct Options {
+    summarize: bool,
+    human_readable: bool,
+    apparent_size: bool,
+    max_depth: Option<usize>,
+    roots: Vec<PathBuf>,
+}
+
+fn main() {
+    let options = match Options::from_args(std::env::args_os().skip(1)) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("sprint-du: {}", msg);
+            eprintln!("usage: sprint-du [-s] [-h] [--apparent-size] [--max-depth N] [PATH]...");
+            process::exit(2);
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let mut failed = false;
+
+    for root in &options.roots {
+        match usage(root, &options, &mut out) {
+            Ok(root_failed) => failed |= root_failed,
+            // Most likely a closed pipe, nothing left to report to.
+            Err(_) => process::exit(1),
+        }
+    }
+
+    let _ = out.flush();
+    if failed {
+        process::exit(1);
+    }
+}
+
+impl Options {
+    fn from_args(mut args: impl Iterator<Item = OsString>) -> Result<Self, String> {
+        let mut options = Options {
+            summarize: false,
+            human_readable: false,
+            apparent_size: false,
+            max_depth: None,
+            roots: vec![],
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_bytes() {
+                b"-s" | b"--summarize" => options.summarize = true,
+                b"-h" | b"--human-readable" => options.human_readable = true,
+                b"--apparent-size" => options.apparent_size = true,
+                b"--max-depth" => {
+                    let depth = args.next().ok_or("missing argument to --max-depth")?;
+                    options.max_depth = Some(parse_depth(depth.as_bytes())?);
+                }
+                other if other.starts_with(b"--max-depth=") => {
+                    let depth = &other[b"--max-depth=".len()..];
+                    options.max_depth = Some(parse_depth(depth)?);
+                }
+                other if other.starts_with(b"-") && other.len() > 1 => {
+                    return Err(format!("unrecognized option {:?}", arg));
+                }
+                _ => options.roots.push(PathBuf::from(arg)),
+            }
+        }
+
+        if options.summarize {
+            if options.max_depth.is_some_and(|depth| depth > 0) {
+                return Err("cannot both summarize and show all entries".into());
+            }
+            options.max_depth = Some(0);
+        }
+
+        if options.roots.is_empty() {
+            options.roots.push(PathBuf::from("."));
+        }
+
+        Ok(options)
+    }
+}
+
+fn parse_depth(arg: &[u8]) -> Result<usize, String> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|depth| depth.parse().ok())
+        .ok_or_else(|| format!("invalid maximum depth {:?}", String::from_utf8_lossy(arg)))
+}
+
+/// Walk the tree below `root` and print the usage of its directories, returns if any error
+/// occurred.
+fn usage(root: &Path, options: &Options, out: &mut impl Write) -> io::Result<bool> {
+    let mut failed = false;
+    // Files with several hard links are only counted once.
+    let sizes = WalkDir::new(root).sort_by_file_name().aggregate_sizes(true);
+
+    for dir in sizes {
+        let dir = match dir {
+            Ok(dir) => dir,
+            Err(err) => {
+                eprintln!("sprint-du: {}", err);
+                failed = true;
+                continue;
+            }
+        };
+
+        // The deeper directories are still summed up, only not printed.
+        if options.max_depth.is_some_and(|max| dir.entry().depth() > max) {
+            continue;
+        }
+
+        let size = if options.apparent_size {
+            dir.size()
+        } else {
+            dir.disk_usage()
+        };
+
+        if options.human_readable {
+            write!(out, "{}\t", human_size(size))?;
+        } else {
+            write!(out, "{}\t", size.div_ceil(1024))?;
+        }
+        out.write_all(dir.entry().path().as_os_str().as_bytes())?;
+        out.write_all(b"\n")?;
+    }
+
+    Ok(failed)
+}
+
+/// Format a size with a binary unit suffix, rounding up like `du -h`.
+fn human_size(size: u64) -> String {
+    const UNITS: &[&str] = &["K", "M", "G", "T", "P", "E"];
+
+    if size < 1024 {
+        return size.to_string();
+    }
+
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    let tenths = (value * 10.0).ceil() / 10.0;
+    if tenths < 10.0 {
+        format!("{:.1}{}", tenths, UNITS[unit])
+    } else {
+        format!("{}{}", value.ceil(), UNITS[unit])
+    }
+}
@@ -1,3 +1,117 @@
+//! List all entries below the given paths, like `find`.
+//!
+//! Paths are written to stdout as raw bytes so that names which are not UTF-8 come out intact.
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::process;
+
+use sprint_dir::WalkDir;
+
+struct Options {
+    escape: bool,
+    roots: Vec<PathBuf>,
+}
+
 fn main() {
-    todo!()
+    let options = match Options::from_args(std::env::args_os().skip(1)) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("find: {}", msg);
+            eprintln!("usage: find [--escape] [PATH]...");
+            process::exit(2);
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let mut failed = false;
+
+    for root in &options.roots {
+        for entry in WalkDir::new(root) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    eprintln!("find: {}", err);
+                    failed = true;
+                    continue;
+                }
+            };
+
+            let path = entry.path().as_os_str().as_bytes();
+            let written = if options.escape {
+                write_escaped(&mut out, path)
+            } else {
+                out.write_all(path)
+            };
+
+            if written.and_then(|_| out.write_all(b"\n")).is_err() {
+                // Most likely a closed pipe, nothing left to report to.
+                process::exit(1);
+            }
+        }
+    }
+
+    let _ = out.flush();
+    if failed {
+        process::exit(1);
+    }
+}
+
+impl Options {
+    fn from_args(args: impl Iterator<Item = OsString>) -> Result<Self, String> {
+        let mut options = Options {
+            escape: false,
+            roots: vec![],
+        };
+
+        for arg in args {
+            match arg.as_bytes() {
+                b"--escape" => options.escape = true,
+                other if other.starts_with(b"-") && other.len() > 1 => {
+                    return Err(format!("unrecognized option {:?}", arg));
+                }
+                _ => options.roots.push(PathBuf::from(arg)),
+            }
+        }
+
+        if options.roots.is_empty() {
+            options.roots.push(PathBuf::from("."));
+        }
+
+        Ok(options)
+    }
+}
+
+/// Write the path with invalid UTF-8, control characters and backslashes escaped.
+///
+/// The output is always a single line of printable UTF-8.
+fn write_escaped(out: &mut impl Write, mut path: &[u8]) -> io::Result<()> {
+    loop {
+        match std::str::from_utf8(path) {
+            Ok(valid) => return write_escaped_str(out, valid),
+            Err(err) => {
+                let (valid, rest) = path.split_at(err.valid_up_to());
+                write_escaped_str(out, std::str::from_utf8(valid).unwrap())?;
+                let invalid = err.error_len().unwrap_or(rest.len());
+                for byte in &rest[..invalid] {
+                    write!(out, "\\x{:02x}", byte)?;
+                }
+                path = &rest[invalid..];
+            }
+        }
+    }
+}
+
+fn write_escaped_str(out: &mut impl Write, path: &str) -> io::Result<()> {
+    let mut start = 0;
+    for (idx, ch) in path.char_indices() {
+        if ch == '\\' || ch.is_control() {
+            out.write_all(&path.as_bytes()[start..idx])?;
+            write!(out, "{}", ch.escape_default())?;
+            start = idx + ch.len_utf8();
+        }
+    }
+    out.write_all(&path.as_bytes()[start..])
 }
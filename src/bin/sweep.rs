@@ -0,0 +1,185 @@
+//! Recursively delete directory trees.
+//!
+//! The tree is walked with its contents first, so that every directory is already empty when it
+//! is yielded. Each entry is removed with `unlinkat` relative to its directory. Directories that
+//! the walk had to close are opened again beneath the root, never through a symbolic link.
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use sprint_dir::{DirEntry, WalkDir};
+
+/// Report progress after this many entries.
+const PROGRESS_INTERVAL: usize = 1 << 12;
+
+struct Options {
+    dry_run: bool,
+    one_file_system: bool,
+    roots: Vec<PathBuf>,
+}
+
+struct Sweep<'opt> {
+    options: &'opt Options,
+    /// The device of the root, when staying on its file system.
+    root_device: Option<u64>,
+    found: usize,
+    removed: usize,
+    failed: bool,
+}
+
+fn main() {
+    let options = match Options::from_args(std::env::args_os().skip(1)) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("sweep: {}", msg);
+            eprintln!("usage: sweep [--dry-run] [--one-file-system] PATH...");
+            process::exit(2);
+        }
+    };
+
+    let mut failed = false;
+    for root in &options.roots {
+        let mut sweep = Sweep::new(&options);
+        sweep.remove(root);
+        sweep.report();
+        failed |= sweep.failed;
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
+
+impl Options {
+    fn from_args(args: impl Iterator<Item = OsString>) -> Result<Self, String> {
+        let mut options = Options {
+            dry_run: false,
+            one_file_system: false,
+            roots: vec![],
+        };
+
+        for arg in args {
+            match arg.as_bytes() {
+                b"-n" | b"--dry-run" => options.dry_run = true,
+                b"-x" | b"--one-file-system" => options.one_file_system = true,
+                other if other.starts_with(b"-") && other.len() > 1 => {
+                    return Err(format!("unrecognized option {:?}", arg));
+                }
+                _ => options.roots.push(PathBuf::from(arg)),
+            }
+        }
+
+        if options.roots.is_empty() {
+            return Err("missing operand".into());
+        }
+
+        Ok(options)
+    }
+}
+
+impl<'opt> Sweep<'opt> {
+    fn new(options: &'opt Options) -> Self {
+        Sweep {
+            options,
+            root_device: None,
+            found: 0,
+            removed: 0,
+            failed: false,
+        }
+    }
+
+    /// Remove the root and everything below it.
+    fn remove(&mut self, root: &Path) {
+        let root_meta = match fs::symlink_metadata(root) {
+            Ok(meta) => meta,
+            Err(err) => return self.error(root, err),
+        };
+
+        // Never walk through a symbolic link, only remove the link itself.
+        if !root_meta.is_dir() {
+            self.found += 1;
+            if self.options.dry_run {
+                return self.print(root);
+            }
+            match fs::remove_file(root) {
+                Ok(()) => self.removed += 1,
+                Err(err) => self.error(root, err),
+            }
+            return;
+        }
+
+        if self.options.one_file_system {
+            self.root_device = Some(root_meta.dev());
+        }
+
+        // Directories on other file systems are yielded, but not descended into. Directories
+        // are only opened beneath the root, even when they have to be opened again by path.
+        let walk = WalkDir::new(root)
+            .contents_first(true)
+            .same_file_system(self.options.one_file_system)
+            .sandbox(true);
+
+        for entry in walk {
+            match entry {
+                Ok(entry) => self.remove_entry(&entry),
+                Err(err) => {
+                    eprintln!("sweep: {}", err);
+                    self.failed = true;
+                }
+            }
+        }
+    }
+
+    /// Remove an entry whose contents, if any, were removed already.
+    fn remove_entry(&mut self, entry: &DirEntry) {
+        self.found += 1;
+        if self.found.is_multiple_of(PROGRESS_INTERVAL) {
+            eprint!("\rsweep: {} entries", self.found);
+        }
+
+        if let (Some(device), true) = (self.root_device, entry.file_type().is_dir()) {
+            match entry.symlink_metadata() {
+                Ok(meta) if meta.dev() != device => {
+                    eprintln!("sweep: skipping {}, on a different file system",
+                        entry.path().display());
+                    self.failed = true;
+                    return;
+                }
+                Ok(_) => {}
+                Err(err) => return self.error(entry.path(), err),
+            }
+        }
+
+        if self.options.dry_run {
+            return self.print(entry.path());
+        }
+
+        match entry.remove() {
+            Ok(()) => self.removed += 1,
+            Err(err) => self.error(entry.path(), err),
+        }
+    }
+
+    fn print(&self, path: &Path) {
+        let mut out = io::stdout();
+        let _ = out.write_all(path.as_os_str().as_bytes());
+        let _ = out.write_all(b"\n");
+    }
+
+    fn report(&self) {
+        if self.options.dry_run {
+            eprintln!("\rsweep: would remove {} entries", self.found);
+        } else {
+            eprintln!("\rsweep: removed {} of {} entries", self.removed, self.found);
+        }
+    }
+
+    fn error(&mut self, path: &Path, err: io::Error) {
+        eprintln!("sweep: cannot remove {}: {}", path.display(), err);
+        self.failed = true;
+    }
+}
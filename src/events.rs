@@ -0,0 +1,121 @@
+//! Walking a tree as a stream of events, entering and leaving each directory like `fts(3)`.
+use std::iter::FusedIterator;
+
+use crate::walker::{DirEntry, Error, IntoIter, Stats};
+
+/// A step of the walk, see [`WalkDir::into_events`](crate::WalkDir::into_events).
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A directory, before any of its contents.
+    Enter(DirEntry),
+    /// A directory that was entered before, after all of its contents.
+    Leave(DirEntry),
+    /// Any entry that is not a directory.
+    File(DirEntry),
+}
+
+/// An iterator over the events of a walk.
+///
+/// Created by [`WalkDir::into_events`](crate::WalkDir::into_events). Each directory is left
+/// before the next entry outside of it is yielded, errors are passed on in the order the walk
+/// reports them.
+pub struct Events {
+    it: IntoIter,
+    /// The directories that were entered but not left yet, the innermost last.
+    entered: Vec<DirEntry>,
+    /// The next entry, held back until the directories it is not in were left.
+    pending: Option<DirEntry>,
+    /// If the last event left a directory.
+    left_last: bool,
+}
+
+impl Event {
+    /// The entry this event is about.
+    pub fn entry(&self) -> &DirEntry {
+        match self {
+            Event::Enter(entry) | Event::Leave(entry) | Event::File(entry) => entry,
+        }
+    }
+
+    /// Convert the event into its entry.
+    pub fn into_entry(self) -> DirEntry {
+        match self {
+            Event::Enter(entry) | Event::Leave(entry) | Event::File(entry) => entry,
+        }
+    }
+}
+
+impl Events {
+    pub(crate) fn new(it: IntoIter) -> Self {
+        Events {
+            it,
+            entered: vec![],
+            pending: None,
+            left_last: false,
+        }
+    }
+
+    /// Do not descend further into the directory of the last event.
+    ///
+    /// After entering a directory none of its contents are yielded, it is left next. After a file
+    /// the remaining entries of its parent are skipped. Directories that were left already are
+    /// not affected, this does nothing after a `Leave` event.
+    pub fn skip_current_dir(&mut self) {
+        if !self.left_last {
+            self.it.skip_current_dir();
+        }
+    }
+
+    /// The system calls made by this walk so far.
+    pub fn stats(&self) -> &Stats {
+        self.it.stats()
+    }
+
+    /// Leave the innermost directory, if the entry at `depth` is not within it.
+    fn leave(&mut self, depth: usize) -> Option<DirEntry> {
+        match self.entered.last() {
+            Some(dir) if dir.depth() >= depth => self.entered.pop(),
+            _ => None,
+        }
+    }
+
+    fn enter(&mut self, entry: DirEntry) -> Event {
+        if entry.file_type().is_dir() {
+            self.entered.push(entry.clone());
+            Event::Enter(entry)
+        } else {
+            Event::File(entry)
+        }
+    }
+}
+
+impl Iterator for Events {
+    type Item = Result<Event, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.left_last = false;
+        loop {
+            if let Some(entry) = &self.pending {
+                if let Some(dir) = self.leave(entry.depth()) {
+                    self.left_last = true;
+                    return Some(Ok(Event::Leave(dir)));
+                }
+
+                let entry = self.pending.take().unwrap();
+                return Some(Ok(self.enter(entry)));
+            }
+
+            match self.it.next() {
+                Some(Ok(entry)) => self.pending = Some(entry),
+                Some(Err(err)) => return Some(Err(err)),
+                None => {
+                    let dir = self.entered.pop()?;
+                    self.left_last = true;
+                    return Some(Ok(Event::Leave(dir)));
+                }
+            }
+        }
+    }
+}
+
+impl FusedIterator for Events {}
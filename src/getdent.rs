@@ -9,9 +9,13 @@ use std::io;
 use std::ffi;
 use std::os::unix::ffi::OsStrExt;
 
-use super::UnixFileType as FileType;
+use super::UnixFileType as FileTypeInner;
+use super::walker::FileType;
 
 /// A buffer for collecting results of `getdents`.
+///
+/// Filled from a directory descriptor with [`fill_buf`](DirentBuf::fill_buf), the entries are
+/// then parsed in place without further allocation.
 pub struct DirentBuf {
     inner: Box<[u8]>,
     /// The index of the first set buffer.
@@ -20,24 +24,35 @@ pub struct DirentBuf {
     last: usize,
 }
 
-/// A reference to a single entry.
+/// A reference to a single entry in the buffer.
 pub struct Entry<'buf> {
     inner: &'buf Dirent64,
 }
 
 /// A consistency error of the result buffer.
+///
+/// Only occurs if the kernel wrote something other than dirents into the buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum DirentErr {
     TooShort,
     InvalidLength,
 }
 
+/// The outcome of filling a buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum More {
+    /// Entries were added to the buffer.
     More,
+    /// The free space of the buffer is too small for the next entry.
     Blocked,
+    /// The end of the directory was reached.
     Done,
 }
 
 impl DirentBuf {
+    /// A buffer of this many bytes.
+    ///
+    /// Panics if the size does not fit the argument of `getdents64`.
     pub fn with_size(length: usize) -> Self {
         libc::c_uint::try_from(length).expect("Buffer size invalid for `getdent` syscall.");
 
@@ -48,12 +63,35 @@ impl DirentBuf {
         }
     }
 
+    /// The number of bytes that one call can fill.
+    pub fn capacity(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Forget all entries that were not yet drained.
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.last = 0;
+    }
+
+    /// The number of bytes of entries that were not yet drained.
+    pub fn len(&self) -> usize {
+        self.last - self.start
+    }
+
+    /// If all entries were drained.
+    pub fn is_empty(&self) -> bool {
+        self.start == self.last
+    }
+
+    /// Iterate over the entries that were not yet drained, without removing them.
     pub fn iter(&self) -> Entries<'_> {
         Entries {
             remaining: &self.inner[self.start..self.last],
         }
     }
 
+    /// Iterate over the entries and remove each one that is yielded.
     pub fn drain(&mut self) -> Drain<'_> {
         Drain {
             inner: Entries {
@@ -64,6 +102,9 @@ impl DirentBuf {
         }
     }
 
+    /// Read more entries from a directory descriptor, into the free space after the buffered ones.
+    ///
+    /// The descriptor is advanced past the entries that were read.
     pub fn fill_buf(&mut self, fd: libc::c_int) -> io::Result<More> {
         // Make buffer as large as possible.
         if self.start == self.last {
@@ -98,7 +139,7 @@ impl DirentBuf {
     }
 }
 
-/// Iterates like entries but removes the entries.
+/// Iterates over the entries of a buffer.
 pub struct Entries<'a> {
     remaining: &'a [u8],
 }
@@ -110,13 +151,32 @@ pub struct Drain<'a> {
     last: usize,
 }
 
-impl Entry<'_> {
-    pub(crate) fn file_name(&self) -> &ffi::OsStr {
+impl<'buf> Entry<'buf> {
+    /// The name, without the terminating null byte.
+    pub fn file_name(&self) -> &'buf ffi::OsStr {
         ffi::OsStr::from_bytes(&self.inner.d_name)
     }
 
-    pub(crate) fn file_type(&self) -> Option<FileType> {
-        FileType::new(self.inner.d_type)
+    /// The type reported by the file system, it may be unknown.
+    pub fn file_type(&self) -> FileType {
+        FileType::from_kind(self.kind())
+    }
+
+    pub(crate) fn kind(&self) -> Option<FileTypeInner> {
+        FileTypeInner::new(self.inner.d_type)
+    }
+
+    /// The inode number.
+    pub fn ino(&self) -> u64 {
+        unsafe { ptr::read_unaligned(ptr::addr_of!(self.inner.d_ino)) }
+    }
+
+    /// The offset of the next entry in the directory stream.
+    ///
+    /// Seeking the directory descriptor to it continues reading after this entry.
+    pub fn offset(&self) -> i64 {
+        let offset = unsafe { ptr::read_unaligned(ptr::addr_of!(self.inner.d_off)) };
+        offset as i64
     }
 }
 
@@ -127,10 +187,10 @@ struct DirentTarget {
 }
 
 /// The actual unsized descriptor of the entry.
-#[repr(packed)]
+#[repr(C, packed)]
 struct Dirent64 {
     /// The inode associated with the entry.
-    d_ino: libc::c_ulong,
+    d_ino: u64,
     /// The offset to the next entry, for seeking.
     d_off: libc::c_ulong,
     /// The length of the buffer, _after_ the syscall succeeded.
@@ -146,12 +206,12 @@ struct Dirent64 {
 // FFI type
 // Be careful that this struct is actually zeroable and a Pod. In particular we want to avoid
 // having any padding bytes.
-#[repr(packed)]
+#[repr(C, packed)]
 // Merely a descriptor type.
 #[allow(unused)]
 #[derive(Clone, Copy)]
 struct dirent64 {
-    d_ino: libc::c_ulong,
+    d_ino: u64,
     d_off: libc::c_ulong,
     /// The length of the buffer, _after_ the syscall succeeded.
     d_reclen: libc::c_ushort,
@@ -260,7 +320,7 @@ impl<'a> Iterator for Drain<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         match self.inner.next() {
             Some(Ok(entry)) => {
-                let len = unsafe { ptr::read_unaligned(&entry.inner.d_reclen) };
+                let len = unsafe { ptr::read_unaligned(ptr::addr_of!(entry.inner.d_reclen)) };
                 *self.start += len as usize;
                 Some(Ok(entry))
             }
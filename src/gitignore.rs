@@ -0,0 +1,106 @@
+//! Skipping entries matched by ignore files, with the `ignore` feature.
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::sync::Arc;
+
+use ignore::Match;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+/// The ignore files of each directory, later ones take precedence.
+const IGNORE_FILES: [&[u8]; 2] = [b".gitignore\0", b".ignore\0"];
+
+/// The settings of a walk that respects ignore files.
+#[derive(Clone)]
+pub(crate) struct IgnoreFiles {
+    /// The global excludes of git, checked after all ignore files.
+    global: Arc<Gitignore>,
+    /// The ignore files that apply to the root, for the levels of a parallel walk.
+    pub(crate) root: Option<Arc<Ignores>>,
+}
+
+/// The ignore files of a directory, and those of its parents.
+#[derive(Debug)]
+pub(crate) struct Ignores {
+    matcher: Gitignore,
+    /// The closest parent directory with ignore files.
+    parent: Option<Arc<Ignores>>,
+}
+
+impl IgnoreFiles {
+    pub(crate) fn new() -> Self {
+        // Like git, a broken global configuration does not stop us.
+        let (global, _) = Gitignore::global();
+        IgnoreFiles {
+            global: Arc::new(global),
+            root: None,
+        }
+    }
+
+    /// Read the ignore files of a directory from its open descriptor.
+    ///
+    /// Without any ignore files the directory shares those of its parent.
+    pub(crate) fn read(&self, dir: libc::c_int, path: &Path, parent: Option<&Arc<Ignores>>)
+        -> io::Result<Option<Arc<Ignores>>>
+    {
+        let mut builder = GitignoreBuilder::new(path);
+        for name in IGNORE_FILES.iter() {
+            let name = CStr::from_bytes_with_nul(name).unwrap();
+            let contents = match read_at(dir, name)? {
+                Some(contents) => contents,
+                None => continue,
+            };
+
+            let from = path.join(name.to_str().unwrap());
+            for line in String::from_utf8_lossy(&contents).lines() {
+                // Invalid patterns are skipped, like git does.
+                let _ = builder.add_line(Some(from.clone()), line);
+            }
+        }
+
+        match builder.build() {
+            Ok(matcher) if !matcher.is_empty() => Ok(Some(Arc::new(Ignores {
+                matcher,
+                parent: parent.cloned(),
+            }))),
+            _ => Ok(parent.cloned()),
+        }
+    }
+
+    /// If an entry is ignored, given the ignore files of its directory.
+    pub(crate) fn is_ignored(&self, ignores: Option<&Arc<Ignores>>, path: &Path, is_dir: bool)
+        -> bool
+    {
+        let mut next = ignores;
+        while let Some(ignores) = next {
+            match ignores.matcher.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => next = ignores.parent.as_ref(),
+            }
+        }
+
+        self.global.matched(path, is_dir).is_ignore()
+    }
+}
+
+/// The contents of a file in the directory, if there is one.
+fn read_at(dir: libc::c_int, name: &CStr) -> io::Result<Option<Vec<u8>>> {
+    // Links are not followed, they could lead outside of a sandboxed walk.
+    let flags = libc::O_RDONLY | libc::O_CLOEXEC | libc::O_NOCTTY | libc::O_NOFOLLOW;
+    let fd = unsafe { libc::openat(dir, name.as_ptr(), flags) };
+    if fd < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENOENT) | Some(libc::ELOOP) => Ok(None),
+            _ => Err(err),
+        };
+    }
+
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    Ok(Some(contents))
+}
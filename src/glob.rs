@@ -0,0 +1,83 @@
+//! Matching entries against glob patterns, with the `globset` feature.
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobMatcher};
+
+use crate::walker::DirEntry;
+
+/// The glob patterns that are matched against the entries of a walk.
+///
+/// Patterns that only match file names are checked on the dirent buffer instead, as name filters.
+#[derive(Clone, Default)]
+pub(crate) struct GlobFilter {
+    /// Only entries that match one of them are yielded, unless there are none.
+    include: Vec<Pattern>,
+    /// Entries that match one of them are skipped, along with their contents.
+    exclude: Vec<Pattern>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Pattern {
+    matcher: GlobMatcher,
+    /// If the pattern is matched against the path relative to the root, not the file name.
+    relative: bool,
+}
+
+impl Pattern {
+    pub(crate) fn new(pattern: &str) -> Result<Self, globset::Error> {
+        Ok(Pattern {
+            matcher: Glob::new(pattern)?.compile_matcher(),
+            relative: pattern.contains('/'),
+        })
+    }
+
+    pub(crate) fn is_relative(&self) -> bool {
+        self.relative
+    }
+
+    /// Match a file name, the pattern must not be relative.
+    pub(crate) fn matches_name(&self, name: &Path) -> bool {
+        self.matcher.is_match(name)
+    }
+}
+
+impl GlobFilter {
+    pub(crate) fn include(&mut self, pattern: Pattern) {
+        self.include.push(pattern);
+    }
+
+    pub(crate) fn exclude(&mut self, pattern: Pattern) {
+        self.exclude.push(pattern);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// If the entry, and its contents, are skipped. The roots are never excluded.
+    pub(crate) fn excludes(&self, entry: &DirEntry) -> bool {
+        entry.depth() > 0 && matches_any(&self.exclude, entry)
+    }
+
+    /// If the entry may be yielded. The roots are always included.
+    pub(crate) fn includes(&self, entry: &DirEntry) -> bool {
+        self.include.is_empty() || entry.depth() == 0 || matches_any(&self.include, entry)
+    }
+}
+
+fn matches_any(patterns: &[Pattern], entry: &DirEntry) -> bool {
+    let mut relative = None;
+    patterns.iter().any(|pattern| if pattern.relative {
+        let path = relative.get_or_insert_with(|| relative_path(entry));
+        pattern.matcher.is_match(path)
+    } else {
+        pattern.matcher.is_match(entry.file_name())
+    })
+}
+
+/// The path below the root, each level of depth added one component to it.
+fn relative_path(entry: &DirEntry) -> PathBuf {
+    let mut components: Vec<_> = entry.path().components().rev().take(entry.depth()).collect();
+    components.reverse();
+    components.into_iter().collect()
+}
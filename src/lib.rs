@@ -1,17 +1,65 @@
+mod ahead;
+mod events;
 mod getdent;
+#[cfg(feature = "ignore")]
+mod gitignore;
+#[cfg(feature = "globset")]
+mod glob;
+mod metadata;
+mod name;
+mod open;
+#[cfg(feature = "rayon")]
+mod par;
+mod parallel;
+mod preopen;
+mod sizes;
+mod state;
+#[cfg(feature = "tokio")]
+mod stream;
+mod visit;
 mod walker;
 #[cfg(test)]
 mod tests;
 
-pub use walker::{DirEntry, Error, FilterEntry, IntoIter, WalkDir};
+pub use events::{Event, Events};
+pub use metadata::Metadata;
+pub use open::OpenOptions;
+#[cfg(feature = "rayon")]
+pub use par::ParEntries;
+pub use parallel::ParallelIter;
+pub use sizes::{DirSize, Sizes};
+pub use state::WalkState;
+#[cfg(feature = "tokio")]
+pub use stream::WalkStream;
+pub use visit::Visitor;
+#[cfg(feature = "histogram-stats")]
+pub use walker::Histograms;
+pub use walker::{
+    CancelToken, Decision, DirEntry, EntryRef, Error, ErrorPolicy, FileType, FilterEntry, IntoIter,
+    Parent, Progress, SiblingOrder, SortOrder, Stats, Vanished, WalkDir,
+};
 
-#[derive(Clone, Copy, Debug, PartialEq)]
-enum UnixFileType {
+/// Reading a single directory with `getdents64`, without walking a tree.
+///
+/// This is the buffer the walk reads each directory into. It works on any directory descriptor,
+/// for example one opened by other means than the walk.
+pub mod raw {
+    pub use crate::getdent::{DirentBuf, DirentErr, Drain, Entries, Entry, More};
+}
+
+/// The kinds of files on Unix, as reported by the file system.
+///
+/// See [`FileType::kind`], and convert it into a [`FileType`] for [`WalkDir::types`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum UnixFileType {
     BlockDevice = 1,
     CharDevice,
     Directory,
+    /// A FIFO.
     NamedPipe,
     SymbolicLink,
+    /// A regular file.
     File,
     UnixSocket,
 }
@@ -30,4 +78,39 @@ impl UnixFileType {
             _ => None,
         }
     }
+
+    /// The kind of a file type from the standard library, `None` if it is none of these.
+    pub fn from_std(ty: std::fs::FileType) -> Option<Self> {
+        use std::os::unix::fs::FileTypeExt;
+        if ty.is_block_device() {
+            Some(Self::BlockDevice)
+        } else if ty.is_char_device() {
+            Some(Self::CharDevice)
+        } else if ty.is_dir() {
+            Some(Self::Directory)
+        } else if ty.is_fifo() {
+            Some(Self::NamedPipe)
+        } else if ty.is_symlink() {
+            Some(Self::SymbolicLink)
+        } else if ty.is_file() {
+            Some(Self::File)
+        } else if ty.is_socket() {
+            Some(Self::UnixSocket)
+        } else {
+            None
+        }
+    }
+
+    fn from_mode(mode: libc::mode_t) -> Option<Self> {
+        match mode & libc::S_IFMT {
+            libc::S_IFBLK => Some(Self::BlockDevice),
+            libc::S_IFCHR => Some(Self::CharDevice),
+            libc::S_IFDIR => Some(Self::Directory),
+            libc::S_IFIFO => Some(Self::NamedPipe),
+            libc::S_IFLNK => Some(Self::SymbolicLink),
+            libc::S_IFREG => Some(Self::File),
+            libc::S_IFSOCK => Some(Self::UnixSocket),
+            _ => None,
+        }
+    }
 }
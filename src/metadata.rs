@@ -0,0 +1,149 @@
+// The field types of `stat` differ between targets.
+#![allow(clippy::unnecessary_cast)]
+use core::fmt;
+use std::fs::Permissions;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::walker::FileType;
+
+/// Meta data of an entry, like [`std::fs::Metadata`].
+///
+/// The standard type can only be created by a stat of a path or an open file. This one is the
+/// result of a `fstatat` relative to the directory of an entry. The accessors mirror those of the
+/// standard type and of its `MetadataExt` extension on Unix.
+#[derive(Clone)]
+pub struct Metadata {
+    stat: libc::stat,
+}
+
+impl Metadata {
+    pub(crate) fn new(stat: libc::stat) -> Self {
+        Metadata { stat }
+    }
+
+    pub fn file_type(&self) -> FileType {
+        FileType::from_mode(self.stat.st_mode)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type().is_dir()
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type().is_file()
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type().is_symlink()
+    }
+
+    /// The size of the file in bytes.
+    // Named like the standard method, a file is not a collection.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u64 {
+        self.size()
+    }
+
+    pub fn permissions(&self) -> Permissions {
+        Permissions::from_mode(self.mode())
+    }
+
+    /// The time of the last modification, see [`std::fs::Metadata::modified`].
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        Ok(system_time(self.mtime(), self.mtime_nsec()))
+    }
+
+    /// The time of the last access, see [`std::fs::Metadata::accessed`].
+    pub fn accessed(&self) -> io::Result<SystemTime> {
+        Ok(system_time(self.atime(), self.atime_nsec()))
+    }
+
+    pub fn dev(&self) -> u64 {
+        self.stat.st_dev as u64
+    }
+
+    pub fn ino(&self) -> u64 {
+        self.stat.st_ino as u64
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.stat.st_mode as u32
+    }
+
+    pub fn nlink(&self) -> u64 {
+        self.stat.st_nlink as u64
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.stat.st_uid as u32
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.stat.st_gid as u32
+    }
+
+    pub fn rdev(&self) -> u64 {
+        self.stat.st_rdev as u64
+    }
+
+    pub fn size(&self) -> u64 {
+        self.stat.st_size as u64
+    }
+
+    pub fn atime(&self) -> i64 {
+        self.stat.st_atime as i64
+    }
+
+    pub fn atime_nsec(&self) -> i64 {
+        self.stat.st_atime_nsec as i64
+    }
+
+    pub fn mtime(&self) -> i64 {
+        self.stat.st_mtime as i64
+    }
+
+    pub fn mtime_nsec(&self) -> i64 {
+        self.stat.st_mtime_nsec as i64
+    }
+
+    pub fn ctime(&self) -> i64 {
+        self.stat.st_ctime as i64
+    }
+
+    pub fn ctime_nsec(&self) -> i64 {
+        self.stat.st_ctime_nsec as i64
+    }
+
+    pub fn blksize(&self) -> u64 {
+        self.stat.st_blksize as u64
+    }
+
+    /// The number of 512-byte blocks allocated.
+    pub fn blocks(&self) -> u64 {
+        self.stat.st_blocks as u64
+    }
+}
+
+impl fmt::Debug for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Metadata")
+            .field("file_type", &self.file_type())
+            .field("len", &self.len())
+            .field("mode", &format_args!("{:o}", self.mode()))
+            .field("dev", &self.dev())
+            .field("ino", &self.ino())
+            .field("mtime", &self.mtime())
+            .finish()
+    }
+}
+
+fn system_time(secs: i64, nsecs: i64) -> SystemTime {
+    let nsecs = Duration::from_nanos(nsecs as u64);
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64) + nsecs
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + nsecs
+    }
+}
@@ -0,0 +1,90 @@
+use core::cmp::Ordering;
+use core::fmt;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+/// The longest name that is stored without a separate allocation.
+///
+/// Chosen such that the whole enum is no larger than an `OsString`.
+const INLINE_LEN: usize = 22;
+
+/// An owned file name, stored inline when it is short.
+///
+/// Most file names are short so this avoids one allocation for almost every entry.
+#[derive(Clone)]
+pub enum FileName {
+    Inline {
+        len: u8,
+        bytes: [u8; INLINE_LEN],
+    },
+    Heap(Box<[u8]>),
+}
+
+impl FileName {
+    pub fn new(name: &OsStr) -> Self {
+        let name = name.as_bytes();
+        if name.len() <= INLINE_LEN {
+            let mut bytes = [0; INLINE_LEN];
+            bytes[..name.len()].copy_from_slice(name);
+            FileName::Inline {
+                len: name.len() as u8,
+                bytes,
+            }
+        } else {
+            FileName::Heap(name.into())
+        }
+    }
+
+    pub fn as_os_str(&self) -> &OsStr {
+        let bytes = match self {
+            FileName::Inline { len, bytes } => &bytes[..usize::from(*len)],
+            FileName::Heap(bytes) => bytes,
+        };
+
+        OsStr::from_bytes(bytes)
+    }
+}
+
+impl fmt::Debug for FileName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_os_str().fmt(f)
+    }
+}
+
+/// Compare names like `ls -v`, with each run of digits ordered by its value.
+///
+/// Names that only differ in leading zeros are ordered by their bytes.
+pub(crate) fn natural_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+            let (end_a, end_b) = (digits_end(a, i), digits_end(b, j));
+            let (num_a, num_b) = (trim_zeros(&a[i..end_a]), trim_zeros(&b[j..end_b]));
+            // Without leading zeros the longer number is the larger one.
+            match num_a.len().cmp(&num_b.len()).then_with(|| num_a.cmp(num_b)) {
+                Ordering::Equal => {}
+                ord => return ord,
+            }
+            i = end_a;
+            j = end_b;
+        } else {
+            match a[i].cmp(&b[j]) {
+                Ordering::Equal => {}
+                ord => return ord,
+            }
+            i += 1;
+            j += 1;
+        }
+    }
+
+    (a.len() - i).cmp(&(b.len() - j)).then_with(|| a.cmp(b))
+}
+
+fn digits_end(name: &[u8], start: usize) -> usize {
+    name[start..].iter().position(|b| !b.is_ascii_digit()).map_or(name.len(), |len| start + len)
+}
+
+fn trim_zeros(digits: &[u8]) -> &[u8] {
+    let zeros = digits.iter().take_while(|&&b| b == b'0').count();
+    &digits[zeros..]
+}
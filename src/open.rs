@@ -0,0 +1,122 @@
+use std::io;
+
+/// Options for opening an entry, like [`std::fs::OpenOptions`].
+///
+/// The standard type does not reveal the flags it would use, so entries can not open themselves
+/// relative to their directory with it. Used with
+/// [`DirEntry::open_with`](crate::DirEntry::open_with).
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+    custom_flags: libc::c_int,
+    mode: libc::mode_t,
+}
+
+impl OpenOptions {
+    /// Options with all flags unset, see [`std::fs::OpenOptions::new`].
+    pub fn new() -> Self {
+        OpenOptions {
+            read: false,
+            write: false,
+            append: false,
+            truncate: false,
+            create: false,
+            create_new: false,
+            custom_flags: 0,
+            mode: 0o666,
+        }
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+
+    /// Additional flags for `open`, the access mode bits are ignored.
+    pub fn custom_flags(&mut self, flags: i32) -> &mut Self {
+        self.custom_flags = flags;
+        self
+    }
+
+    /// The permissions of a newly created file, before the umask is applied.
+    pub fn mode(&mut self, mode: u32) -> &mut Self {
+        self.mode = mode as libc::mode_t;
+        self
+    }
+
+    /// The flags for `open`, rejecting the same combinations as the standard library.
+    pub(crate) fn flags(&self) -> io::Result<libc::c_int> {
+        let access = match (self.read, self.write, self.append) {
+            (true, false, false) => libc::O_RDONLY,
+            (false, true, false) => libc::O_WRONLY,
+            (true, true, false) => libc::O_RDWR,
+            (false, _, true) => libc::O_WRONLY | libc::O_APPEND,
+            (true, _, true) => libc::O_RDWR | libc::O_APPEND,
+            (false, false, false) => return Err(invalid()),
+        };
+
+        let writes = self.write || self.append;
+        if !writes && (self.truncate || self.create || self.create_new) {
+            return Err(invalid());
+        }
+
+        if self.append && self.truncate && !self.create_new {
+            return Err(invalid());
+        }
+
+        let creation = match (self.create, self.truncate, self.create_new) {
+            (_, _, true) => libc::O_CREAT | libc::O_EXCL,
+            (true, true, false) => libc::O_CREAT | libc::O_TRUNC,
+            (true, false, false) => libc::O_CREAT,
+            (false, true, false) => libc::O_TRUNC,
+            (false, false, false) => 0,
+        };
+
+        let custom = self.custom_flags & !libc::O_ACCMODE;
+        Ok(libc::O_CLOEXEC | access | creation | custom)
+    }
+
+    pub(crate) fn file_mode(&self) -> libc::mode_t {
+        self.mode
+    }
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions::new()
+    }
+}
+
+fn invalid() -> io::Error {
+    io::Error::from_raw_os_error(libc::EINVAL)
+}
@@ -0,0 +1,84 @@
+//! Walking a tree as a rayon parallel iterator.
+//!
+//! Rayon divides the work before each piece is walked sequentially, so the walk is split with
+//! [`IntoIter::split`] whenever rayon asks for it. A fresh walk has only its root pending, thus a
+//! piece is walked ahead until it has pending entries to divide.
+use rayon::iter::plumbing::{bridge_unindexed, Folder, UnindexedConsumer, UnindexedProducer};
+use rayon::iter::ParallelIterator;
+
+use crate::walker::{DirEntry, Error, IntoIter};
+
+/// The most entries a piece reads ahead while looking for work to split off.
+const MAX_READY: usize = 1 << 10;
+
+/// A rayon parallel iterator over the entries of a tree.
+///
+/// Created by [`WalkDir::par_entries`](crate::WalkDir::par_entries). Each piece of the walk
+/// yields its entries in the configured order but the pieces are not ordered relative to each
+/// other.
+pub struct ParEntries {
+    it: IntoIter,
+}
+
+/// One piece of the walk, with the entries that were read ahead.
+struct Producer {
+    ready: Vec<Result<DirEntry, Error>>,
+    it: IntoIter,
+}
+
+impl ParEntries {
+    pub(crate) fn new(it: IntoIter) -> Self {
+        ParEntries { it }
+    }
+}
+
+impl ParallelIterator for ParEntries {
+    type Item = Result<DirEntry, Error>;
+
+    fn drive_unindexed<C>(self, consumer: C) -> C::Result
+    where
+        C: UnindexedConsumer<Self::Item>,
+    {
+        let producer = Producer {
+            ready: vec![],
+            it: self.it,
+        };
+
+        bridge_unindexed(producer, consumer)
+    }
+}
+
+impl UnindexedProducer for Producer {
+    type Item = Result<DirEntry, Error>;
+
+    fn split(mut self) -> (Self, Option<Self>) {
+        while !self.it.is_divisible() {
+            if self.ready.len() >= MAX_READY {
+                return (self, None);
+            }
+
+            match self.it.next() {
+                Some(item) => self.ready.push(item),
+                None => return (self, None),
+            }
+        }
+
+        match self.it.split_off() {
+            Ok(it) => {
+                let other = Producer { ready: vec![], it };
+                (self, Some(other))
+            }
+            Err(err) => {
+                self.ready.push(Err(err));
+                (self, None)
+            }
+        }
+    }
+
+    fn fold_with<F>(self, folder: F) -> F
+    where
+        F: Folder<Self::Item>,
+    {
+        folder.consume_iter(self.ready.into_iter().chain(self.it))
+    }
+}
@@ -0,0 +1,399 @@
+//! Walking a tree with a pool of threads.
+//!
+//! Every directory is a unit of work, read by a regular walk limited to its direct children.
+//! Each thread keeps its own queue of units and takes the most recently found directory, idle
+//! threads steal the oldest units of the others. This keeps each thread close to a depth-first
+//! order, limiting the memory for pending directories, while the stolen units are large subtrees.
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(feature = "ignore")]
+use crate::gitignore::Ignores;
+use crate::walker::{
+    Base, Decision, DirEntry, Error, FileId, FileIds, LinkTargets, Vanished, WalkDir,
+};
+
+/// Entries that may be waiting for the consumer, per thread.
+const CHANNEL_DEPTH: usize = 1 << 10;
+/// How long an idle thread waits before looking for work again.
+const IDLE_WAIT: Duration = Duration::from_millis(1);
+
+/// An iterator over the entries of a tree, found by a pool of threads.
+///
+/// Created by [`WalkDir::into_parallel_iter`]. Entries arrive in no particular order. Dropping the
+/// iterator stops the threads and waits for them to finish.
+pub struct ParallelIter {
+    entries: Option<mpsc::Receiver<Result<DirEntry, Error>>>,
+    workers: Vec<thread::JoinHandle<()>>,
+    shared: Arc<Shared>,
+    /// The entries yielded so far, counted against the limit.
+    nr_yielded: usize,
+}
+
+/// The state shared by all threads.
+struct Shared {
+    /// The template for the walk of each unit.
+    walk: WalkDir,
+    queues: Vec<Mutex<VecDeque<Unit>>>,
+    /// Units that were queued but are not yet finished.
+    pending: AtomicUsize,
+    /// Set when the consumer is gone or the walk was aborted.
+    cancelled: AtomicBool,
+    /// Wakes up idle threads when there is new work, or none is left.
+    idle: Mutex<()>,
+    wakeup: Condvar,
+    /// Directories that could not be opened for lack of permission, when collecting them.
+    denied: Mutex<Vec<PathBuf>>,
+    /// The files yielded by all threads, when skipping further links to them.
+    files: FileIds,
+    /// The directories that were descended into, when visiting each only once.
+    dirs: FileIds,
+    /// The targets of the links that were followed, when caching them.
+    links: LinkTargets,
+}
+
+/// A directory whose children were not yet read.
+struct Unit {
+    path: PathBuf,
+    /// Resolves the path, unless it is a root.
+    base: Option<Arc<Base>>,
+    depth: usize,
+    is_root: bool,
+    /// The directories on the path to this one, only tracked when following links.
+    ancestors: Option<Arc<Ancestor>>,
+    /// The device of the root, only tracked when staying on its file system.
+    root_device: Option<libc::dev_t>,
+    /// The ignore files that apply to the directory.
+    #[cfg(feature = "ignore")]
+    ignores: Option<Arc<Ignores>>,
+}
+
+struct Ancestor {
+    path: PathBuf,
+    id: (libc::dev_t, libc::ino_t),
+    parent: Option<Arc<Ancestor>>,
+}
+
+impl ParallelIter {
+    pub(crate) fn new(walk: WalkDir) -> Self {
+        let threads = match walk.config().threads {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+
+        let (sender, entries) = mpsc::sync_channel(threads * CHANNEL_DEPTH);
+        let roots: Vec<_> = walk.roots()
+            .iter()
+            .map(|root| Unit {
+                path: root.to_owned(),
+                base: None,
+                depth: 0,
+                is_root: true,
+                ancestors: None,
+                root_device: None,
+                #[cfg(feature = "ignore")]
+                ignores: None,
+            })
+            .collect();
+
+        let shared = Arc::new(Shared {
+            walk,
+            queues: (0..threads).map(|_| Mutex::default()).collect(),
+            pending: AtomicUsize::new(0),
+            cancelled: AtomicBool::new(false),
+            idle: Mutex::new(()),
+            wakeup: Condvar::new(),
+            denied: Mutex::default(),
+            files: FileIds::default(),
+            dirs: FileIds::default(),
+            links: LinkTargets::default(),
+        });
+
+        for (index, root) in roots.into_iter().enumerate() {
+            shared.push(index % threads, root);
+        }
+
+        let workers = (0..threads)
+            .map(|index| {
+                let shared = shared.clone();
+                let sender = sender.clone();
+                thread::spawn(move || shared.work(index, &sender))
+            })
+            .collect();
+
+        ParallelIter {
+            entries: Some(entries),
+            workers,
+            shared,
+            nr_yielded: 0,
+        }
+    }
+
+    /// The directories that could not be opened for lack of permission, found so far.
+    ///
+    /// Only collected with [`WalkDir::collect_denied`], otherwise they are yielded as errors.
+    /// The list is complete once the iterator is exhausted.
+    pub fn denied(&self) -> Vec<PathBuf> {
+        self.shared.denied.lock().unwrap().clone()
+    }
+}
+
+impl Iterator for ParallelIter {
+    type Item = Result<DirEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Ends when all threads have finished and dropped their senders.
+        let item = self.entries.as_ref()?.recv().ok()?;
+        if let Some(limit) = self.shared.walk.config().limit_reached(self.nr_yielded) {
+            // Threads notice the closed channel on their next entry.
+            self.shared.cancelled.store(true, Ordering::SeqCst);
+            drop(self.entries.take());
+            return Some(Err(Error::truncated(limit)));
+        }
+
+        self.nr_yielded += item.is_ok() as usize;
+        Some(item)
+    }
+}
+
+impl Drop for ParallelIter {
+    fn drop(&mut self) {
+        // Threads notice the closed channel on their next entry.
+        drop(self.entries.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Shared {
+    fn work(&self, index: usize, sender: &mpsc::SyncSender<Result<DirEntry, Error>>) {
+        while let Some(unit) = self.next_unit(index) {
+            if self.visit(index, unit, sender).is_err() {
+                self.cancelled.store(true, Ordering::SeqCst);
+            }
+
+            if self.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+                // That was the last one, release everyone waiting for more.
+                self.wakeup.notify_all();
+            }
+        }
+    }
+
+    fn push(&self, index: usize, unit: Unit) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        self.queues[index].lock().unwrap().push_back(unit);
+        self.wakeup.notify_one();
+    }
+
+    /// Take work from our own queue or steal it from another thread.
+    ///
+    /// Returns `None` once there is no work left anywhere.
+    fn next_unit(&self, index: usize) -> Option<Unit> {
+        loop {
+            if self.cancelled.load(Ordering::SeqCst) {
+                return None;
+            }
+
+            if let Some(unit) = self.queues[index].lock().unwrap().pop_back() {
+                return Some(unit);
+            }
+
+            let others = self
+                .queues
+                .iter()
+                .cycle()
+                .skip(index + 1)
+                .take(self.queues.len() - 1);
+            for queue in others {
+                if let Some(unit) = queue.lock().unwrap().pop_front() {
+                    return Some(unit);
+                }
+            }
+
+            if self.pending.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
+
+            // Work may be queued between our check and the wait, hence the timeout.
+            let guard = self.idle.lock().unwrap();
+            let _ = self.wakeup.wait_timeout(guard, IDLE_WAIT).unwrap();
+        }
+    }
+
+    /// Read one directory, queue its subdirectories and send all entries.
+    ///
+    /// Fails if the consumer is gone, or the walk has to be aborted.
+    fn visit(
+        &self,
+        index: usize,
+        mut unit: Unit,
+        sender: &mpsc::SyncSender<Result<DirEntry, Error>>,
+    ) -> Result<(), ()> {
+        let config = self.walk.config();
+
+        // The root is the first ancestor, and its device is the one to stay on.
+        let mut revisited = false;
+        if unit.is_root && (config.checks_loops() || config.same_file_system) {
+            // Errors are reported when the directory is opened.
+            if let Ok(id) = self.walk.identify(&unit.path, None) {
+                unit.root_device = Some(id.0);
+                revisited = config.visit_dirs_once && !self.first_visit(id);
+                if config.checks_loops() {
+                    unit.ancestors = Some(Arc::new(Ancestor {
+                        path: unit.path.clone(),
+                        id,
+                        parent: None,
+                    }));
+                }
+            }
+        }
+
+        let level = self.walk
+            .level(unit.path.clone(), unit.base.clone(), unit.is_root)
+            .share_files(self.files.clone(), self.links.clone());
+        // A root that was visited before is yielded again, but not its contents.
+        let level = if revisited { level.max_depth(0) } else { level };
+        #[cfg(feature = "ignore")]
+        let level = level.root_ignores(unit.ignores.clone());
+        for item in level {
+            let item = match item {
+                Ok(mut entry) => {
+                    let is_child = entry.depth() == 1;
+                    entry.add_depth(unit.depth);
+                    if is_child && config.excludes(&entry) {
+                        continue;
+                    }
+                    if is_child && entry.file_type().is_dir() && entry.depth() < config.max_depth {
+                        match self.sub_unit(&unit, &entry) {
+                            Ok(Some(sub)) => self.push(index, sub),
+                            Ok(None) => {}
+                            Err(err) => {
+                                self.report(err, sender)?;
+                                continue;
+                            }
+                        }
+                    }
+
+                    if entry.depth() < config.min_depth || !config.yields(&entry) {
+                        continue;
+                    }
+
+                    Ok(entry)
+                }
+                Err(mut err) => {
+                    err.add_depth(unit.depth);
+                    // The walk of the unit already ended, the other threads have to stop as well.
+                    if config.vanished == Vanished::Abort && err.is_vanished() {
+                        let _ = sender.send(Err(err));
+                        return Err(());
+                    }
+                    self.report(err, sender)?;
+                    continue;
+                }
+            };
+
+            sender.send(item).map_err(drop)?;
+        }
+
+        Ok(())
+    }
+
+    /// Send an error according to the policy of the walk.
+    ///
+    /// Fails if the consumer is gone, or the walk has to be aborted.
+    fn report(&self, err: Error, sender: &mpsc::SyncSender<Result<DirEntry, Error>>)
+        -> Result<(), ()>
+    {
+        let config = self.walk.config();
+        if config.collect_denied && err.is_denied() {
+            self.denied.lock().unwrap().extend(err.path().map(Path::to_owned));
+            return Ok(());
+        }
+
+        match config.decide(&err) {
+            Decision::Ignore => Ok(()),
+            Decision::Yield => sender.send(Err(err)).map_err(drop),
+            Decision::Abort => {
+                let _ = sender.send(Err(err));
+                Err(())
+            }
+        }
+    }
+
+    /// Remember a directory that is descended into, returns if it was not visited before.
+    fn first_visit(&self, id: (libc::dev_t, libc::ino_t)) -> bool {
+        let id = FileId { dev: id.0, ino: id.1 };
+        self.dirs.lock().unwrap_or_else(|err| err.into_inner()).insert(id)
+    }
+
+    /// The unit for a subdirectory, unless it is on another file system or a skipped loop.
+    ///
+    /// Fails if the directory is one of its ancestors.
+    fn sub_unit(&self, unit: &Unit, entry: &DirEntry) -> Result<Option<Unit>, Error> {
+        let config = self.walk.config();
+        let mut sub = Unit {
+            path: entry.path().to_owned(),
+            base: entry.base(),
+            depth: entry.depth(),
+            is_root: false,
+            ancestors: None,
+            root_device: unit.root_device,
+            #[cfg(feature = "ignore")]
+            ignores: entry.parent_ignores(),
+        };
+
+        if !config.checks_loops() && !config.same_file_system {
+            return Ok(Some(sub));
+        }
+
+        // Errors are reported when the directory is opened.
+        let id = match self.walk.identify(&sub.path, sub.base.as_deref()) {
+            Ok(id) => id,
+            Err(_) => return Ok(Some(sub)),
+        };
+
+        if let Some(ancestor) = unit.find_ancestor(id) {
+            if config.skip_loops {
+                return Ok(None);
+            }
+            let err = Error::file_system_loop(sub.path, ancestor.path.clone(), sub.depth);
+            return Err(err);
+        }
+
+        if config.same_file_system && unit.root_device != Some(id.0) {
+            return Ok(None);
+        }
+
+        if config.visit_dirs_once && !self.first_visit(id) {
+            return Ok(None);
+        }
+
+        if config.checks_loops() {
+            sub.ancestors = Some(Arc::new(Ancestor {
+                path: sub.path.clone(),
+                id,
+                parent: unit.ancestors.clone(),
+            }));
+        }
+
+        Ok(Some(sub))
+    }
+}
+
+impl Unit {
+    fn find_ancestor(&self, id: (libc::dev_t, libc::ino_t)) -> Option<&Ancestor> {
+        let mut next = self.ancestors.as_deref();
+        while let Some(ancestor) = next {
+            if ancestor.id == id {
+                return Some(ancestor);
+            }
+            next = ancestor.parent.as_deref();
+        }
+        None
+    }
+}
@@ -0,0 +1,120 @@
+//! Opening the roots of a walk up front, on helper threads.
+//!
+//! Each root is opened as soon as a helper is free, and handed to the walk in the order they
+//! finish. A slow root, like one on a network mount, then only delays itself.
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// The roots that are opened by helper threads.
+pub(crate) struct Preopen<T> {
+    work: Arc<Work<T>>,
+    /// Only locked to share the iterator of the walk across threads.
+    receiver: Mutex<mpsc::Receiver<(usize, T)>>,
+    /// The roots that were handed to the walk, by their index.
+    received: Vec<bool>,
+    /// The roots that were not yet taken by the walk.
+    pending: usize,
+    /// The helper threads that were started.
+    helpers: usize,
+}
+
+struct Work<T> {
+    roots: Vec<PathBuf>,
+    /// The index of the first root that no helper has started on.
+    next: AtomicUsize,
+    open: Box<dyn Fn(PathBuf) -> T + Send + Sync>,
+}
+
+impl<T: Send + 'static> Preopen<T> {
+    /// Open the roots on at most `threads` helpers.
+    ///
+    /// Each helper holds at most one opened root until the walk takes it.
+    pub(crate) fn start<F>(roots: Vec<PathBuf>, threads: usize, open: F) -> Self where
+        F: Fn(PathBuf) -> T + Send + Sync + 'static,
+    {
+        let pending = roots.len();
+        let work = Arc::new(Work {
+            roots,
+            next: AtomicUsize::new(0),
+            open: Box::new(open),
+        });
+
+        let (sender, receiver) = mpsc::sync_channel(0);
+        let mut helpers = 0;
+        for _ in 0..threads.min(pending) {
+            let work = Arc::clone(&work);
+            let sender = sender.clone();
+            // Roots that no helper takes are opened by the walk itself.
+            let spawned = thread::Builder::new()
+                .name("sprint-dir preopen".into())
+                .spawn(move || {
+                    while let Some(index) = work.next_root() {
+                        let root = (work.open)(work.roots[index].clone());
+                        // The walk is gone, the root is closed again.
+                        if sender.send((index, root)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            helpers += usize::from(spawned.is_ok());
+        }
+
+        Preopen {
+            received: vec![false; pending],
+            work,
+            receiver: Mutex::new(receiver),
+            pending,
+            helpers,
+        }
+    }
+
+    /// The next root that was opened, waiting for one. `None` once all of them were taken.
+    pub(crate) fn next(&mut self) -> Option<T> {
+        self.pending = self.pending.checked_sub(1)?;
+        let receiver = self.receiver.get_mut().unwrap_or_else(|err| err.into_inner());
+        let (index, root) = match receiver.recv() {
+            Ok(received) => received,
+            // All helpers are gone, or none could be started.
+            Err(_) => {
+                let index = self.work.next_root()?;
+                (index, (self.work.open)(self.work.roots[index].clone()))
+            }
+        };
+        self.received[index] = true;
+        Some(root)
+    }
+
+    /// If there are roots that the walk did not take yet.
+    pub(crate) fn is_pending(&self) -> bool {
+        self.pending > 0
+    }
+
+    /// The helper threads that were started.
+    pub(crate) fn helpers(&self) -> usize {
+        self.helpers
+    }
+
+    /// Stop the helpers, without waiting for them.
+    ///
+    /// Returns the roots that the walk did not take yet, in their order. Roots that helpers are
+    /// still opening are closed again once they are done.
+    pub(crate) fn stop(self) -> Vec<PathBuf> {
+        self.work.next.store(self.work.roots.len(), Ordering::Relaxed);
+        self.work.roots
+            .iter()
+            .zip(&self.received)
+            .filter(|(_, &received)| !received)
+            .map(|(root, _)| root.clone())
+            .collect()
+    }
+}
+
+impl<T> Work<T> {
+    /// Claim the index of the next root to open.
+    fn next_root(&self) -> Option<usize> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        (index < self.roots.len()).then_some(index)
+    }
+}
@@ -0,0 +1,144 @@
+//! Summing up the sizes of directories, like `du(1)`.
+use std::collections::HashSet;
+use std::iter::FusedIterator;
+
+use crate::events::{Event, Events};
+use crate::walker::{DirEntry, Error};
+
+/// The total size of a directory and all of its contents.
+#[derive(Debug, Clone)]
+pub struct DirSize {
+    entry: DirEntry,
+    total: Total,
+}
+
+/// An iterator over the sizes of all directories of a walk, each after its contents.
+///
+/// Created by [`WalkDir::aggregate_sizes`](crate::WalkDir::aggregate_sizes).
+pub struct Sizes {
+    events: Events,
+    /// The totals of the directories that were entered, the innermost last.
+    totals: Vec<Total>,
+    /// The files with more than one link that were counted already, by device and inode.
+    links: Option<HashSet<(u64, u64)>>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Total {
+    len: u64,
+    blocks: u64,
+    entries: u64,
+}
+
+impl DirSize {
+    /// The directory.
+    pub fn entry(&self) -> &DirEntry {
+        &self.entry
+    }
+
+    /// Convert into the directory entry.
+    pub fn into_entry(self) -> DirEntry {
+        self.entry
+    }
+
+    /// The sum of the lengths of the directory and its contents, in bytes.
+    pub fn size(&self) -> u64 {
+        self.total.len
+    }
+
+    /// The disk space allocated for the directory and its contents, in bytes.
+    pub fn disk_usage(&self) -> u64 {
+        self.total.blocks * 512
+    }
+
+    /// The number of entries below the directory, not counting itself.
+    pub fn entries(&self) -> u64 {
+        self.total.entries
+    }
+}
+
+impl Sizes {
+    pub(crate) fn new(events: Events, count_links_once: bool) -> Self {
+        Sizes {
+            events,
+            totals: vec![],
+            links: if count_links_once { Some(HashSet::new()) } else { None },
+        }
+    }
+
+    /// The size of a single entry, nothing for files that were counted before.
+    fn measure(&mut self, entry: &DirEntry) -> Result<Total, Error> {
+        // Relative to the open directory, this is a single `fstatat`.
+        let meta = entry.metadata_cached().map_err(|err| Error::from_stat(entry, err))?;
+        if let Some(links) = &mut self.links {
+            if !meta.is_dir() && meta.nlink() > 1 && !links.insert((meta.dev(), meta.ino())) {
+                return Ok(Total::default());
+            }
+        }
+
+        Ok(Total {
+            len: meta.len(),
+            blocks: meta.blocks(),
+            entries: 0,
+        })
+    }
+}
+
+impl Total {
+    fn add(&mut self, other: Total) {
+        self.len += other.len;
+        self.blocks += other.blocks;
+        self.entries += other.entries;
+    }
+}
+
+impl Iterator for Sizes {
+    type Item = Result<DirSize, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let event = match self.events.next()? {
+                Ok(event) => event,
+                Err(err) => return Some(Err(err)),
+            };
+
+            match event {
+                Event::Enter(entry) => match self.measure(&entry) {
+                    Ok(own) => self.totals.push(own),
+                    Err(err) => {
+                        // The directory is entered anyways, its contents are still summed up.
+                        self.totals.push(Total::default());
+                        return Some(Err(err));
+                    }
+                },
+                Event::File(entry) => {
+                    let own = self.measure(&entry);
+                    // Only the roots have no parent, they are not part of any total.
+                    let parent = match self.totals.last_mut() {
+                        Some(parent) => parent,
+                        None => match own {
+                            Ok(_) => continue,
+                            Err(err) => return Some(Err(err)),
+                        },
+                    };
+
+                    parent.entries += 1;
+                    match own {
+                        Ok(own) => parent.add(own),
+                        Err(err) => return Some(Err(err)),
+                    }
+                }
+                Event::Leave(entry) => {
+                    let total = self.totals.pop().unwrap_or_default();
+                    if let Some(parent) = self.totals.last_mut() {
+                        parent.add(total);
+                        parent.entries += 1;
+                    }
+                    return Some(Ok(DirSize { entry, total }));
+                }
+            }
+        }
+    }
+}
+
+impl FusedIterator for Sizes {}
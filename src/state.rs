@@ -0,0 +1,55 @@
+//! Saving the progress of a walk, to continue it later.
+use crate::UnixFileType as FileTypeInner;
+
+/// The pending work of a walk, see [`IntoIter::save_state`](crate::IntoIter::save_state).
+///
+/// Holds the directories that were not visited completely, with the entries read from them but
+/// not yet yielded and the offset at which to read the rest. Continue the walk with
+/// [`WalkDir::resume`](crate::WalkDir::resume). With the `serde` feature it can be serialized, to
+/// continue the walk in another process.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WalkState {
+    /// From the outermost directory to the innermost.
+    pub(crate) dirs: Vec<DirState>,
+    /// Directories that are yielded after their contents, with their depth, innermost last.
+    pub(crate) deferred: Vec<(usize, EntryState)>,
+    /// Directories whose contents are visited in a later level of a breadth first walk.
+    pub(crate) queue: Vec<(usize, EntryState)>,
+    pub(crate) root_device: Option<libc::dev_t>,
+}
+
+/// A directory that was not visited completely.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct DirState {
+    /// The path of the directory, `None` for the root of the walk before it is visited.
+    pub(crate) path: Option<Vec<u8>>,
+    /// The depth of its entries.
+    pub(crate) depth: usize,
+    /// The device and inode of the directory, if they were determined.
+    pub(crate) id: Option<(libc::dev_t, libc::ino_t)>,
+    /// The entries read but not yet yielded, the next one last.
+    pub(crate) entries: Vec<EntryState>,
+    /// The offset at which to read the remaining entries, if there are any.
+    pub(crate) offset: Option<i64>,
+}
+
+/// An entry that was not yet yielded.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct EntryState {
+    /// The file name, or the full path for entries without a directory.
+    pub(crate) name: Vec<u8>,
+    pub(crate) file_type: Option<FileTypeInner>,
+    pub(crate) ino: Option<u64>,
+    pub(crate) offset: Option<i64>,
+}
+
+impl WalkState {
+    /// If nothing is left to visit.
+    pub fn is_done(&self) -> bool {
+        let pending = |dir: &DirState| !dir.entries.is_empty() || dir.offset.is_some();
+        !self.dirs.iter().any(pending) && self.deferred.is_empty() && self.queue.is_empty()
+    }
+}
@@ -0,0 +1,43 @@
+//! Walking a tree as an async stream, with the `tokio` feature.
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use tokio::sync::mpsc;
+
+use crate::walker::{DirEntry, Error, IntoIter};
+
+/// Entries that may be waiting for the consumer.
+const CHANNEL_DEPTH: usize = 1 << 10;
+
+/// An async stream over the entries of a tree.
+///
+/// Created by [`WalkDir::into_stream`](crate::WalkDir::into_stream). The walk runs on the blocking
+/// pool of the tokio runtime and waits while too many entries were not yet polled. Dropping the
+/// stream ends the walk.
+pub struct WalkStream {
+    entries: mpsc::Receiver<Result<DirEntry, Error>>,
+}
+
+impl WalkStream {
+    pub(crate) fn new(it: IntoIter) -> Self {
+        let (sender, entries) = mpsc::channel(CHANNEL_DEPTH);
+        tokio::task::spawn_blocking(move || {
+            for entry in it {
+                if sender.blocking_send(entry).is_err() {
+                    break;
+                }
+            }
+        });
+
+        WalkStream { entries }
+    }
+}
+
+impl Stream for WalkStream {
+    type Item = Result<DirEntry, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.entries.poll_recv(cx)
+    }
+}
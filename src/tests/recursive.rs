@@ -6,7 +6,7 @@ use crate::WalkDir;
 
 #[test]
 fn send_sync_traits() {
-    use crate::{FilterEntry, IntoIter};
+    use crate::{DirEntry, Error, FilterEntry, IntoIter, ParallelIter, Parent, WalkState};
 
     fn assert_send<T: Send>() {}
     fn assert_sync<T: Sync>() {}
@@ -17,6 +17,55 @@ fn send_sync_traits() {
     assert_sync::<IntoIter>();
     assert_send::<FilterEntry<IntoIter, u8>>();
     assert_sync::<FilterEntry<IntoIter, u8>>();
+    assert_send::<DirEntry>();
+    assert_sync::<DirEntry>();
+    assert_send::<Parent>();
+    assert_sync::<Parent>();
+    assert_send::<Error>();
+    assert_sync::<Error>();
+    assert_send::<WalkState>();
+    assert_send::<ParallelIter>();
+}
+
+#[test]
+fn send_between_threads() {
+    use std::sync::mpsc;
+    use std::thread;
+
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b/c");
+    dir.mkdirp("d/e");
+    dir.touch_all(&["a/1", "a/b/2", "a/b/c/3", "d/e/4"]);
+
+    let expected = dir.run_recursive(WalkDir::new(dir.path())).sorted_paths();
+
+    // Start on this thread, then continue on another one which sends the entries back.
+    let mut it = WalkDir::new(dir.path()).max_open(2).into_iter();
+    let mut found = vec![];
+    for entry in it.by_ref().take(3) {
+        found.push(entry.unwrap());
+    }
+
+    let (send, recv) = mpsc::channel();
+    let walker = thread::spawn(move || {
+        for entry in it {
+            send.send(entry.unwrap()).unwrap();
+        }
+    });
+
+    // Used while the walk goes on, with the descriptors of their directories open or not.
+    for entry in recv {
+        assert_eq!(entry.file_type(), entry.stat().unwrap().file_type());
+        found.push(entry);
+    }
+    walker.join().unwrap();
+
+    for entry in &found {
+        assert_eq!(entry.file_type(), entry.stat().unwrap().file_type());
+    }
+    let mut paths: Vec<_> = found.iter().map(|entry| entry.path().to_owned()).collect();
+    paths.sort();
+    assert_eq!(paths, expected);
 }
 
 #[test]
@@ -643,6 +692,46 @@ fn sym_loop_detect() {
 
     assert_eq!(4, err.depth());
     assert!(err.io_error().is_none());
+    assert!(err.to_string().contains("loop"));
+}
+
+#[test]
+fn sym_loop_skip() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b/c");
+    dir.symlink_dir("a", "a/b/c/a-link");
+
+    let wd = WalkDir::new(dir.path()).follow_links(true).skip_loops(true);
+    let r = dir.run_recursive(wd);
+    r.assert_no_errors();
+
+    // The looping link is yielded, but not descended into.
+    let expected = vec![
+        dir.path().to_path_buf(),
+        dir.join("a"),
+        dir.join("a/b"),
+        dir.join("a/b/c"),
+        dir.join("a/b/c/a-link"),
+    ];
+    assert_eq!(expected, r.sorted_paths());
+
+    let wd = WalkDir::new(dir.path()).follow_links(true).skip_loops(true).threads(2);
+    let r = dir.run_recursive(wd.into_parallel_iter());
+    r.assert_no_errors();
+    assert_eq!(expected, r.sorted_paths());
+}
+
+#[test]
+fn detect_loops_without_links() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b/c");
+    dir.symlink_dir("a", "a/b/c/a-link");
+
+    // Links are not followed, so they do not form a loop.
+    let wd = WalkDir::new(dir.path()).detect_loops(true);
+    let r = dir.run_recursive(wd);
+    r.assert_no_errors();
+    assert_eq!(5, r.ents().len());
 }
 
 #[test]
@@ -792,6 +881,20 @@ fn min_max_depth_diff_nada() {
     assert_eq!(expected, r.sorted_paths());
 }
 
+#[test]
+fn max_min_depth_clamped() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b/c");
+
+    // The bound set last is clamped to the other one.
+    let wd = WalkDir::new(dir.path()).max_depth(2).min_depth(3);
+    let r = dir.run_recursive(wd);
+    r.assert_no_errors();
+
+    let expected = vec![dir.join("a").join("b")];
+    assert_eq!(expected, r.sorted_paths());
+}
+
 #[test]
 fn min_max_depth_diff_0() {
     let dir = Dir::tmp();
@@ -831,6 +934,27 @@ fn contents_first() {
     assert_eq!(expected, r.paths());
 }
 
+#[test]
+fn contents_first_nested() {
+    let dir = Dir::tmp();
+    dir.mkdirp("foo/bar");
+    dir.touch_all(&["foo/a", "foo/bar/b"]);
+
+    let wd = WalkDir::new(dir.path()).contents_first(true);
+    let r = dir.run_recursive(wd);
+    r.assert_no_errors();
+
+    let paths = r.paths();
+    assert_eq!(5, paths.len());
+    assert_eq!(dir.path(), paths[4]);
+
+    // Every directory comes after everything below it.
+    let position = |path: &std::path::Path| paths.iter().position(|p| p == path).unwrap();
+    assert!(position(&dir.join("foo/bar/b")) < position(&dir.join("foo/bar")));
+    assert!(position(&dir.join("foo/bar")) < position(&dir.join("foo")));
+    assert!(position(&dir.join("foo/a")) < position(&dir.join("foo")));
+}
+
 #[test]
 fn skip_current_dir() {
     let dir = Dir::tmp();
@@ -978,3 +1102,2649 @@ fn regression_skip_current_dir() {
     wd.skip_current_dir();
     wd.next();
 }
+
+#[test]
+fn filter_name() {
+    let dir = Dir::tmp();
+    dir.mkdirp("foo/bar");
+    dir.mkdirp("skip/bar");
+    dir.touch_all(&["foo/a", "foo/skip", "skip/b"]);
+
+    let wd = WalkDir::new(dir.path())
+        .filter_name(|name| !name.starts_with(b"skip"));
+    let r = dir.run_recursive(wd);
+    r.assert_no_errors();
+
+    let expected = vec![
+        dir.path().to_path_buf(),
+        dir.join("foo"),
+        dir.join("foo").join("a"),
+        dir.join("foo").join("bar"),
+    ];
+    assert_eq!(expected, r.sorted_paths());
+}
+
+#[test]
+fn parent_handle() {
+    let dir = Dir::tmp();
+    dir.mkdirp("foo");
+    dir.touch_all(&["foo/a", "foo/b", "c"]);
+
+    let wd = WalkDir::new(dir.path());
+    let r = dir.run_recursive(wd);
+    r.assert_no_errors();
+
+    let ents = r.sorted_ents();
+    assert!(ents[0].parent().is_none());
+
+    let parent_of = |ent: &crate::DirEntry| ent.parent().unwrap();
+    let (c, foo, a, b) = (&ents[1], &ents[2], &ents[3], &ents[4]);
+    assert_eq!(parent_of(a), parent_of(b));
+    assert_eq!(parent_of(c), parent_of(foo));
+    assert_ne!(parent_of(a), parent_of(c));
+    assert_eq!(dir.join("foo"), parent_of(a).path());
+    assert_eq!(dir.path(), parent_of(c).path());
+}
+
+#[test]
+fn into_remaining() {
+    let dir = Dir::tmp();
+    dir.mkdirp("foo/bar");
+    dir.mkdirp("quux");
+    dir.touch_all(&["foo/a", "foo/bar/b", "quux/c"]);
+
+    let all = dir.run_recursive(WalkDir::new(dir.path())).sorted_paths();
+
+    let mut it = WalkDir::new(dir.path()).into_iter();
+    let mut paths: Vec<_> = it
+        .by_ref()
+        .take(3)
+        .map(|ent| ent.unwrap().into_path())
+        .collect();
+
+    for root in it.into_remaining().unwrap() {
+        let r = dir.run_recursive(WalkDir::new(root));
+        r.assert_no_errors();
+        paths.extend(r.paths());
+    }
+
+    paths.sort();
+    assert_eq!(all, paths);
+
+    dir.mkdirp("zed");
+    dir.touch_all(&["zed/d"]);
+    let all = dir.run_recursive(WalkDir::new(dir.path())).sorted_paths();
+
+    // The directories still to be yielded after their contents, and none of a skipped one.
+    let mut it = WalkDir::new(dir.path()).contents_first(true).sort_by_file_name().into_iter();
+    let mut paths: Vec<_> = it
+        .by_ref()
+        .take(2)
+        .map(|ent| ent.unwrap().into_path())
+        .collect();
+    assert_eq!(paths, [dir.join("foo/a"), dir.join("foo/bar/b")]);
+    it.skip_descendants_of(dir.join("quux"));
+
+    let remaining = it.into_remaining().unwrap();
+    let (subtrees, deferred) = remaining.split_at(1);
+    assert_eq!(subtrees, [dir.join("zed")]);
+    assert_eq!(deferred, [dir.join("foo/bar"), dir.join("foo"), dir.path().to_owned()]);
+    for root in subtrees {
+        paths.extend(dir.run_recursive(WalkDir::new(root)).paths());
+    }
+    for root in deferred {
+        paths.extend(dir.run_recursive(WalkDir::new(root).max_depth(0)).paths());
+    }
+
+    paths.sort();
+    let all: Vec<_> = all.into_iter().filter(|path| !path.starts_with(dir.join("quux"))).collect();
+    assert_eq!(all, paths);
+}
+
+#[test]
+fn require_dir_root() {
+    let dir = Dir::tmp();
+    dir.touch("a");
+
+    let wd = WalkDir::new(dir.join("a")).require_dir_root(true);
+    let r = dir.run_recursive(wd);
+
+    assert_eq!(0, r.ents().len());
+    assert_eq!(1, r.errs().len());
+
+    let err = &r.errs()[0];
+    assert_eq!(Some(&*dir.join("a")), err.path());
+    assert_eq!(0, err.depth());
+    assert_eq!(Some(libc::ENOTDIR), err.io_error().and_then(|err| err.raw_os_error()));
+
+    let wd = WalkDir::new(dir.path()).require_dir_root(true);
+    let r = dir.run_recursive(wd);
+    r.assert_no_errors();
+    assert_eq!(vec![dir.path().to_path_buf(), dir.join("a")], r.sorted_paths());
+}
+
+#[test]
+fn raw_byte_root() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = Dir::tmp();
+    dir.mkdirp(std::ffi::OsStr::from_bytes(b"\xff"));
+    dir.touch(std::ffi::OsStr::from_bytes(b"\xff/a"));
+
+    let mut root = dir.path().as_os_str().as_bytes().to_owned();
+    root.extend_from_slice(b"/\xff");
+    let r = dir.run_recursive(WalkDir::from_bytes(root.clone()));
+    r.assert_no_errors();
+
+    let root = PathBuf::from(std::ffi::OsStr::from_bytes(&root));
+    assert_eq!(vec![root.clone(), root.join("a")], r.sorted_paths());
+}
+
+#[test]
+fn walk_by_reference() {
+    let dir = Dir::tmp();
+    dir.mkdirp("foo");
+    dir.touch("foo/a");
+
+    let wd = WalkDir::new(dir.path());
+    let first = dir.run_recursive(&wd);
+    let second = dir.run_recursive(&wd);
+    first.assert_no_errors();
+    second.assert_no_errors();
+
+    assert_eq!(3, first.ents().len());
+    assert_eq!(first.sorted_paths(), second.sorted_paths());
+}
+
+#[test]
+fn walk_from_entry() {
+    let dir = Dir::tmp();
+    dir.mkdirp("foo/bar");
+    dir.touch_all(&["foo/bar/a", "b"]);
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()));
+    r.assert_no_errors();
+
+    let foo = r.ents().iter().find(|ent| ent.file_name() == "foo").unwrap();
+    let sub = dir.run_recursive(foo.walk());
+    sub.assert_no_errors();
+
+    let expected = vec![
+        dir.join("foo"),
+        dir.join("foo").join("bar"),
+        dir.join("foo").join("bar").join("a"),
+    ];
+    assert_eq!(expected, sub.sorted_paths());
+    assert_eq!(0, sub.sorted_ents()[0].depth());
+}
+
+#[test]
+fn walk_from_entry_in_open_dir() {
+    let dir = Dir::tmp();
+    dir.mkdirp("root/foo/bar");
+    dir.touch_all(&["root/foo/bar/a", "root/b"]);
+
+    let mut it = WalkDir::new(dir.join("root")).sort_by_file_name().into_iter();
+    it.next().unwrap().unwrap();
+    it.next().unwrap().unwrap();
+    let foo = it.next().unwrap().unwrap();
+    assert_eq!(foo.file_name(), "foo");
+
+    // The path of the entry no longer resolves, its open directory still does.
+    fs::rename(dir.join("root"), dir.join("moved")).unwrap();
+    let sub = dir.run_recursive(foo.walk());
+    sub.assert_no_errors();
+
+    let names: Vec<_> = sub.sorted_ents().iter().map(|ent| ent.file_name().to_owned()).collect();
+    assert_eq!(names, ["foo", "bar", "a"]);
+    assert_eq!(sub.ents()[0].path(), dir.join("root").join("foo"));
+}
+
+#[test]
+fn split() {
+    let dir = Dir::tmp();
+    dir.mkdirp("foo/bar");
+    dir.mkdirp("quux");
+    dir.touch_all(&["foo/a", "foo/b", "foo/bar/c", "quux/d", "quux/e"]);
+
+    let depths = |ents: &mut dyn Iterator<Item = crate::DirEntry>| {
+        ents.map(|ent| (ent.depth(), ent.into_path())).collect::<Vec<_>>()
+    };
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()));
+    r.assert_no_errors();
+    let mut all = depths(&mut r.ents().iter().cloned());
+    all.sort();
+
+    let mut it = WalkDir::new(dir.path()).into_iter();
+    let mut found = depths(&mut it.by_ref().take(3).map(Result::unwrap));
+
+    let (left, right) = it.split().unwrap();
+    let left = dir.run_recursive(left);
+    let right = dir.run_recursive(right);
+    left.assert_no_errors();
+    right.assert_no_errors();
+    assert!(!left.ents().is_empty());
+    assert!(!right.ents().is_empty());
+
+    found.extend(depths(&mut left.ents().iter().cloned()));
+    found.extend(depths(&mut right.ents().iter().cloned()));
+    found.sort();
+    assert_eq!(all, found);
+}
+
+#[test]
+fn verify_file_types() {
+    let dir = Dir::tmp();
+    dir.mkdirp("foo/bar");
+    dir.touch_all(&["foo/a", "b"]);
+    dir.symlink_file("b", "c");
+
+    let wd = WalkDir::new(dir.path()).verify_file_types(1);
+    let r = dir.run_recursive(wd);
+    r.assert_no_errors();
+
+    let ents = r.sorted_ents();
+    assert_eq!(6, ents.len());
+    assert!(ents[1].file_type().is_file());
+    assert!(ents[2].file_type().is_symlink());
+    assert!(ents[3].file_type().is_dir());
+}
+
+#[test]
+fn typed_root() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a");
+    dir.touch("a/zzz");
+    dir.symlink_dir("a", "a-link");
+
+    let r = dir.run_recursive(WalkDir::new(dir.join("a-link")));
+    r.assert_no_errors();
+
+    let ents = r.sorted_ents();
+    assert_eq!(2, ents.len());
+    assert!(ents[0].file_type().is_symlink());
+    assert_eq!(dir.join("a-link").join("zzz"), ents[1].path());
+}
+
+#[test]
+fn max_open_deep() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b/c/d/e");
+    dir.mkdirp("a/x/y");
+    dir.touch_all(&["a/1", "a/b/2", "a/b/c/3", "a/b/c/d/4", "a/x/5"]);
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()));
+    r.assert_no_errors();
+    let expected = r.sorted_paths();
+
+    for max_open in 1..4 {
+        let wd = WalkDir::new(dir.path()).max_open(max_open);
+        let r = dir.run_recursive(wd);
+        r.assert_no_errors();
+        assert_eq!(expected, r.sorted_paths());
+
+        let wd = WalkDir::new(dir.path()).max_open(max_open).contents_first(true);
+        let r = dir.run_recursive(wd);
+        r.assert_no_errors();
+        assert_eq!(expected, r.sorted_paths());
+        assert_eq!(dir.path(), r.paths().last().unwrap());
+    }
+}
+
+#[test]
+fn parallel() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b/c/d/e");
+    dir.mkdirp("a/x/y");
+    dir.touch_all(&["a/1", "a/b/2", "a/b/c/3", "a/b/c/d/4", "a/x/5"]);
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()));
+    r.assert_no_errors();
+    let expected = r.sorted_paths();
+
+    for threads in 1..4 {
+        let wd = WalkDir::new(dir.path()).threads(threads);
+        let r = dir.run_recursive(wd.into_parallel_iter());
+        r.assert_no_errors();
+        assert_eq!(expected, r.sorted_paths());
+    }
+
+    let wd = WalkDir::new(dir.path()).min_depth(2).max_depth(3);
+    let r = dir.run_recursive(wd);
+    let expected = r.sorted_ents();
+
+    let wd = WalkDir::new(dir.path()).min_depth(2).max_depth(3).threads(2);
+    let r = dir.run_recursive(wd.into_parallel_iter());
+    r.assert_no_errors();
+    let ents = r.sorted_ents();
+    assert_eq!(expected.len(), ents.len());
+    for (expected, ent) in expected.iter().zip(&ents) {
+        assert_eq!(expected.path(), ent.path());
+        assert_eq!(expected.depth(), ent.depth());
+    }
+}
+
+#[test]
+fn parallel_sym_loop_detect() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b/c");
+    dir.symlink_dir("a", "a/b/c/a-link");
+
+    let wd = WalkDir::new(dir.path()).follow_links(true).threads(2);
+    let r = dir.run_recursive(wd.into_parallel_iter());
+
+    let (ents, errs) = (r.sorted_ents(), r.errs());
+    assert_eq!(4, ents.len());
+    assert_eq!(1, errs.len());
+
+    let err = &errs[0];
+    assert_eq!(Some(&*dir.join("a/b/c/a-link")), err.path());
+    assert_eq!(Some(&*dir.join("a")), err.loop_ancestor());
+    assert_eq!(4, err.depth());
+}
+
+#[test]
+#[cfg(feature = "rayon")]
+fn par_entries() {
+    use rayon::iter::ParallelIterator;
+
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b/c/d/e");
+    dir.mkdirp("a/x/y");
+    dir.mkdirp("f/g");
+    dir.touch_all(&["a/1", "a/b/2", "a/b/c/3", "a/b/c/d/4", "a/x/5", "f/6", "f/g/7"]);
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()));
+    r.assert_no_errors();
+    let expected = r.sorted_paths();
+
+    let ents: Vec<_> = WalkDir::new(dir.path()).par_entries().collect();
+    let r = dir.run_recursive(ents);
+    r.assert_no_errors();
+    assert_eq!(expected, r.sorted_paths());
+
+    let wd = WalkDir::new(dir.path()).min_depth(2).max_depth(3);
+    let expected = dir.run_recursive(wd).sorted_paths();
+    let ents: Vec<_> = WalkDir::new(dir.path()).min_depth(2).max_depth(3).par_entries().collect();
+    let r = dir.run_recursive(ents);
+    r.assert_no_errors();
+    assert_eq!(expected, r.sorted_paths());
+}
+
+#[test]
+fn ino() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b/c");
+    dir.touch_all(&["a/1", "a/b/2", "a/b/c/3"]);
+    dir.symlink_file("a/1", "a/b/c/link");
+
+    let walks = vec![
+        WalkDir::new(dir.path()),
+        WalkDir::new(dir.path()).max_open(1),
+        WalkDir::new(dir.path()).sort_by(|a, b| a.file_name().cmp(b.file_name())),
+    ];
+
+    for wd in walks {
+        let r = dir.run_recursive(wd);
+        r.assert_no_errors();
+
+        let ents = r.sorted_ents();
+        assert_eq!(8, ents.len());
+        assert_eq!(None, ents[0].ino());
+        for ent in &ents[1..] {
+            let meta = fs::symlink_metadata(ent.path()).unwrap();
+            assert_eq!(Some(meta.ino()), ent.ino());
+        }
+    }
+}
+
+#[test]
+fn open_entry() {
+    use std::io::{Read, Write};
+
+    use crate::OpenOptions;
+
+    let dir = Dir::tmp();
+    dir.mkdirp("a");
+    fs::write(dir.join("a/file"), b"contents").unwrap();
+
+    let mut it = WalkDir::new(dir.path()).into_iter();
+    assert_eq!(dir.path(), it.next().unwrap().unwrap().path());
+    assert_eq!(dir.join("a"), it.next().unwrap().unwrap().path());
+
+    // The directory is open, so the entry is found even though its path changed.
+    fs::rename(dir.join("a"), dir.join("z")).unwrap();
+    let file = it.next().unwrap().unwrap();
+    assert_eq!(dir.join("a/file"), file.path());
+
+    let mut contents = String::new();
+    file.open().unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!("contents", contents);
+
+    let mut options = OpenOptions::new();
+    options.append(true);
+    file.open_with(&options).unwrap().write_all(b" and more").unwrap();
+    assert_eq!(b"contents and more", &*fs::read(dir.join("z/file")).unwrap());
+
+    // Without a directory the full path is used.
+    assert!(it.next().is_none());
+    let err = file.open().unwrap_err();
+    assert_eq!(std::io::ErrorKind::NotFound, err.kind());
+
+    let err = file.open_with(&OpenOptions::new()).unwrap_err();
+    assert_eq!(Some(libc::EINVAL), err.raw_os_error());
+}
+
+#[test]
+fn metadata_relative() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = Dir::tmp();
+    dir.mkdirp("a");
+    fs::write(dir.join("a/file"), b"contents").unwrap();
+    dir.symlink_file("a/file", "a/link");
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()));
+    r.assert_no_errors();
+    for ent in r.ents() {
+        let meta = ent.stat().unwrap();
+        let expected = fs::symlink_metadata(ent.path()).unwrap();
+        // The standard meta data, for code that hands it on.
+        let std: fs::Metadata = ent.metadata().unwrap();
+        assert_eq!(expected.ino(), std.ino());
+        assert_eq!(expected.ino(), meta.ino());
+        assert_eq!(expected.len(), meta.len());
+        assert_eq!(expected.modified().unwrap(), meta.modified().unwrap());
+        assert_eq!(expected.is_dir(), meta.is_dir());
+        assert_eq!(expected.file_type().is_symlink(), meta.is_symlink());
+    }
+
+    let wd = WalkDir::new(dir.path()).sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    let mut it = wd.into_iter();
+    it.next().unwrap().unwrap();
+    it.next().unwrap().unwrap();
+
+    // The directory is open, so the entry is found even though its path changed.
+    fs::rename(dir.join("a"), dir.join("z")).unwrap();
+    let file = it.next().unwrap().unwrap();
+    assert_eq!(dir.join("a/file"), file.path());
+    assert_eq!(8, file.stat().unwrap().len());
+    assert_eq!(8, file.metadata().unwrap().len());
+}
+
+#[test]
+fn read_link() {
+    let dir = Dir::tmp();
+    dir.touch("a");
+    dir.symlink_file("a", "link");
+    // Longer than the initial buffer.
+    let long = ["x".repeat(100), "y".repeat(100), "z".repeat(100)].join("/");
+    dir.mkdirp(&long);
+    dir.symlink_dir(&long, "long");
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()).follow_links(true));
+    r.assert_no_errors();
+
+    let find = |name: &str| {
+        r.ents().iter().find(|ent| ent.file_name() == name).unwrap().clone()
+    };
+
+    let link = find("link");
+    assert_eq!(dir.join("a"), link.read_link().unwrap());
+    assert!(link.metadata().unwrap().is_file());
+    assert!(link.symlink_metadata().unwrap().is_symlink());
+
+    assert_eq!(dir.join(&long), find("long").read_link().unwrap());
+
+    let err = find("a").read_link().unwrap_err();
+    assert_eq!(Some(libc::EINVAL), err.raw_os_error());
+}
+
+#[test]
+fn stats() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.mkdirp("c");
+    dir.touch_all(&["a/1", "c/2"]);
+
+    let mut it = WalkDir::new(dir.path()).into_iter();
+    assert_eq!(dir.path(), it.next().unwrap().unwrap().path());
+    let start = it.stats().clone();
+    assert_eq!(1, start.nr_open());
+    assert_eq!(0, start.nr_openat());
+
+    assert_eq!(5, it.by_ref().count());
+    let stats = it.stats();
+    assert_eq!(1, stats.nr_open());
+    assert_eq!(3, stats.nr_openat());
+    assert_eq!(4, stats.nr_close());
+    assert!(stats.nr_getdent() >= 4);
+
+    let since = stats.since(&start);
+    assert_eq!(0, since.nr_open());
+    assert_eq!(3, since.nr_openat());
+    assert_eq!(since, since.since(&crate::Stats::default()));
+}
+
+#[test]
+#[cfg(feature = "histogram-stats")]
+fn histogram_stats() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.touch_all(&["a/1", "a/2", "a/3", "a/b/4"]);
+
+    let mut it = WalkDir::new(dir.path()).max_open(1).into_iter();
+    assert_eq!(7, it.by_ref().count());
+
+    let histograms = it.stats().histograms();
+    // The root with `a` and `b` with one file, `a` with four entries, all with `.` and `..`.
+    assert_eq!(&[0, 0, 2, 1][..], histograms.entries_per_dir());
+    // Opening `a` and `b` each closed their parent.
+    assert_eq!(2, histograms.nr_spill());
+
+    let by_depth = histograms.by_depth();
+    assert_eq!(3, by_depth.len());
+    for stats in by_depth {
+        assert_eq!(1, stats.nr_open());
+        assert_eq!(0, stats.nr_openat());
+        assert_eq!(1, stats.nr_close());
+    }
+
+    let calls: usize = histograms.getdent_bytes().iter().sum();
+    assert_eq!(it.stats().nr_getdent(), calls);
+    assert_eq!(0, it.stats().since(it.stats()).histograms().nr_spill());
+}
+
+#[test]
+fn buffer_size() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a");
+    let names: Vec<_> = (0..200).map(|i| format!("a/file-with-a-longer-name-{}", i)).collect();
+    dir.touch_all(&names);
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()));
+    r.assert_no_errors();
+    let expected = r.sorted_paths();
+
+    let mut small = WalkDir::new(dir.path()).buffer_size(0).into_iter();
+    let r = dir.run_recursive(small.by_ref());
+    r.assert_no_errors();
+    assert_eq!(expected, r.sorted_paths());
+
+    let mut large = WalkDir::new(dir.path()).buffer_size(1 << 20).into_iter();
+    let r = dir.run_recursive(large.by_ref());
+    r.assert_no_errors();
+    assert_eq!(expected, r.sorted_paths());
+
+    // One call for each directory and one that finds its end.
+    assert_eq!(4, large.stats().nr_getdent());
+    assert!(small.stats().nr_getdent() > 4);
+}
+
+#[test]
+fn skip_current_dir_reused_buffer() {
+    let dir = Dir::tmp();
+    let mut expected = vec![dir.path().to_owned()];
+    for d in 0..10 {
+        dir.mkdirp(format!("d{}", d));
+        dir.touch_all(&(0..5).map(|f| format!("d{}/f{}-{}", d, d, f)).collect::<Vec<_>>());
+        expected.push(dir.join(format!("d{}", d)));
+    }
+
+    let mut it = WalkDir::new(dir.path()).max_open(2).into_iter();
+    let mut files = 0;
+    let mut paths = vec![];
+    while let Some(entry) = it.next() {
+        let entry = entry.unwrap();
+        if entry.depth() == 2 {
+            // Leaves the other files of the directory in its buffer.
+            assert!(entry.path().exists(), "{} does not exist", entry.path().display());
+            files += 1;
+            it.skip_current_dir();
+        } else {
+            paths.push(entry.into_path());
+        }
+    }
+
+    paths.sort();
+    expected.sort();
+    assert_eq!(expected, paths);
+    assert_eq!(10, files);
+}
+
+#[test]
+fn max_buffer_size() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a");
+    let names: Vec<_> = (0..500).map(|i| format!("a/file-with-a-longer-name-{}", i)).collect();
+    dir.touch_all(&names);
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()));
+    r.assert_no_errors();
+    let expected = r.sorted_paths();
+
+    let mut fixed = WalkDir::new(dir.path()).buffer_size(0).max_buffer_size(0).into_iter();
+    let r = dir.run_recursive(fixed.by_ref());
+    r.assert_no_errors();
+    assert_eq!(expected, r.sorted_paths());
+    assert_eq!(0, fixed.stats().nr_buffer_grow());
+
+    let mut grown = WalkDir::new(dir.path()).buffer_size(0).into_iter();
+    let r = dir.run_recursive(grown.by_ref());
+    r.assert_no_errors();
+    assert_eq!(expected, r.sorted_paths());
+    assert!(grown.stats().nr_buffer_grow() > 0);
+    assert!(grown.stats().nr_getdent() < fixed.stats().nr_getdent());
+
+    // The same when the directory is read to the end at once.
+    let wd = WalkDir::new(dir.path()).buffer_size(0).sort_by(|a, b| a.path().cmp(b.path()));
+    let mut sorted = wd.into_iter();
+    let r = dir.run_recursive(sorted.by_ref());
+    r.assert_no_errors();
+    assert_eq!(expected, r.paths());
+    assert!(sorted.stats().nr_buffer_grow() > 0);
+}
+
+#[test]
+fn next_borrowed() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.touch_all(&["f", "a/g", "a/b/a-file-with-a-longer-name", "a/b/h"]);
+    dir.symlink_file("f", "a/link");
+
+    for min_depth in 0..3 {
+        let r = dir.run_recursive(WalkDir::new(dir.path()).min_depth(min_depth));
+        r.assert_no_errors();
+        let expected = r.sorted_paths();
+
+        let mut it = WalkDir::new(dir.path()).min_depth(min_depth).into_iter();
+        let mut paths = vec![];
+        while let Some(entry) = it.next_borrowed() {
+            let entry = entry.unwrap();
+            let path = entry.to_path_buf();
+            assert_eq!(Some(entry.file_name()), path.file_name());
+            let entry = entry.into_owned();
+            assert_eq!(path, entry.path());
+            paths.push(entry.into_path());
+        }
+
+        paths.sort();
+        assert_eq!(expected, paths);
+    }
+}
+
+#[test]
+fn next_chunk() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.touch_all(&["f", "a/g", "a/h", "a/b/i", "a/b/j"]);
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()));
+    r.assert_no_errors();
+    let expected = r.sorted_paths();
+
+    let mut it = WalkDir::new(dir.path()).into_iter();
+    let mut chunk = vec![];
+    assert_eq!(0, it.next_chunk(&mut chunk, 0).unwrap());
+    assert_eq!(3, it.next_chunk(&mut chunk, 3).unwrap());
+    assert_eq!(3, chunk.len());
+    assert_eq!(5, it.next_chunk(&mut chunk, 10).unwrap());
+    assert_eq!(0, it.next_chunk(&mut chunk, 10).unwrap());
+
+    let mut paths: Vec<_> = chunk.into_iter().map(|entry| entry.into_path()).collect();
+    paths.sort();
+    assert_eq!(expected, paths);
+}
+
+#[test]
+fn fold_like_next() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b/c");
+    dir.touch_all(&["f", "a/g", "a/b/h", "a/b/c/i", "a/b/c/j"]);
+    dir.symlink_dir("a", "a/b/link");
+
+    let walks = vec![
+        WalkDir::new(dir.path()),
+        WalkDir::new(dir.path()).min_depth(2),
+        WalkDir::new(dir.path()).max_depth(2),
+        WalkDir::new(dir.path()).contents_first(true),
+        WalkDir::new(dir.path()).follow_links(true),
+        WalkDir::new(dir.path()).verify_file_types(1),
+    ];
+
+    for walk in walks {
+        let r = dir.run_recursive(walk.clone());
+        let expected = r.sorted_paths();
+        let errors = r.errs().len();
+
+        let (mut paths, folded_errors) = walk.into_iter()
+            .fold((vec![], 0), |(mut paths, errors), entry| match entry {
+                Ok(entry) => {
+                    paths.push(entry.into_path());
+                    (paths, errors)
+                }
+                Err(_) => (paths, errors + 1),
+            });
+
+        paths.sort();
+        assert_eq!(expected, paths);
+        assert_eq!(errors, folded_errors);
+    }
+}
+
+#[test]
+fn backlog_names() {
+    let dir = Dir::tmp();
+    let names = ["a", "bb", "a-much-longer-name-than-the-others", "c", "dd.ext"];
+    for sub in &["x", "y", "z"] {
+        dir.mkdirp(format!("{}/inner", sub));
+        let files: Vec<_> = names.iter().map(|name| format!("{}/{}", sub, name)).collect();
+        dir.touch_all(&files);
+    }
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()));
+    r.assert_no_errors();
+    let expected = r.sorted_paths();
+
+    // Every directory is closed while its siblings are visited.
+    let r = dir.run_recursive(WalkDir::new(dir.path()).max_open(1));
+    r.assert_no_errors();
+    assert_eq!(expected, r.sorted_paths());
+    for ent in r.ents() {
+        assert_eq!(ent.path().file_name(), Some(ent.file_name()));
+        assert!(ent.path().symlink_metadata().is_ok(), "{}", ent.path().display());
+    }
+
+    let mut it = WalkDir::new(dir.path()).max_open(1).into_iter();
+    let mut found: Vec<_> = it.by_ref().take(4).map(|ent| ent.unwrap().into_path()).collect();
+    let (left, right) = it.split().unwrap();
+    for half in [left, right] {
+        let r = dir.run_recursive(half);
+        r.assert_no_errors();
+        found.extend(r.ents().iter().map(|ent| ent.path().to_owned()));
+    }
+
+    found.sort();
+    assert_eq!(expected, found);
+}
+
+#[test]
+fn max_backlog() {
+    let dir = Dir::tmp();
+    for sub in &["a", "b", "c"] {
+        dir.mkdirp(format!("{}/inner", sub));
+        let names: Vec<_> = (0..100).map(|i| format!("{}/file-{}", sub, i)).collect();
+        dir.touch_all(&names);
+    }
+    // Directories late in the stream, after the first buffer of their parent.
+    let names: Vec<_> = (0..100).map(|i| format!("file-{}", i)).collect();
+    dir.touch_all(&names);
+    dir.mkdirp("z/inner");
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()));
+    r.assert_no_errors();
+    let expected = r.sorted_paths();
+
+    let walk = WalkDir::new(dir.path()).max_open(1).buffer_size(0).max_backlog(0);
+    let mut it = walk.clone().into_iter();
+    let r = dir.run_recursive(it.by_ref());
+    r.assert_no_errors();
+    assert_eq!(expected, r.sorted_paths());
+    assert!(it.stats().nr_seek() > 0);
+
+    // Entries after reopening still resolve their own paths.
+    for ent in r.ents() {
+        assert!(ent.metadata().is_ok(), "{}", ent.path().display());
+    }
+
+    let mut it = walk.clone().into_iter();
+    let mut found: Vec<_> = it.by_ref().take(50).map(|ent| ent.unwrap().into_path()).collect();
+    for root in it.into_remaining().unwrap() {
+        let r = dir.run_recursive(WalkDir::new(root));
+        r.assert_no_errors();
+        found.extend(r.ents().iter().map(|ent| ent.path().to_owned()));
+    }
+    found.sort();
+    assert_eq!(expected, found);
+
+    let mut it = walk.into_iter();
+    let mut found: Vec<_> = it.by_ref().take(50).map(|ent| ent.unwrap().into_path()).collect();
+    let (left, right) = it.split().unwrap();
+    for half in [left, right] {
+        let r = dir.run_recursive(half);
+        r.assert_no_errors();
+        found.extend(r.ents().iter().map(|ent| ent.path().to_owned()));
+    }
+    found.sort();
+    assert_eq!(expected, found);
+}
+
+#[test]
+fn resume_at() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.mkdirp("c");
+    let names: Vec<_> = (0..20).map(|i| format!("file-{}", i)).collect();
+    dir.touch_all(&names);
+    dir.touch_all(&["a/d", "a/b/e", "c/f"]);
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()));
+    r.assert_no_errors();
+    let expected = r.sorted_paths();
+
+    // Stop at a file in the middle of the root directory.
+    let mut found = vec![];
+    let mut offset = None;
+    for ent in WalkDir::new(dir.path()) {
+        let ent = ent.unwrap();
+        let stop = ent.depth() == 1 && ent.file_type().is_file() && found.len() > 10;
+        if stop {
+            offset = ent.offset();
+        }
+        found.push(ent.into_path());
+        if stop {
+            break;
+        }
+    }
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()).resume_at(offset.unwrap()));
+    r.assert_no_errors();
+    let rest = r.ents();
+    assert_eq!(dir.path(), rest[0].path());
+    assert!(rest.len() > 1);
+    found.extend(rest[1..].iter().map(|ent| ent.path().to_owned()));
+
+    found.sort();
+    assert_eq!(expected, found);
+}
+
+#[test]
+fn save_state() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b/c");
+    dir.mkdirp("d/e");
+    let names: Vec<_> = (0..30).map(|i| format!("a/file-{}", i)).collect();
+    dir.touch_all(&names);
+    dir.touch_all(&["f", "a/b/g", "a/b/c/h", "d/i", "d/e/j"]);
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()));
+    r.assert_no_errors();
+    let expected = r.sorted_paths();
+
+    let walks = vec![
+        WalkDir::new(dir.path()),
+        WalkDir::new(dir.path()).contents_first(true),
+        WalkDir::new(dir.path()).max_open(1).buffer_size(0).max_backlog(0),
+        WalkDir::new(dir.path()).sort_by(|a, b| a.file_name().cmp(b.file_name())),
+        WalkDir::new(dir.path()).breadth_first(true),
+        WalkDir::new(dir.path()).read_ahead(true).buffer_size(0),
+        WalkDir::new(dir.path()).read_ahead(true).max_open(1).buffer_size(0).max_backlog(0),
+        WalkDir::new(dir.path()).descend_priority(|entry| entry.depth() as u32),
+    ];
+
+    for walk in walks {
+        for taken in &[0, 1, 5, 20, 40] {
+            let mut it = walk.clone().into_iter();
+            let mut found: Vec<_> = it.by_ref()
+                .take(*taken)
+                .map(|ent| ent.unwrap().into_path())
+                .collect();
+            let state = it.save_state().unwrap();
+
+            let mut rest = dir.run_recursive(it).sorted_paths();
+            let r = dir.run_recursive(walk.clone().resume(state));
+            r.assert_no_errors();
+            assert_eq!(rest, r.sorted_paths());
+
+            found.append(&mut rest);
+            found.sort();
+            assert_eq!(expected, found);
+        }
+    }
+
+    let mut it = WalkDir::new(dir.path()).into_iter();
+    it.by_ref().for_each(drop);
+    assert!(it.save_state().unwrap().is_done());
+}
+
+#[test]
+fn breadth_first() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b/c/d");
+    dir.mkdirp("e/f");
+    dir.touch_all(&["g", "a/h", "a/b/i", "a/b/c/j", "e/k", "e/f/l"]);
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()));
+    r.assert_no_errors();
+    let expected = r.sorted_paths();
+
+    let walks = vec![
+        WalkDir::new(dir.path()).breadth_first(true),
+        WalkDir::new(dir.path()).breadth_first(true).max_open(1),
+        WalkDir::new(dir.path()).breadth_first(true).contents_first(true),
+        WalkDir::new(dir.path()).breadth_first(true)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name())),
+    ];
+
+    for walk in walks {
+        let r = dir.run_recursive(walk);
+        r.assert_no_errors();
+        let depths: Vec<_> = r.ents().iter().map(|ent| ent.depth()).collect();
+        assert!(depths.windows(2).all(|w| w[0] <= w[1]), "{:?}", depths);
+        assert_eq!(expected, r.sorted_paths());
+    }
+
+    // Skipping a directory that was just yielded leaves it out of the queue.
+    let mut it = WalkDir::new(dir.path()).breadth_first(true).into_iter();
+    let mut paths = vec![];
+    while let Some(ent) = it.next() {
+        let ent = ent.unwrap();
+        if ent.file_name() == "b" {
+            it.skip_current_dir();
+        }
+        paths.push(ent.into_path());
+    }
+    paths.sort();
+    let mut expected_skip: Vec<_> = expected.iter()
+        .filter(|path| !path.starts_with(dir.join("a/b")) || path.ends_with("b"))
+        .cloned()
+        .collect();
+    expected_skip.sort();
+    assert_eq!(expected_skip, paths);
+
+    // The queue is part of the remaining work.
+    for taken in 1..8 {
+        let mut it = WalkDir::new(dir.path()).breadth_first(true).into_iter();
+        let mut found: Vec<_> = it.by_ref()
+            .take(taken)
+            .map(|ent| ent.unwrap().into_path())
+            .collect();
+        for root in it.into_remaining().unwrap() {
+            let r = dir.run_recursive(WalkDir::new(root));
+            r.assert_no_errors();
+            found.extend(r.ents().iter().map(|ent| ent.path().to_owned()));
+        }
+        found.sort();
+        assert_eq!(expected, found);
+    }
+}
+
+#[test]
+fn raw_read_dir() {
+    use crate::raw::{DirentBuf, More};
+    use std::os::unix::io::AsRawFd;
+
+    let dir = Dir::tmp();
+    dir.mkdirp("a");
+    let names: Vec<_> = (0..50).map(|i| format!("file-{}", i)).collect();
+    dir.touch_all(&names);
+
+    let file = std::fs::File::open(dir.path()).unwrap();
+    let mut buf = DirentBuf::with_size(1 << 10);
+    let mut found = vec![];
+    loop {
+        match buf.fill_buf(file.as_raw_fd()).unwrap() {
+            More::More => {},
+            More::Blocked => panic!("Drained buffer is too small"),
+            More::Done => break,
+        }
+        for entry in buf.drain() {
+            let entry = entry.unwrap();
+            if entry.file_name() == "." || entry.file_name() == ".." {
+                continue;
+            }
+            assert!(entry.file_type().is_dir() == (entry.file_name() == "a"));
+            found.push(entry.file_name().to_owned());
+        }
+        assert!(buf.is_empty());
+    }
+
+    let mut expected: Vec<_> = names.iter().map(std::ffi::OsString::from).collect();
+    expected.push("a".into());
+    expected.sort();
+    found.sort();
+    assert_eq!(expected, found);
+}
+
+#[test]
+fn new_at() {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let dir = Dir::tmp();
+    dir.mkdirp("foo/bar/baz");
+    dir.mkdirp("quux/a/b");
+    dir.touch_all(&["foo/bar/baz/x", "quux/a/b/y", "z"]);
+
+    let fd = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_DIRECTORY)
+        .open(dir.path())
+        .unwrap();
+    // A single open directory, so most of them are reopened relative to the descriptor.
+    let walk = WalkDir::new_at(fd, ".").max_open(1).contents_first(true);
+    let entries: Vec<_> = walk.into_iter().collect::<Result<_, _>>().unwrap();
+
+    let mut paths: Vec<_> = entries.iter().map(|entry| entry.path().to_owned()).collect();
+    paths.sort();
+    let expected: Vec<PathBuf> = [
+        ".",
+        "./foo",
+        "./foo/bar",
+        "./foo/bar/baz",
+        "./foo/bar/baz/x",
+        "./quux",
+        "./quux/a",
+        "./quux/a/b",
+        "./quux/a/b/y",
+        "./z",
+    ].iter().map(PathBuf::from).collect();
+    assert_eq!(expected, paths);
+
+    // Entries resolve themselves relative to the descriptor as well, after the walk is done.
+    for entry in &entries {
+        let meta = entry.metadata().unwrap();
+        assert_eq!(meta.is_dir(), entry.file_type().is_dir());
+    }
+}
+
+#[test]
+fn sandbox() {
+    let dir = Dir::tmp();
+    dir.mkdirp("root/a/b");
+    dir.mkdirp("outside");
+    dir.touch_all(&["outside/secret", "root/a/1", "root/a/b/c"]);
+    dir.symlink_dir("outside", "root/link");
+
+    // Replace a directory with a link after it was read from its parent but before it is opened.
+    let walk_swapped = |walk: WalkDir| {
+        let walk = walk.sort_by(|a, b| a.file_name().cmp(b.file_name()));
+        let mut found = vec![];
+        let mut errors = 0;
+        for entry in walk {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => {
+                    errors += 1;
+                    continue;
+                }
+            };
+            if entry.file_name() == "1" {
+                fs::rename(dir.join("root/a/b"), dir.join("moved")).unwrap();
+                dir.symlink_dir("outside", "root/a/b");
+            }
+            found.push(entry.file_name().to_owned());
+        }
+        fs::remove_file(dir.join("root/a/b")).unwrap();
+        fs::rename(dir.join("moved"), dir.join("root/a/b")).unwrap();
+        (found, errors)
+    };
+
+    let (found, errors) = walk_swapped(WalkDir::new(dir.join("root")));
+    assert!(found.iter().any(|name| name == "secret"));
+    assert_eq!(errors, 0);
+
+    // Both opened relative to the parent and, with a single descriptor, by the path.
+    for max_open in [1, 10] {
+        let walk = WalkDir::new(dir.join("root")).max_open(max_open).sandbox(true);
+        let (found, errors) = walk_swapped(walk);
+        assert!(!found.iter().any(|name| name == "secret"), "{:?}", found);
+        assert_eq!(errors, 1);
+    }
+
+    let walk = WalkDir::new(dir.join("root")).follow_links(true).sandbox(true);
+    let r = dir.run_recursive(walk);
+    assert!(!r.ents().iter().any(|entry| entry.file_name() == "secret"));
+    assert_eq!(r.errs().len(), 1);
+
+    let walk = WalkDir::new(dir.join("root")).sandbox(true);
+    let r = dir.run_recursive(walk);
+    r.assert_no_errors();
+    assert_eq!(r.ents().len(), 6);
+}
+
+#[test]
+fn relative_paths() {
+    let dir = Dir::tmp();
+    dir.mkdirp("foo/bar/baz");
+    dir.mkdirp("quux");
+    dir.touch_all(&["foo/bar/baz/x", "quux/y", "z"]);
+
+    let expected: Vec<PathBuf> = ["", "foo", "foo/bar", "foo/bar/baz", "foo/bar/baz/x", "quux"]
+        .iter()
+        .chain(&["quux/y", "z"])
+        .map(PathBuf::from)
+        .collect();
+
+    // A single open directory, so most of them are reopened by their full path.
+    for max_open in [1, 10] {
+        let walk = WalkDir::new(dir.path()).relative_paths(true).max_open(max_open);
+        let r = dir.run_recursive(walk);
+        r.assert_no_errors();
+        assert_eq!(expected, r.sorted_paths());
+
+        for entry in r.ents() {
+            let meta = entry.metadata().unwrap();
+            assert_eq!(meta.is_dir(), entry.file_type().is_dir());
+        }
+        assert_eq!(r.ents()[0].file_name(), dir.path().file_name().unwrap());
+    }
+
+    // The sandbox follows the root, whether it is configured before or after.
+    let walk = WalkDir::new(dir.path()).sandbox(true).relative_paths(true).max_open(1);
+    let r = dir.run_recursive(walk);
+    r.assert_no_errors();
+    assert_eq!(expected, r.sorted_paths());
+
+    let walk = WalkDir::new(dir.path()).relative_paths(true).relative_paths(false);
+    let r = dir.run_recursive(walk);
+    let absolute: Vec<_> = expected.iter().map(|path| dir.join(path)).collect();
+    assert_eq!(absolute, r.sorted_paths());
+
+    // A root that is not a directory.
+    let walk = WalkDir::new(dir.join("z")).relative_paths(true);
+    let r = dir.run_recursive(walk);
+    r.assert_no_errors();
+    assert_eq!(vec![PathBuf::new()], r.paths());
+    assert!(r.ents()[0].file_type().is_file());
+
+    let walk = WalkDir::new(dir.path()).relative_paths(true).into_parallel_iter();
+    let mut paths: Vec<_> = walk.map(|entry| entry.unwrap().path().to_owned()).collect();
+    paths.sort();
+    assert_eq!(expected, paths);
+}
+
+#[test]
+fn new_multi() {
+    let dir = Dir::tmp();
+    dir.mkdirp("foo/a/b");
+    dir.mkdirp("bar/c");
+    dir.touch_all(&["foo/a/b/x", "bar/c/y", "z"]);
+
+    let roots = [dir.join("foo"), dir.join("missing"), dir.join("bar"), dir.join("z")];
+    let walk = WalkDir::new_multi(roots.iter()).max_open(1);
+    let r = dir.run_recursive(walk);
+    assert_eq!(r.errs().len(), 1);
+    assert_eq!(r.errs()[0].depth(), 0);
+
+    // Each root is visited completely before the next one.
+    let paths = r.paths();
+    let start = |root: &PathBuf| paths.iter().position(|path| path == root).unwrap();
+    let end = |root: &PathBuf| paths.iter().rposition(|path| path.starts_with(root)).unwrap();
+    assert_eq!((start(&roots[0]), end(&roots[0])), (0, 3));
+    assert_eq!((start(&roots[2]), end(&roots[2])), (4, 6));
+    assert_eq!(start(&roots[3]), 7);
+    let roots = r.ents().iter().filter(|entry| entry.depth() == 0);
+    assert!(roots.clone().all(|entry| entry.parent().is_none()));
+
+    let walk = WalkDir::new_multi(vec![dir.join("foo"), dir.join("bar")]).relative_paths(true);
+    let r = dir.run_recursive(walk);
+    r.assert_no_errors();
+    let expected: Vec<PathBuf> = ["", "", "a", "a/b", "a/b/x", "c", "c/y"]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+    assert_eq!(expected, r.sorted_paths());
+
+    // All roots form the first level.
+    let walk = WalkDir::new_multi(vec![dir.join("foo"), dir.join("bar")]).breadth_first(true);
+    let r = dir.run_recursive(walk);
+    r.assert_no_errors();
+    let depths: Vec<_> = r.ents().iter().map(|entry| entry.depth()).collect();
+    assert_eq!(depths, [0, 0, 1, 1, 2, 2, 3]);
+
+    let walk = WalkDir::new_multi(vec![dir.join("foo"), dir.join("bar")]).into_parallel_iter();
+    let mut paths: Vec<_> = walk.map(|entry| entry.unwrap().path().to_owned()).collect();
+    paths.sort();
+    let r = dir.run_recursive(WalkDir::new_multi(vec![dir.join("foo"), dir.join("bar")]));
+    assert_eq!(r.sorted_paths(), paths);
+
+    let r = dir.run_recursive(WalkDir::new_multi(Vec::<PathBuf>::new()));
+    assert!(r.ents().is_empty() && r.errs().is_empty());
+}
+
+#[test]
+fn dedup_hardlinks() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.mkdirp("c");
+    dir.touch_all(&["a/x", "a/b/y", "z"]);
+    fs::hard_link(dir.join("a/x"), dir.join("a/b/x")).unwrap();
+    fs::hard_link(dir.join("a/x"), dir.join("c/x")).unwrap();
+    fs::hard_link(dir.join("z"), dir.join("c/z")).unwrap();
+
+    let files = |r: crate::tests::util::RecursiveResults| {
+        r.assert_no_errors();
+        let mut files: Vec<_> = r.ents()
+            .iter()
+            .filter(|entry| !entry.file_type().is_dir())
+            .map(|entry| entry.file_name().to_owned())
+            .collect();
+        files.sort();
+        files
+    };
+
+    let walk = WalkDir::new(dir.path());
+    assert_eq!(files(dir.run_recursive(walk)), ["x", "x", "x", "y", "z", "z"]);
+    let walk = WalkDir::new(dir.path()).dedup_hardlinks(true);
+    assert_eq!(files(dir.run_recursive(walk)), ["x", "y", "z"]);
+    let walk = WalkDir::new(dir.path()).dedup_hardlinks(true).max_open(1).breadth_first(true);
+    assert_eq!(files(dir.run_recursive(walk)), ["x", "y", "z"]);
+
+    // The threads of a parallel walk share the files, across directories.
+    let walk = WalkDir::new(dir.path()).dedup_hardlinks(true).threads(3);
+    assert_eq!(files(dir.run_recursive(walk.into_parallel_iter())), ["x", "y", "z"]);
+
+    // Roots are files without a directory.
+    let walk = WalkDir::new_multi(vec![dir.join("z"), dir.join("c")]).dedup_hardlinks(true);
+    assert_eq!(files(dir.run_recursive(walk)), ["x", "z"]);
+}
+
+#[test]
+fn vanished() {
+    use crate::Vanished;
+
+    let dir = Dir::tmp();
+    dir.mkdirp("a");
+    dir.mkdirp("c");
+    dir.touch_all(&["a/1", "c/3"]);
+
+    // Remove a directory after it was read from its parent but before it is opened.
+    let walk_removed = |walk: WalkDir| {
+        dir.mkdirp("b");
+        dir.touch_all(&["b/2"]);
+        let walk = walk.sort_by(|a, b| a.file_name().cmp(b.file_name()));
+        let mut found = vec![];
+        let mut errors = vec![];
+        for entry in walk {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    errors.push(err.path().unwrap().to_owned());
+                    continue;
+                }
+            };
+            if entry.path() == dir.join("a/1") {
+                fs::remove_dir_all(dir.join("b")).unwrap();
+            }
+            found.push(entry.file_name().to_str().unwrap().to_owned());
+        }
+        (found, errors)
+    };
+
+    for max_open in [1, 10] {
+        for breadth_first in [false, true] {
+            let walk = || {
+                WalkDir::new(dir.path()).max_open(max_open).breadth_first(breadth_first)
+            };
+
+            let (found, errors) = walk_removed(walk());
+            assert_eq!(errors, vec![dir.join("b")]);
+            // Breadth first, the directory was yielded before it was opened.
+            assert_eq!(found.iter().any(|name| name == "b"), breadth_first);
+            assert!(found.iter().any(|name| name == "3"));
+
+            let (found, errors) = walk_removed(walk().vanished(Vanished::Ignore));
+            assert!(errors.is_empty(), "{:?}", errors);
+            assert_eq!(found.iter().any(|name| name == "b"), breadth_first);
+            assert!(found.iter().any(|name| name == "3"));
+
+            let (found, errors) = walk_removed(walk().vanished(Vanished::Abort));
+            assert_eq!(errors, vec![dir.join("b")]);
+            assert!(!found.iter().any(|name| name == "3"), "{:?}", found);
+        }
+    }
+}
+
+#[test]
+fn out_of_descriptors() {
+    const CHILD: &str = "SPRINT_DIR_TEST_OUT_OF_DESCRIPTORS";
+
+    // The limit applies to the whole process, run without the other tests.
+    if std::env::var_os(CHILD).is_none() {
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "tests::recursive::out_of_descriptors", "--test-threads=1"])
+            .env(CHILD, "1")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        return;
+    }
+
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b/c/d/e/f/g");
+    dir.touch_all(&["a/b/c/d/e/f/g/1"]);
+
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) }, 0);
+    // Leave only a few descriptors for the walk, counting the one for reading the list.
+    let in_use = fs::read_dir("/proc/self/fd").unwrap().count();
+    let low = libc::rlimit { rlim_cur: in_use as libc::rlim_t + 2, ..limit };
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &low) }, 0);
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()).max_open(10));
+    let contents_first = WalkDir::new(dir.path()).max_open(10).contents_first(true);
+    let r_contents_first = dir.run_recursive(contents_first);
+    let r_default = dir.run_recursive(WalkDir::new(dir.path()));
+
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+    for r in [r, r_contents_first, r_default] {
+        r.assert_no_errors();
+        assert_eq!(r.ents().len(), 9);
+    }
+}
+
+#[test]
+fn size_hint() {
+    let dir = Dir::tmp();
+    dir.mkdirp("sub");
+    let files: Vec<_> = (0..100).map(|i| format!("sub/{}", i)).collect();
+    dir.touch_all(&files);
+
+    let mut it = WalkDir::new(dir.path()).into_iter();
+    assert_eq!(it.size_hint(), (1, None));
+    let root = it.next().unwrap().unwrap();
+    assert_eq!(root.depth(), 0);
+    let sub = it.next().unwrap().unwrap();
+    assert_eq!(sub.file_name(), "sub");
+    // The first entry of the subdirectory reads all of them.
+    it.next().unwrap().unwrap();
+    assert_eq!(it.size_hint(), (99, None));
+
+    let count = it.by_ref().count();
+    assert_eq!(count, 99);
+    assert_eq!(it.size_hint(), (0, Some(0)));
+    assert!(it.next().is_none());
+    assert!(it.next().is_none());
+
+    // Directories yielded after their contents are certain, as are sorted entries.
+    let walk = WalkDir::new(dir.path())
+        .contents_first(true)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    let mut it = walk.into_iter();
+    it.next().unwrap().unwrap();
+    assert_eq!(it.size_hint(), (101, None));
+
+    // Only entries at the minimum depth are yielded.
+    let mut it = WalkDir::new(dir.path()).min_depth(2).into_iter();
+    it.next().unwrap().unwrap();
+    assert_eq!(it.size_hint(), (99, None));
+}
+
+#[test]
+fn types() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.touch_all(&["a/1", "a/b/2"]);
+    dir.symlink_dir("a", "link");
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()).files_only());
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), vec![dir.join("a/1"), dir.join("a/b/2")]);
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()).dirs_only());
+    r.assert_no_errors();
+    let dirs = vec![dir.path().to_path_buf(), dir.join("a"), dir.join("a/b")];
+    assert_eq!(r.sorted_paths(), dirs);
+
+    // Followed links are filtered by their target, and descended into.
+    let r = dir.run_recursive(WalkDir::new(dir.path()).files_only().follow_links(true));
+    r.assert_no_errors();
+    let files = vec![dir.join("a/1"), dir.join("a/b/2"), dir.join("link/1"), dir.join("link/b/2")];
+    assert_eq!(r.sorted_paths(), files);
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()));
+    let link = r.ents().iter().find(|entry| entry.file_name() == "link").unwrap().file_type();
+    for verify in [0, 1] {
+        let walk = WalkDir::new(dir.path()).types(&[link]).verify_file_types(verify);
+        let r = dir.run_recursive(walk);
+        r.assert_no_errors();
+        assert_eq!(r.sorted_paths(), vec![dir.join("link")]);
+    }
+
+    // The predicate still decides about descending into directories that are not yielded.
+    let walk = WalkDir::new(dir.path()).files_only().into_iter();
+    let r = dir.run_recursive(walk.filter_entry(|entry| entry.file_name() != "b"));
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), vec![dir.join("a/1")]);
+
+    let walk = WalkDir::new(dir.path()).files_only().min_depth(2).contents_first(true);
+    let r = dir.run_recursive(walk);
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), vec![dir.join("a/1"), dir.join("a/b/2")]);
+
+    let walk = WalkDir::new(dir.path()).files_only().threads(2);
+    let r = dir.run_recursive(walk.into_parallel_iter());
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), vec![dir.join("a/1"), dir.join("a/b/2")]);
+
+    let walk = WalkDir::new(dir.path()).dirs_only().threads(2);
+    let r = dir.run_recursive(walk.into_parallel_iter());
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), dirs);
+}
+
+#[test]
+fn special_file_types() {
+    use crate::UnixFileType;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixListener;
+
+    let dir = Dir::tmp();
+    dir.touch_all(&["file"]);
+    let fifo = CString::new(dir.join("fifo").as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+    let _socket = UnixListener::bind(dir.join("socket")).unwrap();
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()).min_depth(1));
+    r.assert_no_errors();
+    for entry in r.ents() {
+        let ty = entry.file_type();
+        assert!(!ty.is_unknown());
+        assert_eq!(ty, entry.stat().unwrap().file_type());
+        assert_eq!(ty.is_file(), entry.file_name() == "file");
+        assert_eq!(ty.is_fifo(), entry.file_name() == "fifo");
+        assert_eq!(ty.is_socket(), entry.file_name() == "socket");
+        assert!(!ty.is_block_device() && !ty.is_char_device());
+
+        let std_type = fs::symlink_metadata(entry.path()).unwrap().file_type();
+        assert_eq!(ty, std_type.into());
+        assert_eq!(ty.kind(), UnixFileType::from_std(std_type));
+        assert_eq!(entry.clone().file_type_resolved().unwrap(), ty);
+    }
+
+    let walk = WalkDir::new(dir.path()).types(&[UnixFileType::NamedPipe.into()]);
+    let r = dir.run_recursive(walk);
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), vec![dir.join("fifo")]);
+    assert_eq!(r.ents()[0].file_type().kind(), Some(UnixFileType::NamedPipe));
+
+    let r = dir.run_recursive(WalkDir::new("/dev/null"));
+    r.assert_no_errors();
+    assert!(r.ents()[0].file_type().is_char_device());
+    let std_type = fs::metadata("/dev/null").unwrap().file_type();
+    assert_eq!(r.ents()[0].file_type(), crate::FileType::from(std_type));
+}
+
+#[test]
+fn metadata_cached() {
+    let dir = Dir::tmp();
+    dir.touch_all(&["file"]);
+    dir.symlink_file("file", "link");
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()).follow_links(true).min_depth(1));
+    r.assert_no_errors();
+    let entry = |name: &str| r.ents().iter().find(|entry| entry.file_name() == name).unwrap();
+    let (file, link) = (entry("file"), entry("link"));
+
+    fs::write(dir.join("file"), b"12345").unwrap();
+    // The walk inspected the followed link before the file was changed.
+    assert_eq!(link.metadata_cached().unwrap().len(), 0);
+    assert_eq!(link.metadata().unwrap().len(), 5);
+    assert_eq!(file.metadata_cached().unwrap().len(), 5);
+
+    fs::write(dir.join("file"), b"1234567").unwrap();
+    assert_eq!(file.metadata_cached().unwrap().len(), 5);
+    assert_eq!(file.clone().metadata_cached().unwrap().len(), 5);
+    assert_eq!(file.metadata().unwrap().len(), 7);
+}
+
+#[test]
+fn with_metadata() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.touch_all(&["a/small", "a/b/large"]);
+    fs::write(dir.join("a/b/large"), b"12345").unwrap();
+
+    let count_stats = |walk: WalkDir| {
+        let mut it = walk.into_iter();
+        let entries = it.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+        (entries, it.stats().nr_stat())
+    };
+
+    let (plain, plain_stats) = count_stats(WalkDir::new(dir.path()));
+    let (entries, stats) = count_stats(WalkDir::new(dir.path()).with_metadata(true));
+    assert_eq!(entries.len(), plain.len());
+    assert_eq!(stats, plain_stats + entries.len());
+
+    // The meta data is that of the walk, even after the file changed.
+    fs::write(dir.join("a/small"), b"123").unwrap();
+    let small = entries.iter().find(|entry| entry.file_name() == "small").unwrap();
+    assert_eq!(small.metadata_cached().unwrap().len(), 0);
+    assert_eq!(small.metadata().unwrap().len(), 3);
+
+    // Available to the predicate.
+    let walk = WalkDir::new(dir.path()).with_metadata(true).files_only().into_iter();
+    let large = walk.filter_entry(|entry| {
+        let metadata = entry.metadata_cached().unwrap();
+        metadata.is_dir() || metadata.len() > 4
+    });
+    let r = dir.run_recursive(large);
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), vec![dir.join("a/b/large")]);
+}
+
+#[test]
+fn len_and_modified() {
+    let dir = Dir::tmp();
+    dir.touch_all(&["file"]);
+    fs::write(dir.join("file"), b"12345").unwrap();
+
+    for with_metadata in [false, true] {
+        let walk = WalkDir::new(dir.path()).with_metadata(with_metadata).min_depth(1);
+        let r = dir.run_recursive(walk);
+        r.assert_no_errors();
+        let entry = &r.ents()[0];
+        assert_eq!(entry.len().unwrap(), 5);
+        let modified = fs::metadata(dir.join("file")).unwrap().modified().unwrap();
+        assert_eq!(entry.modified().unwrap(), modified);
+    }
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()).min_depth(1));
+    fs::remove_file(dir.join("file")).unwrap();
+    assert!(r.ents()[0].len().is_err());
+}
+
+#[test]
+fn sort_by_key_meta() {
+    use std::time::{Duration, SystemTime};
+
+    let dir = Dir::tmp();
+    dir.mkdirp("a");
+    dir.touch_all(&["a/old", "a/new", "a/middle"]);
+    dir.symlink_file("a/old", "a/link");
+    let now = SystemTime::now();
+    for (name, age) in [("old", 30), ("middle", 20), ("new", 10)] {
+        let file = fs::File::options().write(true).open(dir.join("a").join(name)).unwrap();
+        file.set_modified(now - Duration::from_secs(age)).unwrap();
+    }
+
+    let newest_first = |walk: WalkDir| {
+        let walk = walk.min_depth(2).sort_by_key_meta(|entry, meta| {
+            (std::cmp::Reverse(meta.modified().unwrap()), entry.file_name().to_owned())
+        });
+        let r = dir.run_recursive(walk);
+        r.assert_no_errors();
+        r.ents().iter().map(|entry| entry.file_name().to_owned()).collect::<Vec<_>>()
+    };
+
+    // The link itself is the newest.
+    let expected = ["link", "new", "middle", "old"];
+    assert_eq!(newest_first(WalkDir::new(dir.path())), expected);
+    assert_eq!(newest_first(WalkDir::new(dir.path()).max_open(1)), expected);
+
+    // A followed link is sorted by its own meta data, but caches that of its target.
+    let walk = WalkDir::new(dir.path()).follow_links(true);
+    let r = dir.run_recursive(walk.sort_by_key_meta(|_, meta| meta.len()));
+    let link = r.ents().iter().find(|entry| entry.file_name() == "link").unwrap();
+    assert!(link.metadata_cached().unwrap().is_file());
+}
+
+#[test]
+#[cfg(feature = "globset")]
+fn globs() {
+    let dir = Dir::tmp();
+    dir.mkdirp("src/gen");
+    dir.mkdirp("target");
+    dir.touch_all(&["src/a.rs", "src/b.txt", "src/gen/c.rs", "target/d.rs"]);
+
+    let rust = vec![
+        dir.path().to_path_buf(),
+        dir.join("src/a.rs"),
+        dir.join("src/gen/c.rs"),
+        dir.join("target/d.rs"),
+    ];
+    let r = dir.run_recursive(WalkDir::new(dir.path()).include_glob("*.rs").unwrap());
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), rust);
+
+    let walk = WalkDir::new(dir.path()).exclude_glob("target").unwrap();
+    let walk = walk.exclude_glob("src/gen").unwrap().include_glob("*.rs").unwrap();
+    let expected = vec![dir.path().to_path_buf(), dir.join("src/a.rs")];
+    let r = dir.run_recursive(walk.clone());
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), expected);
+
+    let r = dir.run_recursive(walk.clone().breadth_first(true));
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), expected);
+
+    let r = dir.run_recursive(walk.threads(2).into_parallel_iter());
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), expected);
+
+    // Relative paths are matched below the root, whatever its path.
+    let walk = WalkDir::new(dir.join("src")).include_glob("gen/*").unwrap();
+    let r = dir.run_recursive(walk.min_depth(1));
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), vec![dir.join("src/gen/c.rs")]);
+
+    assert!(WalkDir::new(dir.path()).exclude_glob("a[").is_err());
+}
+
+#[test]
+#[cfg(feature = "ignore")]
+fn ignore_files() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/target");
+    dir.mkdirp("a/b");
+    dir.touch_all(&["a/1.log", "a/2.txt", "a/target/3", "a/b/4.log", "a/b/keep.log"]);
+    fs::write(dir.join(".gitignore"), "*.log\ntarget/\n").unwrap();
+    fs::write(dir.join("a/b/.gitignore"), "!keep.log\n").unwrap();
+    fs::write(dir.join("a/.ignore"), "2.txt\n").unwrap();
+
+    let expected = vec![
+        dir.path().to_path_buf(),
+        dir.join(".gitignore"),
+        dir.join("a"),
+        dir.join("a/.ignore"),
+        dir.join("a/b"),
+        dir.join("a/b/.gitignore"),
+        dir.join("a/b/keep.log"),
+    ];
+    let walk = WalkDir::new(dir.path()).ignore_files(true);
+    let r = dir.run_recursive(walk.clone());
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), expected);
+
+    // Ignore files are inherited by directories that had to be reopened.
+    let r = dir.run_recursive(walk.clone().max_open(1).breadth_first(true));
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), expected);
+
+    let r = dir.run_recursive(walk.threads(2).into_parallel_iter());
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), expected);
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()).ignore_files(false));
+    assert_eq!(r.sorted_paths().len(), 12);
+}
+
+#[test]
+fn skip_hidden() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/.git/objects");
+    dir.mkdirp(".hidden");
+    dir.touch_all(&["a/.env", "a/1", ".hidden/2", "a/.git/objects/3"]);
+
+    let expected = vec![dir.path().to_path_buf(), dir.join("a"), dir.join("a/1")];
+    let r = dir.run_recursive(WalkDir::new(dir.path()).skip_hidden(true));
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), expected);
+
+    // A hidden root is still walked.
+    let r = dir.run_recursive(WalkDir::new(dir.join(".hidden")).skip_hidden(true));
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), vec![dir.join(".hidden"), dir.join(".hidden/2")]);
+
+    let r = dir.run_recursive(WalkDir::new(dir.path()).skip_hidden(false));
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths().len(), 9);
+}
+
+#[test]
+fn with_extensions() {
+    let dir = Dir::tmp();
+    dir.mkdirp("src/bin");
+    dir.mkdirp("data.toml");
+    dir.touch_all(&["Cargo.toml", "src/lib.rs", "src/bin/main.rs", "src/notes.txt", "src/.rs"]);
+    dir.touch_all(&["data.toml/inner"]);
+
+    let expected = vec![
+        dir.path().to_path_buf(),
+        dir.join("Cargo.toml"),
+        dir.join("data.toml"),
+        dir.join("src/bin/main.rs"),
+        dir.join("src/lib.rs"),
+    ];
+    let walk = WalkDir::new(dir.path()).with_extensions(&["rs", "toml"]);
+    let r = dir.run_recursive(walk.clone());
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), expected);
+
+    let r = dir.run_recursive(walk.clone().verify_file_types(1));
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), expected);
+
+    let r = dir.run_recursive(walk.threads(2).into_parallel_iter());
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), expected);
+}
+
+#[test]
+#[cfg(feature = "regex")]
+fn name_regex() {
+    use regex::bytes::Regex;
+
+    let dir = Dir::tmp();
+    dir.mkdirp("test_a/b");
+    dir.touch_all(&["test_1", "other", "test_a/test_2", "test_a/b/test_3"]);
+
+    let tests = Regex::new("^test_").unwrap();
+    let walk = WalkDir::new(dir.path()).min_depth(1).name_regex(tests.clone(), false);
+    let r = dir.run_recursive(walk);
+    r.assert_no_errors();
+    let expected = vec![
+        dir.join("test_1"),
+        dir.join("test_a"),
+        dir.join("test_a/b/test_3"),
+        dir.join("test_a/test_2"),
+    ];
+    assert_eq!(r.sorted_paths(), expected);
+
+    // Pruning skips the contents of `b`.
+    let walk = WalkDir::new(dir.path()).min_depth(1).name_regex(tests, true);
+    let r = dir.run_recursive(walk);
+    r.assert_no_errors();
+    let expected = vec![dir.join("test_1"), dir.join("test_a"), dir.join("test_a/test_2")];
+    assert_eq!(r.sorted_paths(), expected);
+}
+
+#[test]
+fn on_error() {
+    use crate::{Decision, ErrorPolicy};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.mkdirp("a/c");
+    dir.symlink_dir("a", "a/b/up");
+    dir.symlink_dir("a", "a/c/up");
+    let walk = || {
+        let walk = WalkDir::new(dir.path()).follow_links(true);
+        walk.sort_by(|a, b| a.file_name().cmp(b.file_name()))
+    };
+
+    let r = dir.run_recursive(walk().on_error(ErrorPolicy::Yield));
+    assert_eq!(r.errs().len(), 2);
+    assert_eq!(r.ents().len(), 4);
+
+    let r = dir.run_recursive(walk().on_error(ErrorPolicy::Ignore));
+    r.assert_no_errors();
+    assert_eq!(r.ents().len(), 4);
+
+    // The walk ends before `a/c` is visited.
+    let r = dir.run_recursive(walk().on_error(ErrorPolicy::Abort));
+    assert_eq!(r.errs().len(), 1);
+    assert_eq!(r.sorted_paths(), vec![dir.path().to_path_buf(), dir.join("a"), dir.join("a/b")]);
+
+    let seen = Arc::new(AtomicUsize::new(0));
+    let counter = seen.clone();
+    let decide = move |err: &crate::Error| {
+        assert!(err.loop_ancestor().is_some());
+        match counter.fetch_add(1, Ordering::SeqCst) {
+            0 => Decision::Ignore,
+            _ => Decision::Yield,
+        }
+    };
+    let r = dir.run_recursive(walk().on_error(ErrorPolicy::Decide(Box::new(decide))));
+    assert_eq!(r.errs().len(), 1);
+    assert_eq!(r.errs()[0].path(), Some(&*dir.join("a/c/up")));
+    assert_eq!(seen.load(Ordering::SeqCst), 2);
+
+    let walk = walk().on_error(ErrorPolicy::Ignore).threads(2);
+    let r = dir.run_recursive(walk.into_parallel_iter());
+    r.assert_no_errors();
+    assert_eq!(r.ents().len(), 4);
+}
+
+#[test]
+fn collect_denied() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = Dir::tmp();
+    dir.mkdirp("a/locked/inner");
+    dir.mkdirp("b");
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+    fs::set_permissions(dir.join("a/locked"), fs::Permissions::from_mode(0o000)).unwrap();
+
+    // Permissions do not apply to root, check them as someone else on this thread. Threads of a
+    // parallel walk that are spawned from it inherit this.
+    let root = unsafe { libc::geteuid() } == 0;
+    if root {
+        unsafe { libc::setfsuid(65534) };
+    }
+
+    let walk = WalkDir::new(dir.path()).collect_denied(true);
+    let mut it = walk.clone().into_iter();
+    let r = dir.run_recursive(&mut it);
+    let denied = it.denied().to_vec();
+    let mut par = walk.threads(2).into_parallel_iter();
+    let r_par = dir.run_recursive(&mut par);
+    let denied_par = par.denied();
+    let r_errors = dir.run_recursive(WalkDir::new(dir.path()));
+
+    if root {
+        unsafe { libc::setfsuid(0) };
+    }
+    fs::set_permissions(dir.join("a/locked"), fs::Permissions::from_mode(0o755)).unwrap();
+
+    let expected = vec![
+        dir.path().to_path_buf(),
+        dir.join("a"),
+        dir.join("a/locked"),
+        dir.join("b"),
+    ];
+    for r in [r, r_par] {
+        r.assert_no_errors();
+        assert_eq!(r.sorted_paths(), expected);
+    }
+    assert_eq!(denied, vec![dir.join("a/locked")]);
+    assert_eq!(denied_par, vec![dir.join("a/locked")]);
+
+    let errs = r_errors.errs();
+    assert_eq!(errs.len(), 1);
+    assert_eq!(errs[0].io_error().unwrap().kind(), std::io::ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn cancel_token() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b/c/d");
+    dir.touch_all(&["a/1", "a/b/2", "a/b/c/3", "a/b/c/d/4"]);
+
+    let mut it = WalkDir::new(dir.path()).into_iter();
+    let token = it.cancel_token();
+    for _ in 0..4 {
+        it.next().unwrap().unwrap();
+    }
+    assert!(!token.is_cancelled());
+
+    std::thread::spawn(move || token.cancel()).join().unwrap();
+    assert!(it.next().is_none());
+    assert!(it.next().is_none());
+    let stats = it.stats();
+    assert_eq!(stats.nr_close(), stats.nr_open() + stats.nr_openat());
+
+    let mut it = WalkDir::new(dir.path()).contents_first(true).into_iter();
+    it.next().unwrap().unwrap();
+    it.cancel_token().cancel();
+    assert!(it.next_borrowed().is_none());
+}
+
+#[test]
+fn max_entries_and_deadline() {
+    use std::time::{Duration, Instant};
+
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b/c");
+    dir.touch_all(&["a/1", "a/b/2", "a/b/c/3"]);
+
+    let mut it = WalkDir::new(dir.path()).max_entries(3).into_iter();
+    for _ in 0..3 {
+        it.next().unwrap().unwrap();
+    }
+    let err = it.next().unwrap().unwrap_err();
+    assert!(err.is_truncated());
+    assert!(err.path().is_none() && err.io_error().is_none());
+    assert!(it.next().is_none());
+    let stats = it.stats();
+    assert_eq!(stats.nr_close(), stats.nr_open() + stats.nr_openat());
+
+    // Without more entries the walk is complete.
+    let r = dir.run_recursive(WalkDir::new(dir.path()).max_entries(7));
+    r.assert_no_errors();
+    assert_eq!(r.ents().len(), 7);
+
+    let passed = Instant::now() - Duration::from_millis(1);
+    let r = dir.run_recursive(WalkDir::new(dir.path()).deadline(passed));
+    assert!(r.ents().is_empty());
+    assert!(r.errs()[0].is_truncated());
+
+    let later = Instant::now() + Duration::from_secs(3600);
+    let r = dir.run_recursive(WalkDir::new(dir.path()).deadline(later));
+    r.assert_no_errors();
+    assert_eq!(r.ents().len(), 7);
+
+    let walk = WalkDir::new(dir.path()).max_entries(3).threads(2);
+    let r = dir.run_recursive(walk.into_parallel_iter());
+    assert_eq!(r.ents().len(), 3);
+    assert_eq!(r.errs().len(), 1);
+    assert!(r.errs()[0].is_truncated());
+}
+
+#[test]
+fn on_progress() {
+    use std::sync::{Arc, Mutex};
+
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.touch_all(&["a/1", "a/b/2", "a/b/3"]);
+
+    let reports = Arc::new(Mutex::new(vec![]));
+    let seen = Arc::clone(&reports);
+    let walk = WalkDir::new(dir.path()).on_progress(move |progress| {
+        let path = progress.path().map(|path| path.to_path_buf());
+        seen.lock().unwrap().push((progress.entries(), progress.dirs(), progress.open(), path));
+    });
+    let r = dir.run_recursive(walk);
+    r.assert_no_errors();
+
+    // Each directory is reported once, the deepest one first.
+    let reports = reports.lock().unwrap();
+    assert_eq!(reports.len(), 3);
+    assert_eq!(reports[0].1, 1);
+    assert_eq!(reports[0].3.as_deref(), Some(dir.join("a/b").as_path()));
+    assert_eq!(reports[2].0, 6);
+    assert_eq!(reports[2].1, 3);
+    assert_eq!(reports[2].3.as_deref(), Some(dir.path()));
+    assert!(reports.iter().all(|report| report.2 <= 3));
+
+    let count = Arc::new(Mutex::new(0));
+    let seen = Arc::clone(&count);
+    let walk = WalkDir::new(dir.path())
+        .on_progress(move |_| *seen.lock().unwrap() += 1)
+        .progress_every(2);
+    let r = dir.run_recursive(walk);
+    r.assert_no_errors();
+    assert_eq!(*count.lock().unwrap(), 3 + 3);
+
+    // Entries taken directly from the buffers are counted as well.
+    let last = Arc::new(Mutex::new(0));
+    let seen = Arc::clone(&last);
+    let walk = WalkDir::new(dir.path())
+        .on_progress(move |progress| *seen.lock().unwrap() = progress.entries());
+    assert_eq!(walk.into_iter().count(), 6);
+    assert_eq!(*last.lock().unwrap(), 6);
+
+    let seen = Arc::clone(&last);
+    let walk = WalkDir::new(dir.path())
+        .on_progress(move |progress| *seen.lock().unwrap() = progress.entries());
+    let mut it = walk.into_iter();
+    *last.lock().unwrap() = 0;
+    while let Some(entry) = it.next_borrowed() {
+        entry.unwrap();
+    }
+    assert_eq!(*last.lock().unwrap(), 6);
+}
+
+#[test]
+fn into_events() {
+    use crate::Event;
+
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.touch_all(&["a/1", "a/b/2", "3"]);
+
+    let walk = WalkDir::new(dir.path()).sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    let describe = |event: Event| {
+        let kind = match &event {
+            Event::Enter(_) => "enter",
+            Event::Leave(_) => "leave",
+            Event::File(_) => "file",
+        };
+        (kind, event.into_entry().into_path())
+    };
+
+    let events: Vec<_> = walk.clone().contents_first(true).into_events()
+        .map(|event| describe(event.unwrap()))
+        .collect();
+    assert_eq!(events, vec![
+        ("enter", dir.path().to_path_buf()),
+        ("file", dir.join("3")),
+        ("enter", dir.join("a")),
+        ("file", dir.join("a/1")),
+        ("enter", dir.join("a/b")),
+        ("file", dir.join("a/b/2")),
+        ("leave", dir.join("a/b")),
+        ("leave", dir.join("a")),
+        ("leave", dir.path().to_path_buf()),
+    ]);
+
+    let mut it = walk.into_events();
+    let mut events = vec![];
+    while let Some(event) = it.next() {
+        let event = describe(event.unwrap());
+        if event == ("enter", dir.join("a")) {
+            it.skip_current_dir();
+        }
+        events.push(event);
+    }
+    assert_eq!(events, vec![
+        ("enter", dir.path().to_path_buf()),
+        ("file", dir.join("3")),
+        ("enter", dir.join("a")),
+        ("leave", dir.join("a")),
+        ("leave", dir.path().to_path_buf()),
+    ]);
+}
+
+#[test]
+fn aggregate_sizes() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    fs::write(dir.join("a/1"), [0; 10]).unwrap();
+    fs::write(dir.join("a/b/2"), [0; 5]).unwrap();
+    fs::hard_link(dir.join("a/1"), dir.join("a/b/link")).unwrap();
+
+    let sizes = |count_links_once| -> Vec<_> {
+        WalkDir::new(dir.path())
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .aggregate_sizes(count_links_once)
+            .map(|size| size.unwrap())
+            .collect()
+    };
+
+    let all = sizes(false);
+    let paths: Vec<_> = all.iter().map(|size| size.entry().path().to_path_buf()).collect();
+    assert_eq!(paths, vec![dir.join("a/b"), dir.join("a"), dir.path().to_path_buf()]);
+    assert_eq!(all.iter().map(|size| size.entries()).collect::<Vec<_>>(), vec![2, 4, 5]);
+
+    let own = |path: &str| fs::metadata(dir.join(path)).unwrap().len();
+    assert_eq!(all[0].size(), own("a/b") + 15);
+    assert_eq!(all[1].size(), own("a") + own("a/b") + 25);
+    assert!(all[2].disk_usage() >= all[1].disk_usage());
+
+    // The file is found first, its other link is not counted again.
+    let once = sizes(true);
+    assert_eq!(once[0].size(), all[0].size() - 10);
+    assert_eq!(once[1].size(), all[1].size() - 10);
+    assert_eq!(once[2].entries(), 5);
+}
+
+#[test]
+fn visit() {
+    use crate::{DirEntry, Visitor};
+
+    /// Counts the files below each directory.
+    struct Count(Vec<(PathBuf, usize)>);
+
+    impl Visitor for Count {
+        type State = usize;
+
+        fn pre_dir(&mut self, _: &DirEntry, _: Option<&mut usize>) -> usize {
+            0
+        }
+
+        fn file(&mut self, _: &DirEntry, parent: Option<&mut usize>) {
+            *parent.unwrap() += 1;
+        }
+
+        fn post_dir(&mut self, dir: DirEntry, files: usize, parent: Option<&mut usize>) {
+            if let Some(parent) = parent {
+                *parent += files;
+            }
+            self.0.push((dir.into_path(), files));
+        }
+    }
+
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.mkdirp("c");
+    dir.touch_all(&["a/1", "a/b/2", "a/b/3", "4"]);
+
+    let mut count = Count(vec![]);
+    WalkDir::new(dir.path()).visit(&mut count).unwrap();
+    count.0.sort();
+    assert_eq!(count.0, vec![
+        (dir.path().to_path_buf(), 4),
+        (dir.join("a"), 3),
+        (dir.join("a/b"), 2),
+        (dir.join("c"), 0),
+    ]);
+
+    let err = WalkDir::new(dir.join("missing")).visit(&mut Count(vec![])).unwrap_err();
+    assert_eq!(err.path(), Some(dir.join("missing").as_path()));
+}
+
+#[test]
+fn spawn_into() {
+    use std::sync::mpsc;
+
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.touch_all(&["a/1", "a/b/2", "3"]);
+
+    let (sender, receiver) = mpsc::sync_channel(1);
+    let walk = WalkDir::new(dir.path()).spawn_into(sender);
+    let mut paths: Vec<_> = receiver.iter().map(|entry| entry.unwrap().into_path()).collect();
+    let stats = walk.join().unwrap();
+    paths.sort();
+    assert_eq!(paths, dir.run_recursive(WalkDir::new(dir.path())).sorted_paths());
+    assert_eq!(stats.nr_close(), stats.nr_open() + stats.nr_openat());
+
+    // Dropping the receiver ends the walk.
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let walk = WalkDir::new(dir.path()).spawn_into(sender);
+    receiver.recv().unwrap().unwrap();
+    drop(receiver);
+    walk.join().unwrap();
+}
+
+#[test]
+fn read_ahead() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.mkdirp("c");
+    let names: Vec<_> = (0..200).map(|i| format!("a/file-with-a-longer-name-{}", i)).collect();
+    dir.touch_all(&names);
+    dir.touch_all(&["a/b/1", "c/2", "3"]);
+
+    let mut it = WalkDir::new(dir.path()).buffer_size(0).into_iter();
+    let r = dir.run_recursive(it.by_ref());
+    r.assert_no_errors();
+    let expected = r.sorted_paths();
+    let getdents = it.stats().nr_getdent();
+
+    let walks = vec![
+        WalkDir::new(dir.path()).buffer_size(0),
+        WalkDir::new(dir.path()).buffer_size(0).max_open(1),
+        WalkDir::new(dir.path()).buffer_size(0).contents_first(true),
+    ];
+
+    for walk in walks {
+        let mut it = walk.read_ahead(true).into_iter();
+        let r = dir.run_recursive(it.by_ref());
+        r.assert_no_errors();
+        assert_eq!(expected, r.sorted_paths());
+        let stats = it.stats();
+        assert_eq!(stats.nr_close(), stats.nr_open() + stats.nr_openat());
+    }
+
+    let mut it = WalkDir::new(dir.path()).buffer_size(0).read_ahead(true).into_iter();
+    it.by_ref().for_each(drop);
+    assert_eq!(it.stats().nr_getdent(), getdents);
+
+    // Leaving a directory waits for the buffer that is read ahead.
+    let mut it = WalkDir::new(dir.path()).buffer_size(0).read_ahead(true).into_iter();
+    let mut paths = vec![];
+    while let Some(entry) = it.next() {
+        let entry = entry.unwrap();
+        if entry.file_type().is_file() && entry.path().starts_with(dir.join("a")) {
+            it.skip_current_dir();
+        }
+        paths.push(entry.into_path());
+    }
+    let files = paths.iter().filter(|path| path.parent() == Some(&dir.join("a")));
+    assert_eq!(files.filter(|path| !path.ends_with("b")).count(), 1);
+    assert!(paths.contains(&dir.join("c/2")));
+    let stats = it.stats();
+    assert_eq!(stats.nr_close(), stats.nr_open() + stats.nr_openat());
+}
+
+#[test]
+#[cfg(feature = "tokio")]
+fn into_stream() {
+    use std::pin::Pin;
+    use futures_core::Stream;
+
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.touch_all(&["a/1", "a/b/2", "3"]);
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let mut paths = runtime.block_on(async {
+        let mut stream = WalkDir::new(dir.path()).into_stream();
+        let mut paths = vec![];
+        loop {
+            let next = std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx));
+            let entry = match next.await {
+                Some(entry) => entry,
+                None => break,
+            };
+            paths.push(entry.unwrap().into_path());
+        }
+        paths
+    });
+
+    paths.sort();
+    assert_eq!(paths, dir.run_recursive(WalkDir::new(dir.path())).sorted_paths());
+}
+
+#[test]
+fn sort_by_file_name_and_key() {
+    let dir = Dir::tmp();
+    dir.mkdirp("b");
+    dir.touch_all(&["b/y", "b/x", "a", "c-long"]);
+
+    let walk = WalkDir::new(dir.path()).sort_by_file_name();
+    let r = dir.run_recursive(walk);
+    r.assert_no_errors();
+    let names = ["", "a", "b", "b/x", "b/y", "c-long"];
+    assert_eq!(r.paths(), names.iter().map(|name| dir.join(name)).collect::<Vec<_>>());
+
+    let walk = WalkDir::new(dir.path()).sort_by_key(|entry| entry.file_name().len());
+    let r = dir.run_recursive(walk.min_depth(1).max_depth(1));
+    r.assert_no_errors();
+    assert_eq!(r.paths().last(), Some(&dir.join("c-long")));
+}
+
+#[test]
+fn sort_order_natural() {
+    use crate::SortOrder;
+
+    let dir = Dir::tmp();
+    let names = ["file10", "file2", "file02", "file1", "file", "file10b", "file9z", "x0"];
+    dir.touch_all(&names);
+
+    let walk = WalkDir::new(dir.path()).sort_order(SortOrder::Natural).min_depth(1);
+    let r = dir.run_recursive(walk);
+    r.assert_no_errors();
+    let sorted = ["file", "file1", "file02", "file2", "file9z", "file10", "file10b", "x0"];
+    assert_eq!(r.paths(), sorted.iter().map(|name| dir.join(name)).collect::<Vec<_>>());
+
+    let walk = WalkDir::new(dir.path()).sort_order(SortOrder::Bytes).min_depth(1);
+    let r = dir.run_recursive(walk);
+    let sorted = ["file", "file02", "file1", "file10", "file10b", "file2", "file9z", "x0"];
+    assert_eq!(r.paths(), sorted.iter().map(|name| dir.join(name)).collect::<Vec<_>>());
+}
+
+#[test]
+fn deterministic() {
+    let dir = Dir::tmp();
+    dir.mkdirp("b/d");
+    dir.mkdirp("a");
+    let names: Vec<_> = (0..40).rev().map(|i| format!("b/{:02}", i)).collect();
+    dir.touch_all(&names);
+    dir.touch_all(&["c", "b/d/f", "b/d/e", "a/Z", "a/z"]);
+
+    // Paths are ordered by their components, so siblings come in the order of their bytes.
+    let expected = dir.run_recursive(WalkDir::new(dir.path())).sorted_paths();
+
+    let walks = vec![
+        WalkDir::new(dir.path()).deterministic(true),
+        WalkDir::new(dir.path()).deterministic(true).max_open(1).buffer_size(0).max_backlog(0),
+        WalkDir::new(dir.path())
+            .sort_by(|a, b| b.file_name().cmp(a.file_name()))
+            .deterministic(true),
+    ];
+
+    for walk in walks {
+        let r = dir.run_recursive(walk);
+        r.assert_no_errors();
+        assert_eq!(r.paths(), expected);
+    }
+}
+
+#[test]
+fn descend_priority() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a");
+    dir.mkdirp("b");
+    dir.mkdirp("c/x");
+    dir.touch_all(&["a/f", "b/f", "c/x/f"]);
+
+    let likely = dir.join("c");
+    let walk = WalkDir::new(dir.path())
+        .sort_by_file_name()
+        .descend_priority(move |entry| if entry.path().starts_with(&likely) { 2 } else { 1 });
+    let r = dir.run_recursive(walk);
+    r.assert_no_errors();
+    let names = ["", "a", "b", "c", "c/x", "c/x/f", "a/f", "b/f"];
+    assert_eq!(r.paths(), names.iter().map(|name| dir.join(name)).collect::<Vec<_>>());
+
+    // Equal priorities are opened in the order they were queued, like a breadth first walk.
+    let walk = WalkDir::new(dir.path()).sort_by_file_name().descend_priority(|_| 0);
+    let r = dir.run_recursive(walk);
+    let names = ["", "a", "b", "c", "a/f", "b/f", "c/x", "c/x/f"];
+    assert_eq!(r.paths(), names.iter().map(|name| dir.join(name)).collect::<Vec<_>>());
+
+    // A queued directory can still be skipped right after it was yielded.
+    let mut it = WalkDir::new(dir.path()).sort_by_file_name().descend_priority(|_| 0).into_iter();
+    let mut found = vec![];
+    while let Some(entry) = it.next() {
+        let entry = entry.unwrap();
+        if entry.path() == dir.join("b") {
+            it.skip_current_dir();
+        }
+        found.push(entry.into_path());
+    }
+    let names = ["", "a", "b", "c", "a/f", "c/x", "c/x/f"];
+    assert_eq!(found, names.iter().map(|name| dir.join(name)).collect::<Vec<_>>());
+}
+
+#[test]
+fn sibling_order() {
+    use crate::SiblingOrder;
+
+    let dir = Dir::tmp();
+    dir.mkdirp("b/d");
+    dir.mkdirp("c");
+    dir.touch_all(&["a", "d", "b/e", "b/a"]);
+
+    let walk = WalkDir::new(dir.path()).sort_by_file_name();
+    let r = dir.run_recursive(walk.clone().sibling_order(SiblingOrder::FilesFirst));
+    r.assert_no_errors();
+    let names = ["", "a", "d", "b", "b/a", "b/e", "b/d", "c"];
+    assert_eq!(r.paths(), names.iter().map(|name| dir.join(name)).collect::<Vec<_>>());
+
+    let r = dir.run_recursive(walk.sibling_order(SiblingOrder::DirsFirst));
+    r.assert_no_errors();
+    let names = ["", "b", "b/d", "b/a", "b/e", "c", "a", "d"];
+    assert_eq!(r.paths(), names.iter().map(|name| dir.join(name)).collect::<Vec<_>>());
+
+    // Without another order, the files still come first.
+    let walk = WalkDir::new(dir.path()).sibling_order(SiblingOrder::FilesFirst).max_depth(1);
+    let r = dir.run_recursive(walk);
+    let dirs: Vec<_> = r.ents().iter().map(|entry| entry.file_type().is_dir()).collect();
+    assert_eq!(dirs, vec![true, false, false, true, true]);
+}
+
+#[test]
+fn skip_descendants_of() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.mkdirp("c");
+    dir.touch_all(&["a/1", "a/2", "a/b/3", "c/4"]);
+    let paths = |names: &[&str]| names.iter().map(|name| dir.join(name)).collect::<Vec<_>>();
+
+    let mut it = WalkDir::new(dir.path()).sort_by_file_name().into_iter();
+    it.skip_descendants_of(dir.join("a"));
+    let r = dir.run_recursive(it);
+    r.assert_no_errors();
+    assert_eq!(r.paths(), paths(&["", "a", "c", "c/4"]));
+
+    let walks = vec![
+        WalkDir::new(dir.path()).sort_by_file_name(),
+        WalkDir::new(dir.path()).sort_by_file_name().max_open(1),
+        WalkDir::new(dir.path()).sort_by_file_name().contents_first(true),
+    ];
+
+    for walk in walks {
+        // Excluded while the walk is inside.
+        let mut it = walk.into_iter();
+        let mut found = vec![];
+        while let Some(entry) = it.next() {
+            let entry = entry.unwrap();
+            if entry.path() == dir.join("a/1") {
+                it.skip_descendants_of(dir.join("a"));
+            }
+            found.push(entry.into_path());
+        }
+        found.sort();
+        assert_eq!(found, paths(&["", "a", "a/1", "c", "c/4"]));
+    }
+
+    let mut it = WalkDir::new(dir.path()).sort_by_file_name().breadth_first(true).into_iter();
+    let mut found = vec![];
+    while let Some(entry) = it.next() {
+        let entry = entry.unwrap();
+        if entry.path() == dir.join("c") {
+            it.skip_descendants_of(dir.join("a/b"));
+        }
+        found.push(entry.into_path());
+    }
+    assert_eq!(found, paths(&["", "a", "c", "a/1", "a/2", "a/b", "c/4"]));
+}
+
+#[test]
+fn start_after() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.mkdirp("c");
+    dir.touch_all(&["a/1", "a/2", "a/b/3", "c/4", "d"]);
+
+    let all = dir.run_recursive(WalkDir::new(dir.path()).deterministic(true)).paths();
+    for (idx, path) in all.iter().enumerate() {
+        let r = dir.run_recursive(WalkDir::new(dir.path()).start_after(path));
+        r.assert_no_errors();
+        assert_eq!(r.paths(), &all[idx + 1..]);
+    }
+
+    // The entry itself need not exist anymore.
+    let walk = WalkDir::new(dir.path()).contents_first(true).start_after(dir.join("a/15"));
+    let r = dir.run_recursive(walk);
+    r.assert_no_errors();
+    let expected: Vec<_> = ["a/2", "a/b", "a/b/3", "c", "c/4", "d"]
+        .iter()
+        .map(|name| dir.join(name))
+        .collect();
+    assert_eq!(r.paths(), expected);
+
+    // Directories before it are not opened.
+    let mut it = WalkDir::new(dir.path()).start_after(dir.join("c")).into_iter();
+    let found: Vec<_> = it.by_ref().map(|entry| entry.unwrap().into_path()).collect();
+    assert_eq!(found, vec![dir.join("c/4"), dir.join("d")]);
+    assert_eq!(1, it.stats().nr_openat());
+}
+
+#[test]
+#[allow(clippy::mutable_key_type)] // The cached path and metadata are not part of the key.
+fn dir_entry_eq_ord() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.touch_all(&["a/1", "a/b/2", "c"]);
+
+    let first = dir.run_recursive(WalkDir::new(dir.path()).sort_by_file_name()).ents().to_vec();
+    let second = dir.run_recursive(WalkDir::new(dir.path()).max_open(1)).ents().to_vec();
+    let set: std::collections::HashSet<_> = first.iter().cloned().collect();
+    assert_eq!(set, second.iter().cloned().collect());
+
+    let mut sorted = second;
+    sorted.sort();
+    assert_eq!(sorted, first);
+    assert!(first[0] < first[1]);
+    assert_ne!(first[0], first[1]);
+}
+
+#[test]
+fn remove_contents_first() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.mkdirp("c");
+    dir.touch_all(&["a/1", "a/b/2", "c/3"]);
+    dir.symlink_dir("c", "a/link");
+
+    let root = dir.join("a");
+    for entry in WalkDir::new(&root).contents_first(true) {
+        entry.unwrap().remove().unwrap();
+    }
+
+    // The link was removed, not the directory it leads to.
+    assert!(!root.exists());
+    assert!(dir.join("c/3").exists());
+
+    // Deeper than the open directories, those that were closed are opened again.
+    for sandbox in [false, true] {
+        dir.mkdirp("d/e/f/g/h");
+        dir.touch_all(&["d/1", "d/e/2", "d/e/f/3", "d/e/f/g/4", "d/e/f/g/h/5"]);
+        let root = dir.join("d");
+        let walk = WalkDir::new(&root).contents_first(true).max_open(2).sandbox(sandbox);
+        for entry in walk {
+            entry.unwrap().remove().unwrap();
+        }
+        assert!(!root.exists());
+    }
+}
+
+#[test]
+fn visit_dirs_once() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a/b");
+    dir.touch_all(&["a/1", "a/b/2"]);
+    dir.symlink_dir("a", "l1");
+    dir.symlink_dir("a/b", "l2");
+
+    let walk = WalkDir::new(dir.path()).follow_links(true).sort_by_file_name();
+    let r = dir.run_recursive(walk.clone());
+    r.assert_no_errors();
+    assert_eq!(r.ents().len(), 1 + 4 + 4 + 2);
+
+    // Each directory is yielded, but the contents of the first one found only.
+    let r = dir.run_recursive(walk.visit_dirs_once(true));
+    r.assert_no_errors();
+    let names = ["", "a", "a/1", "a/b", "a/b/2", "l1", "l2"];
+    assert_eq!(r.paths(), names.iter().map(|name| dir.join(name)).collect::<Vec<_>>());
+
+    let walk = WalkDir::new(dir.path()).follow_links(true).visit_dirs_once(true);
+    let r = dir.run_recursive(walk.into_parallel_iter());
+    r.assert_no_errors();
+    assert_eq!(r.ents().len(), 7);
+
+    // A root that was visited before.
+    let walk = WalkDir::new_multi(vec![dir.join("a"), dir.join("l1")]).visit_dirs_once(true);
+    let r = dir.run_recursive(walk.clone());
+    r.assert_no_errors();
+    assert_eq!(r.ents().len(), 4 + 1);
+    let r = dir.run_recursive(walk.into_parallel_iter());
+    r.assert_no_errors();
+    assert_eq!(r.ents().len(), 4 + 1);
+}
+
+#[test]
+fn cache_links() {
+    let dir = Dir::tmp();
+    dir.mkdirp("a");
+    dir.mkdirp("b");
+    dir.touch_all(&["b/1"]);
+    dir.symlink_dir("b", "a/to-b");
+    dir.symlink_dir("a", "l1");
+    dir.symlink_dir("a", "l2");
+
+    let walk = WalkDir::new(dir.path()).follow_links(true).sort_by_file_name();
+    let mut plain = walk.clone().into_iter();
+    let expected: Vec<_> = plain.by_ref().map(|entry| entry.unwrap().into_path()).collect();
+
+    // The link in `a` is resolved once, not for each of the three walks through `a`.
+    let mut cached = walk.cache_links(true).into_iter();
+    let found: Vec<_> = cached.by_ref().map(|entry| entry.unwrap().into_path()).collect();
+    assert_eq!(found, expected);
+    assert_eq!(cached.stats().nr_stat() + 2, plain.stats().nr_stat());
+
+    let walk = WalkDir::new(dir.path()).follow_links(true).cache_links(true);
+    let r = dir.run_recursive(walk.into_parallel_iter());
+    r.assert_no_errors();
+    assert_eq!(r.ents().len(), expected.len());
+}
+
+#[test]
+fn preopen_roots() {
+    let dir = Dir::tmp();
+    dir.mkdirp("foo/a/b");
+    dir.mkdirp("bar/c");
+    dir.touch_all(&["foo/a/b/x", "bar/c/y", "z"]);
+
+    let roots = vec![dir.join("foo"), dir.join("missing"), dir.join("bar"), dir.join("z")];
+    let mut plain = WalkDir::new_multi(roots.clone()).into_iter();
+    let expected = dir.run_recursive(plain.by_ref());
+    let mut walk = WalkDir::new_multi(roots.clone()).preopen_roots(true).into_iter();
+    let r = dir.run_recursive(walk.by_ref());
+    assert_eq!(r.errs().len(), 1);
+    assert_eq!(r.errs()[0].depth(), 0);
+    assert_eq!(r.sorted_paths(), expected.sorted_paths());
+    assert_eq!(walk.stats().nr_open(), plain.stats().nr_open());
+    assert_eq!(walk.stats().nr_stat(), plain.stats().nr_stat());
+
+    // The helpers hold descriptors out of the budget of the walk.
+    let walk = WalkDir::new_multi(roots.clone()).max_open(2).preopen_roots(true);
+    let r = dir.run_recursive(walk);
+    assert_eq!(r.sorted_paths(), expected.sorted_paths());
+
+    // Each root is still visited completely before the next one, in any order.
+    let r = dir.run_recursive(WalkDir::new_multi(roots.clone()).preopen_roots(true));
+    let paths = r.paths();
+    for root in &roots[..3] {
+        let inside: Vec<_> = paths.iter().map(|path| path.starts_with(root)).collect();
+        let start = inside.iter().position(|&inside| inside).unwrap_or(0);
+        let len = inside.iter().filter(|&&inside| inside).count();
+        assert!(inside[start..start + len].iter().all(|&inside| inside));
+    }
+
+    let walk = WalkDir::new_multi(roots.clone()).relative_paths(true).sandbox(true);
+    let relative = dir.run_recursive(walk.clone());
+    let r = dir.run_recursive(walk.preopen_roots(true));
+    assert_eq!(r.sorted_paths(), relative.sorted_paths());
+
+    let walk = WalkDir::new_multi(roots.clone()).breadth_first(true).preopen_roots(true);
+    let r = dir.run_recursive(walk);
+    let depths: Vec<_> = r.ents().iter().map(|entry| entry.depth()).collect();
+    assert_eq!(depths, [0, 0, 0, 1, 1, 2, 2, 3]);
+
+    // With a single descriptor the helpers only inspect the roots.
+    let walk = WalkDir::new_multi(roots.clone()).max_open(1).preopen_roots(true);
+    let r = dir.run_recursive(walk);
+    assert_eq!(r.sorted_paths(), expected.sorted_paths());
+
+    // Saving and splitting keep the roots that were not handed over yet.
+    let walk = WalkDir::new_multi(roots.clone()).preopen_roots(true);
+    let mut it = walk.clone().into_iter();
+    let mut found: Vec<_> = it.by_ref().take(1).map(|ent| ent.unwrap().into_path()).collect();
+    let state = it.save_state().unwrap();
+    let r = dir.run_recursive(walk.clone().resume(state));
+    found.extend(r.ents().iter().map(|ent| ent.path().to_owned()));
+    found.sort();
+    assert_eq!(found, expected.sorted_paths());
+
+    let (left, right) = walk.into_iter().split().unwrap();
+    let mut found = dir.run_recursive(left).sorted_paths();
+    found.extend(dir.run_recursive(right).sorted_paths());
+    found.sort();
+    assert_eq!(found, expected.sorted_paths());
+}
+
+#[test]
+fn sort_lazily() {
+    use std::cmp;
+    use std::ffi::OsString;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let dir = Dir::tmp();
+    let names: Vec<_> = (0..1000).map(|n| format!("a/{:04}", n)).collect();
+    dir.mkdirp("a");
+    dir.touch_all(&names);
+
+    let compared = Arc::new(AtomicUsize::new(0));
+    let counter = compared.clone();
+    let mut walk = WalkDir::new(dir.join("a"))
+        .sort_by(move |a, b| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            a.file_name().cmp(b.file_name())
+        })
+        .into_iter();
+
+    // The first entry is found without sorting the whole directory.
+    walk.next().unwrap().unwrap();
+    assert_eq!(walk.next().unwrap().unwrap().file_name(), "0000");
+    assert!(compared.load(Ordering::Relaxed) < 3 * names.len());
+
+    let rest: Vec<_> = walk.map(|entry| entry.unwrap().file_name().to_owned()).collect();
+    let expected: Vec<OsString> = (1..1000).map(|n| format!("{:04}", n).into()).collect();
+    assert_eq!(rest, expected);
+
+    // Equal entries keep the order they were read in.
+    let unsorted = dir.run_recursive(WalkDir::new(dir.join("a")));
+    let equal = dir.run_recursive(WalkDir::new(dir.join("a")).sort_by(|_, _| cmp::Ordering::Equal));
+    assert_eq!(equal.paths(), unsorted.paths());
+}
+
+#[test]
+fn bounded_memory() {
+    let dir = Dir::tmp();
+    dir.mkdirp("big/sub/deeper");
+    let names: Vec<_> = (0..2000).map(|i| format!("big/a-longer-file-name-{:04}", i)).collect();
+    dir.touch_all(&names);
+    dir.touch_all(&["big/sub/deeper/x"]);
+
+    let expected = dir.run_recursive(WalkDir::new(dir.path())).sorted_paths();
+    let walk = WalkDir::new(dir.path())
+        .bounded_memory(true)
+        .max_open(1)
+        .buffer_size(1 << 10)
+        .max_backlog(1 << 10);
+    let mut it = walk.into_iter();
+    let r = dir.run_recursive(it.by_ref());
+    r.assert_no_errors();
+    assert_eq!(r.sorted_paths(), expected);
+    assert_eq!(it.stats().nr_buffer_grow(), 0);
+    assert!(it.stats().nr_seek() > 0);
+    // The backlog ends with the buffer that reached the limit.
+    assert!(it.stats().max_backlog() <= 2 << 10, "{}", it.stats().max_backlog());
+
+    // Without the limit, the directory is read to the end when it is closed.
+    let walk = WalkDir::new(dir.path()).max_open(1).max_backlog(usize::MAX);
+    let mut it = walk.into_iter();
+    it.by_ref().for_each(drop);
+    assert!(it.stats().max_backlog() > 2 << 10);
+}
+
+#[test]
+#[should_panic = "bounded_memory"]
+fn bounded_memory_sorted() {
+    let dir = Dir::tmp();
+    let _ = WalkDir::new(dir.path()).bounded_memory(true).sort_by_file_name().into_iter();
+}
@@ -0,0 +1,62 @@
+//! Visiting a tree with a visitor that keeps state for each directory.
+use crate::events::{Event, Events};
+use crate::walker::{DirEntry, Error};
+
+/// Called for each entry of a walk, see [`WalkDir::visit`](crate::WalkDir::visit).
+///
+/// Each directory gets a state when it is entered. Its contents are visited with that state at
+/// hand, and it is handed back once the directory is left, along with the state of its parent.
+/// The roots have no parent.
+pub trait Visitor {
+    /// The state of a directory.
+    type State;
+
+    /// Enter a directory, before any of its contents.
+    fn pre_dir(&mut self, dir: &DirEntry, parent: Option<&mut Self::State>) -> Self::State;
+
+    /// Visit an entry that is not a directory.
+    fn file(&mut self, entry: &DirEntry, parent: Option<&mut Self::State>) {
+        let _ = (entry, parent);
+    }
+
+    /// Leave a directory, after all of its contents.
+    fn post_dir(&mut self, dir: DirEntry, state: Self::State, parent: Option<&mut Self::State>) {
+        let _ = (dir, state, parent);
+    }
+
+    /// Handle an error of the walk, returning it ends the visit.
+    ///
+    /// By default each error ends the visit.
+    fn error(&mut self, err: Error) -> Result<(), Error> {
+        Err(err)
+    }
+}
+
+/// Visit all events, with the state of each directory that was entered and not left yet.
+pub(crate) fn visit<V: Visitor>(events: Events, visitor: &mut V) -> Result<(), Error> {
+    let mut states = vec![];
+    for event in events {
+        let event = match event {
+            Ok(event) => event,
+            Err(err) => {
+                visitor.error(err)?;
+                continue;
+            }
+        };
+
+        match event {
+            Event::Enter(dir) => {
+                let state = visitor.pre_dir(&dir, states.last_mut());
+                states.push(state);
+            }
+            Event::File(entry) => visitor.file(&entry, states.last_mut()),
+            Event::Leave(dir) => {
+                // Each directory that is left was entered before.
+                let state = states.pop().unwrap();
+                visitor.post_dir(dir, state, states.last_mut());
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,25 +1,84 @@
 use crate::getdent::DirentBuf;
+use crate::name::{natural_cmp, FileName};
+use crate::preopen::Preopen;
 
-use core::mem;
+use core::convert::TryFrom;
+use core::{fmt, mem};
+use core::iter::FusedIterator;
+use std::borrow::Cow;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs::File;
 use std::io;
 use std::ffi::{CStr, CString, OsStr, OsString};
 use std::path::{Component, Path, PathBuf};
-use std::sync::Arc;
-use std::os::unix::fs::FileTypeExt;
-use std::os::unix::ffi::OsStrExt;
+use std::sync::{mpsc, Arc, Mutex, Weak};
+use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Instant, SystemTime};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{FromRawFd, IntoRawFd, OwnedFd};
 use once_cell::sync::OnceCell;
 
 use super::UnixFileType as FileTypeInner;
+use super::ahead::{Pending, Worker};
+use super::events::Events;
+use super::sizes::Sizes;
+use super::visit::{self, Visitor};
+#[cfg(feature = "ignore")]
+use super::gitignore::{IgnoreFiles, Ignores};
+#[cfg(feature = "globset")]
+use super::glob::{GlobFilter, Pattern};
+use super::metadata::Metadata;
+use super::open::OpenOptions;
+use super::parallel::ParallelIter;
+use super::state::{DirState, EntryState, WalkState};
+#[cfg(feature = "rayon")]
+use super::par::ParEntries;
+#[cfg(feature = "tokio")]
+use super::stream::WalkStream;
 use super::getdent::{DirentErr, Entry, More};
 
+/// The size of the dirent buffer for a directory we know nothing about.
+const DEFAULT_BUFFER_SIZE: usize = 1 << 14;
+/// Bounds for buffers sized after the directory.
+///
+/// The minimum must fit at least one entry with a name of maximum length.
+const MIN_BUFFER_SIZE: usize = 1 << 10;
+const MAX_BUFFER_SIZE: usize = 1 << 16;
+/// The default limit for enlarging the buffer of a huge directory.
+const DEFAULT_MAX_BUFFER_SIZE: usize = 1 << 20;
+/// Calls to `getdents64` that filled the same buffer before it is enlarged.
+const GROW_AFTER: usize = 4;
+/// The default limit for names read into memory when a directory is closed early.
+const DEFAULT_MAX_BACKLOG: usize = 1 << 20;
+/// The default number of open directories, unless the process may open very few files.
+const DEFAULT_MAX_OPEN: usize = 10;
+/// How often a system call interrupted by a signal is retried by default.
+const DEFAULT_EINTR_RETRIES: usize = 16;
+
 /// Configure walking over all files in a directory tree.
+///
+/// A configured walk can be started multiple times by iterating over a reference to it.
+#[derive(Clone)]
 pub struct WalkDir {
     /// The user supplied configuration.
     config: Configuration,
-    path: PathBuf,
+    /// The roots, in the order they are walked.
+    roots: Vec<PathBuf>,
+    /// The type of the root, if there is only one and we already know it.
+    root_type: Option<FileTypeInner>,
 }
 
 /// The main iterator.
+///
+/// The iterator can be moved to another thread between entries, and the entries sent elsewhere
+/// while the walk continues. An entry keeps the path of its directory alive, but not its
+/// descriptor. Inspecting or opening the entry uses that descriptor while the walk still has it
+/// open, and the path of the entry once it is closed.
+///
+/// A directory that can not be opened is reported only as an error, in place of its entry. With
+/// [`WalkDir::collect_denied`] a directory without permission is yielded instead, without its
+/// contents.
 pub struct IntoIter {
     /// The user supplied configuration.
     config: Configuration,
@@ -29,11 +88,62 @@ pub struct IntoIter {
     open_budget: usize,
     /// Statistics about the system calls etc.
     stats: Stats,
+    /// Entries with a reported type since the last verification.
+    unverified: usize,
+    /// The device of the root directory, once it was opened.
+    root_device: Option<libc::dev_t>,
+    /// Directories that are yielded after their contents, innermost last.
+    deferred: Vec<DirEntry>,
+    /// Directories whose contents are visited after the current level, when breadth first.
+    queue: BinaryHeap<Queued>,
+    /// The number of directories queued so far, to keep those of the same priority in order.
+    nr_queued: u64,
+    /// The last yielded entry if it is a queued directory, kept out of the queue until the walk
+    /// advances so that it can still be skipped.
+    queued_last: Option<Queued>,
+    /// The files yielded so far, when skipping further links to them.
+    yielded_files: FileIds,
+    /// The directories descended into so far, when visiting each only once.
+    visited_dirs: FileIds,
+    /// The targets of the links followed so far, when caching them.
+    link_targets: LinkTargets,
+    /// Buffers of closed directories, for the next ones we open.
+    buffers: BufferPool,
+    /// Directories that could not be opened for lack of permission, instead of their errors.
+    denied: Vec<PathBuf>,
+    /// Directories whose contents are never visited, given while the walk runs.
+    skipped: Vec<PathBuf>,
+    /// The entry after which to start yielding, until an entry after it was found.
+    start_after: Option<PathBuf>,
+    /// Set to end the walk, shared with its cancel tokens.
+    cancelled: Arc<AtomicBool>,
+    /// The entries yielded so far.
+    nr_yielded: usize,
+    /// The directories that were read completely.
+    nr_finished: usize,
+    /// The roots that helpers are still opening, when pre-opening them.
+    roots: Option<Preopen<OpenedRoot>>,
+    /// Roots that were opened by a helper and are not yet descended into.
+    preopened: Vec<(PathBuf, DirFd)>,
+    /// The descriptors that the helpers may hold, taken from the budget while they run.
+    preopen_budget: usize,
+}
+
+/// A root that was opened by a helper, see [`WalkDir::preopen_roots`].
+struct OpenedRoot {
+    path: PathBuf,
+    file_type: Option<FileTypeInner>,
+    fd: Option<DirFd>,
+    /// If the helper inspected the root, its type was not known.
+    nr_stat: usize,
+    /// The directories the helper opened, including the root of a sandbox.
+    nr_open: usize,
 }
 
 /// Describes a file that was found.
 ///
-/// All parents of this entry have already been yielded before.
+/// All parents of this entry have already been yielded before. Entries may be used on any thread,
+/// see [`IntoIter`].
 #[derive(Debug, Clone)]
 pub struct DirEntry {
     /// The file type reported by the call to `getdent`.
@@ -44,23 +154,78 @@ pub struct DirEntry {
     file_name: EntryPath,
     /// The normalized full path of the entry.
     full_path: OnceCell<PathBuf>,
+    /// The meta data, once it was inspected.
+    metadata: OnceCell<Box<Metadata>>,
+    /// Whether this entry is a symbolic link that was followed.
+    follow_link: bool,
+    /// The inode number reported by the call to `getdent`.
+    ino: Option<u64>,
+    /// The offset after this entry in the directory stream, reported by `getdent`.
+    offset: Option<i64>,
 }
 
 #[derive(Debug, Clone)]
 enum EntryPath {
     /// We have already allocate the whole path in its own buffer.
     Full(PathBuf),
+    /// The whole path, relative to the base of the walk instead of the working directory.
+    At {
+        path: PathBuf,
+        base: Arc<Base>,
+    },
     /// The path is given as the filename alone.
     Name {
-        name: OsString,
+        name: FileName,
         /// The parent directory of the entry.
         parent: Arc<Node>,
     },
 }
 
+/// A handle to the directory containing an entry.
+///
+/// This is cheap to clone and compare, the path of the directory is only allocated on request.
+/// Two handles are equal if they refer to the same directory visited during the walk.
+#[derive(Clone, Debug)]
+pub struct Parent {
+    node: Arc<Node>,
+}
+
 #[derive(Debug)]
 pub struct Error {
-    _private: (),
+    /// The depth of the entry at which the error occurred.
+    depth: usize,
+    inner: ErrorInner,
+}
+
+#[derive(Debug)]
+enum ErrorInner {
+    Io {
+        path: Option<PathBuf>,
+        op: Op,
+        err: io::Error,
+    },
+    /// A followed link leads back to one of its ancestors.
+    Loop {
+        ancestor: PathBuf,
+        child: PathBuf,
+    },
+    /// The walk ended early at one of its limits.
+    Truncated(Limit),
+}
+
+/// The limits that end a walk early.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Limit {
+    Entries(usize),
+    Deadline,
+}
+
+/// The system call that failed.
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Open,
+    Read,
+    Stat,
 }
 
 /// The type of a file entry.
@@ -72,32 +237,322 @@ pub struct FileType {
     inner: Option<FileTypeInner>,
 }
 
-#[derive(Copy, Clone)]
-struct Configuration {
-    min_depth: usize,
-    max_depth: usize,
+/// What to do with a directory that disappears before it is opened, see [`WalkDir::vanished`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Vanished {
+    /// Leave it out, as if it was removed before its parent was read. In a breadth first walk
+    /// the directory itself was already yielded.
+    Ignore,
+    /// Yield the error in its place and continue with the rest of the walk. This is the default.
+    YieldError,
+    /// Yield the error and end the walk.
+    Abort,
+}
+
+/// How to handle the errors of a walk, see [`WalkDir::on_error`].
+pub enum ErrorPolicy {
+    /// Leave all errors out.
+    Ignore,
+    /// Yield each error in place of the entry and continue with the rest of the walk. This is the
+    /// default.
+    Yield,
+    /// Yield the first error and end the walk.
+    Abort,
+    /// Decide for each error. In a parallel walk it is called from the threads, one at a time.
+    Decide(Box<dyn FnMut(&Error) -> Decision + Send>),
+}
+
+/// What to do with a single error, returned by [`ErrorPolicy::Decide`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// Leave it out and continue.
+    Ignore,
+    /// Yield it and continue.
+    Yield,
+    /// Yield it and end the walk.
+    Abort,
+}
+
+/// A built-in order of siblings, see [`WalkDir::sort_order`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortOrder {
+    /// By the bytes of the file names.
+    Bytes,
+    /// By the file names with each run of digits compared by its value, so that `file2` comes
+    /// before `file10`. This is the order of `ls -v`.
+    Natural,
+}
+
+/// Where directories come among their siblings, see [`WalkDir::sibling_order`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SiblingOrder {
+    /// Directories and other entries are mixed, in the order of the directory. This is the
+    /// default.
+    Mixed,
+    /// All other entries come before any of the directories.
+    FilesFirst,
+    /// All directories, and their contents, come before any other entry.
+    DirsFirst,
+}
+
+type ErrorCallback = Arc<Mutex<dyn FnMut(&Error) -> Decision + Send>>;
+type ProgressCallback = Arc<Mutex<dyn FnMut(&Progress) + Send>>;
+type PriorityFn = Arc<Mutex<dyn FnMut(&DirEntry) -> u32 + Send>>;
+/// A buffer filled on the helper thread, with the outcome of filling it.
+type Filled = (DirentBuf, io::Result<More>);
+
+#[derive(Clone)]
+pub(crate) struct Configuration {
+    pub(crate) min_depth: usize,
+    pub(crate) max_depth: usize,
     max_open: usize,
-    follow_links: bool,
+    /// End the walk after yielding this many entries.
+    max_entries: usize,
+    /// End the walk at this point in time.
+    deadline: Option<Instant>,
+    pub(crate) follow_links: bool,
     contents_first: bool,
-    same_file_system: bool,
+    breadth_first: bool,
+    /// Opens the queued directories by their priority, if set.
+    priority: Option<PriorityFn>,
+    pub(crate) same_file_system: bool,
+    dedup_hardlinks: bool,
+    /// The files yielded by all threads of a parallel walk, set for each of its levels.
+    shared_files: Option<FileIds>,
+    detect_loops: bool,
+    pub(crate) visit_dirs_once: bool,
+    cache_links: bool,
+    /// The link targets of all threads of a parallel walk, set for each of its levels.
+    shared_links: Option<LinkTargets>,
+    pub(crate) skip_loops: bool,
+    pub(crate) vanished: Vanished,
+    /// Decides about the errors to yield, all are yielded if unset.
+    on_error: Option<ErrorCallback>,
+    /// Collect the paths of directories that may not be opened instead of yielding errors.
+    pub(crate) collect_denied: bool,
+    /// Called with the progress of the walk.
+    progress: Option<ProgressCallback>,
+    /// Report the progress after this many entries, not only after each directory.
+    progress_every: usize,
+    /// The types of entries that are yielded.
+    types: TypeSet,
+    with_metadata: bool,
+    eintr_retries: usize,
+    require_dir_root: bool,
+    verify_types: usize,
+    name_filter: NameFilter,
+    /// Only entries whose name is accepted by all of them are yielded.
+    yielded_names: Vec<NamePredicate>,
+    /// Patterns that need more than the raw file name.
+    #[cfg(feature = "globset")]
+    globs: GlobFilter,
+    /// Skip entries matched by ignore files, if set.
+    #[cfg(feature = "ignore")]
+    ignore_files: Option<IgnoreFiles>,
+    sorter: Option<Comparator>,
+    /// Sort by the bytes of file names, instead of any other order.
+    deterministic: bool,
+    /// Partition the siblings into directories and other entries, before they are sorted.
+    sibling_order: SiblingOrder,
+    /// Skip all entries up to this one, in the deterministic order.
+    start_after: Option<PathBuf>,
+    /// The size of all dirent buffers, instead of sizing them after each directory.
+    buffer_size: Option<usize>,
+    /// The limit for enlarging the buffer of a directory with many entries.
+    max_buffer_size: usize,
+    /// The bytes of names read from a directory that is closed early, before it is reopened.
+    max_backlog: usize,
+    /// Keep no entries beyond their directory, and buffers of a fixed size.
+    bounded_memory: bool,
+    /// Fills the next buffer of open directories ahead of the walk, if set.
+    read_ahead: Option<Arc<Worker>>,
+    /// The offset at which to start reading the root directory.
+    root_offset: Option<i64>,
+    /// The directory that the roots are relative to, instead of the working directory.
+    base_dir: Option<Arc<DirFd>>,
+    /// Yield paths relative to their root.
+    relative_paths: bool,
+    /// Open directories only beneath their root.
+    sandbox: bool,
+    /// Resolve the roots like the entries below this root, instead of as roots of their own.
+    root_base: Option<Arc<Base>>,
+    /// The open directory containing the root, and the name of the root in it.
+    root_dir: Option<(Arc<DirFd>, CString)>,
+    /// Threads of a parallel walk, zero to use all available cores.
+    pub(crate) threads: usize,
+    /// Open several roots on helper threads, before they are walked.
+    preopen_roots: bool,
+}
+
+/// A root whose entries are not simply resolved by their path, shared by all of them.
+#[derive(Debug)]
+pub(crate) struct Base {
+    /// The directory given to `new_at`, that the root path is relative to.
+    dir: Option<Arc<DirFd>>,
+    /// The path of the root.
+    root: PathBuf,
+    /// If the paths of entries are relative to the root, instead of starting with it.
+    relative: bool,
+    /// The root itself for a sandboxed walk, opened along with the first directory.
+    sandbox: Option<OnceCell<DirFd>>,
 }
 
-#[derive(Debug, Default)]
-struct Stats {
+/// The argument of `openat2`, from `linux/openat2.h`.
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+/// Fail on `..` components or absolute paths that would lead outside of the directory.
+const RESOLVE_BENEATH: u64 = 0x08;
+/// Fail on all symbolic links, including those of a race that replaced a directory.
+const RESOLVE_NO_SYMLINKS: u64 = 0x04;
+
+/// Predicates on the raw bytes of file names.
+///
+/// These are checked while parsing the dirent buffer, before any allocation for the entry.
+#[derive(Clone, Default)]
+struct NameFilter {
+    predicates: Vec<NamePredicate>,
+    /// Skip entries whose name starts with a dot.
+    skip_hidden: bool,
+    /// Skip entries whose name is rejected by one of these, unless they could be descended into.
+    yielded: Vec<NamePredicate>,
+    /// Entries of these reported types could be descended into.
+    descended: TypeSet,
+    /// Entries of these reported types are neither yielded nor descended into.
+    skipped: TypeSet,
+}
+
+/// A set of file types, including the unknown type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct TypeSet(u8);
+
+type NamePredicate = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+/// The order of entries within each directory.
+///
+/// Shared between all walks started from the same configuration.
+type Comparator = Arc<Mutex<dyn FnMut(&DirEntry, &DirEntry) -> core::cmp::Ordering + Send + Sync>>;
+
+/// Counts of the system calls made by a walk.
+///
+/// Returned by [`IntoIter::stats`]. Each half of a split walk counts separately.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
     nr_close: usize,
     nr_getdent: usize,
     nr_open: usize,
     nr_openat: usize,
     nr_stat: usize,
+    /// Reported file types that were checked against `stat`.
+    nr_verify: usize,
+    /// Reported file types that differed from the `stat` result.
+    nr_type_mismatch: usize,
+    /// Dirent buffers that were enlarged for a directory with many entries.
+    nr_buffer_grow: usize,
+    nr_seek: usize,
+    /// The most bytes of names held for a directory that was closed early.
+    max_backlog: usize,
+    #[cfg(feature = "alloc-stats")]
+    allocs: AllocStats,
+    #[cfg(feature = "histogram-stats")]
+    histograms: Histograms,
+}
+
+/// Where the system calls of a walk went, counted with the `histogram-stats` feature.
+///
+/// Histograms are in buckets of powers of two: bucket `0` counts the value zero and bucket `i`
+/// counts the values from `2^(i-1)` up to below `2^i`.
+#[cfg(feature = "histogram-stats")]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Histograms {
+    entries_per_dir: Vec<usize>,
+    getdent_bytes: Vec<usize>,
+    by_depth: Vec<Stats>,
+    nr_spill: usize,
+}
+
+/// Heap allocations made by the walker itself, not counting those of the caller.
+#[cfg(feature = "alloc-stats")]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct AllocStats {
+    /// Dirent buffers for newly opened directories.
+    buffers: usize,
+    /// Names that were too long to be stored inline.
+    names: usize,
+    /// Shared parent nodes of open directories.
+    nodes: usize,
+    /// Full paths, for opening, stat or the backlog of closed directories.
+    paths: usize,
+}
+
+/// Dirent buffers that are no longer used by their directory.
+///
+/// Holds no more buffers than directories may be open at the same time.
+struct BufferPool {
+    buffers: Vec<DirentBuf>,
+    limit: usize,
+}
+
+/// A system call made by the walker.
+#[derive(Clone, Copy)]
+enum Syscall {
+    Close,
+    Getdent,
+    Open,
+    Openat,
+    Seek,
+    Stat,
+}
+
+/// The purpose of an allocation.
+#[derive(Clone, Copy)]
+enum Alloc {
+    Buffer,
+    Name,
+    Node,
+    Path,
 }
 
 /// Completed directory nodes that are parents of still open nodes or active entries.
 #[derive(Debug)]
 struct Node {
-    /// The depth at which this node occurs.
+    /// The depth of the entries of this directory.
     depth: usize,
     /// The path of this node.
     path: EntryPath,
+    /// The complete path, built once when the first entry of this directory needs its path.
+    full_path: OnceCell<PathBuf>,
+    /// The identity of the directory, only determined when following links.
+    id: Option<FileId>,
+    /// The descriptor of the directory, while it is open.
+    fd: Weak<DirFd>,
+    /// The ignore files that apply to the entries, once the directory was opened.
+    #[cfg(feature = "ignore")]
+    ignores: Option<Arc<Ignores>>,
+}
+
+/// Files by their identity, shared by all parts of a walk that skip files seen before.
+pub(crate) type FileIds = Arc<Mutex<HashSet<FileId>>>;
+
+/// The targets of followed links, by the directory of each link and its name.
+pub(crate) type LinkTargets = Arc<Mutex<HashMap<FileId, HashMap<OsString, libc::stat>>>>;
+
+/// Identifies a file independent of the path by which it was reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct FileId {
+    pub(crate) dev: libc::dev_t,
+    pub(crate) ino: libc::ino_t,
+}
+
+/// A directory in the queue, opened by priority and then in the order it was queued.
+struct Queued {
+    priority: u32,
+    seq: u64,
+    dir: DirEntry,
 }
 
 enum WorkItem {
@@ -110,7 +565,8 @@ enum WorkItem {
 /// Directories with a file descriptor.
 struct Open {
     /// The open file descriptor.
-    fd: DirFd,
+    /// Entries borrow it through their parent node, to open themselves relative to it.
+    fd: Arc<DirFd>,
     /// The buffer for reading entries of this directory.
     buffer: DirentBuf,
     /// The directory depth of this descriptor.
@@ -118,6 +574,26 @@ struct Open {
     /// The parent representation of this node.
     /// Not to be confused with the potentially still open parent directory.
     as_parent: Arc<Node>,
+    /// All remaining entries, when sorting.
+    /// The descriptor is kept open for opening the children.
+    sorted: Option<Sorted>,
+    /// The entries read from the directory so far, including `.` and `..`.
+    nr_entries: usize,
+    /// Calls to `getdents64` that returned entries, since the buffer was last enlarged.
+    nr_fills: usize,
+    /// The next entries, read into another buffer on the helper thread.
+    ahead: Option<Pending<Filled>>,
+    /// The buffer for reading ahead, while it is not in use.
+    spare: Option<DirentBuf>,
+}
+
+/// The entries of a sorted directory, ordered as they are taken.
+///
+/// A heap by the comparator of the walk, so the first entry is found without sorting all others.
+/// Entries that compare equal keep the order in which they were read.
+struct Sorted {
+    heap: Vec<(usize, DirEntry)>,
+    sorter: Comparator,
 }
 
 /// Describes a directory that had to be closed, and its entries read to memory.
@@ -125,500 +601,4388 @@ struct Closed {
     /// The directory depth of the directory.
     depth: usize,
     /// The children.
-    children: Vec<Backlog>,
+    children: Backlog,
     /// The parent representation of this node.
     /// The parent directory is also surely closed but children might not be.
     as_parent: Option<Arc<Node>>,
+    /// The offset to continue reading at, if not all entries were read before closing.
+    resume: Option<libc::off_t>,
 }
 
+#[derive(Debug)]
 struct DirFd(libc::c_int);
 
-/// Describes an item of a closed directory.
+/// Describes the items of a closed directory.
 ///
 /// The directories represented by this type are no-one's parent yet.
 ///
 /// Note that by using `openat` we can avoid having to construct the complete path as a single
-/// `PathBuf` but this requires keeping the parent `fd` open.
-///
-/// TODO: what if we use a dequeue to actually allocate these consecutively in memory?
+/// `PathBuf` but this requires keeping the parent `fd` open. Since the file descriptor was closed
+/// we can't use `openat` but need to reconstruct the full path from the parent. Only the names are
+/// kept, one after another in a single allocation, instead of one path for each item.
+#[derive(Default)]
 struct Backlog {
-    /// The complete path up to here.
-    /// Since the file descriptor was closed we can't use `openat` but need to reconstruct the full
-    /// path. We might want to track statistics on this since it really is annoying.
-    file_path: PathBuf,
+    /// The names of all items, back to back.
+    /// For the root, which has no parent, this is its complete path instead.
+    names: Vec<u8>,
+    items: Vec<BacklogItem>,
+}
+
+/// Describes an item of a closed directory.
+struct BacklogItem {
+    /// Where the name starts in the names of the backlog, it ends where the next one starts.
+    start: usize,
     file_type: Option<FileTypeInner>,
+    ino: Option<u64>,
+    offset: Option<i64>,
 }
 
 // Public interfaces.
 
 impl WalkDir {
     pub fn new(path: impl AsRef<Path>) -> Self {
+        Self::from_path_buf(path.as_ref().to_owned())
+    }
+
+    /// Walk from a root given as an owned `OsString`, without copying it.
+    pub fn from_os_string(path: OsString) -> Self {
+        Self::from_path_buf(path.into())
+    }
+
+    /// Walk from a root given as raw bytes, without copying or validating them.
+    pub fn from_bytes(path: Vec<u8>) -> Self {
+        Self::from_os_string(OsString::from_vec(path))
+    }
+
+    /// Walk from a root given as a C string, for example received from another system call.
+    pub fn from_c_string(path: CString) -> Self {
+        Self::from_bytes(path.into_bytes())
+    }
+
+    /// Walk relative to a directory descriptor instead of the working directory.
+    ///
+    /// The root path is resolved relative to `dir`, and so are the paths of all entries. Yielded
+    /// paths are relative to it as well. Pass `"."` to walk the directory itself. The descriptor
+    /// may be opened with `O_PATH`. It is closed when the walk and all of its entries are gone.
+    /// To keep using a borrowed descriptor, pass a duplicate from
+    /// [`BorrowedFd::try_clone_to_owned`](std::os::unix::io::BorrowedFd::try_clone_to_owned).
+    pub fn new_at(dir: impl Into<OwnedFd>, path: impl AsRef<Path>) -> Self {
+        let mut walk = Self::new(path);
+        walk.config.base_dir = Some(Arc::new(DirFd(dir.into().into_raw_fd())));
+        walk
+    }
+
+    /// Walk several roots one after another, as a single walk.
+    ///
+    /// The roots share the limit of open directories and the statistics. Each root has depth `0`
+    /// and is visited completely before the next one, unless walking breadth first. Then all
+    /// roots form the first level.
+    pub fn new_multi<I>(roots: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<PathBuf>,
+    {
         WalkDir {
             config: Configuration::default(),
-            path: path.as_ref().to_owned(),
+            roots: roots.into_iter().map(Into::into).collect(),
+            root_type: None,
         }
     }
 
+    /// Open all roots up front on helper threads, when walking several of them.
+    ///
+    /// A root that is slow to open, like one on a network mount, then does not delay the roots
+    /// after it. The roots are walked in the order that opening them finishes, not in the order
+    /// they were given. The roots that the helpers hold open count against `max_open`, the walk
+    /// keeps at least one descriptor for itself. A root that could not be opened is opened again
+    /// when it is walked, which reports the error. Queued roots of a breadth first walk are only
+    /// inspected up front. Saving or splitting the walk stops the helpers without waiting for
+    /// them, the remaining roots are then opened when they are walked.
+    pub fn preopen_roots(mut self, yes: bool) -> Self {
+        self.config.preopen_roots = yes;
+        self
+    }
+
+    fn from_path_buf(path: PathBuf) -> Self {
+        Self::new_multi(Some(path))
+    }
+
+    /// Only yield entries at least this deep, the root has depth `0`.
+    ///
+    /// Shallower directories are still descended into. A depth beyond the maximum depth is lowered
+    /// to it.
     pub fn min_depth(mut self, n: usize) -> Self {
         self.config.min_depth = n;
+        if self.config.min_depth > self.config.max_depth {
+            self.config.min_depth = self.config.max_depth;
+        }
+        self
+    }
+
+    /// End the walk after yielding this many entries.
+    ///
+    /// If there are more entries, an error is yielded in their place that is
+    /// [truncated](Error::is_truncated), and the walk closes all its directories and ends. Errors
+    /// do not count as entries.
+    pub fn max_entries(mut self, n: usize) -> Self {
+        self.config.max_entries = n;
         self
     }
 
+    /// End the walk once the deadline has passed.
+    ///
+    /// The deadline is checked with each entry. When it has passed and there are more entries, an
+    /// error is yielded in their place that is [truncated](Error::is_truncated), and the walk
+    /// closes all its directories and ends.
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.config.deadline = Some(deadline);
+        self
+    }
+
+    /// Only yield entries at most this deep, the root has depth `0`.
+    ///
+    /// Directories at this depth are not opened at all. A depth below the minimum depth is raised
+    /// to it.
     pub fn max_depth(mut self, n: usize) -> Self {
         self.config.max_depth = n;
+        if self.config.max_depth < self.config.min_depth {
+            self.config.max_depth = self.config.min_depth;
+        }
         self
     }
 
+    /// Keep at most this many directories open at the same time.
+    ///
+    /// Deeper directories are opened by their path, after their ancestors were read into memory
+    /// and closed. The default is 10, or less when the limit of open files of the process is
+    /// very low. Should opening a directory still fail for lack of descriptors, the least
+    /// recently used directory is closed and the walk keeps one directory less open from then on.
     pub fn max_open(mut self, n: usize) -> Self {
         self.config.max_open = n;
         self
     }
 
-    pub fn follow_links(mut self, yes: bool) -> Self {
-        self.config.follow_links = yes;
+    /// Use dirent buffers of this many bytes for every directory.
+    ///
+    /// Each open directory has its own buffer and each call to `getdents64` fills it as far as
+    /// possible. By default the buffer is sized after the directory, between 1 KiB and 64 KiB.
+    /// Larger buffers need fewer calls for huge directories. Sizes below 1 KiB are raised to that
+    /// so that any entry fits. Buffers still grow for directories with many entries, see
+    /// [`WalkDir::max_buffer_size`].
+    pub fn buffer_size(mut self, bytes: usize) -> Self {
+        self.config.buffer_size = Some(bytes.max(MIN_BUFFER_SIZE));
         self
     }
 
-    pub fn sort_by<F>(self, cmp: F) -> Self where
-        F: FnMut(&DirEntry, &DirEntry) -> core::cmp::Ordering + Send + Sync + 'static,
-    {
-        todo!()
+    /// Enlarge the buffer of a directory with many entries up to this many bytes.
+    ///
+    /// When reading a directory takes several calls to `getdents64` its buffer is doubled, up to
+    /// this limit. The default is 1 MiB. The buffer is never shrunk, so a limit below the initial
+    /// size of a buffer disables this.
+    pub fn max_buffer_size(mut self, bytes: usize) -> Self {
+        self.config.max_buffer_size = bytes;
+        self
     }
 
-    pub fn contents_first(mut self, yes: bool) -> Self {
-        self.config.contents_first = yes;
+    /// Read at most about this many bytes of names when a directory has to be closed early.
+    ///
+    /// When more directories would be open than allowed by `max_open`, one of them is closed and
+    /// its remaining entries are read into memory. Once this limit is reached the rest of the
+    /// directory is not read. Instead it is reopened later and reading continues where it left
+    /// off. The default is 1 MiB.
+    pub fn max_backlog(mut self, bytes: usize) -> Self {
+        self.config.max_backlog = bytes;
         self
     }
 
-    pub fn same_file_system(mut self, yes: bool) -> Self {
-        self.config.same_file_system = yes;
+    /// Bound the memory of the walk by the depth of the tree, not the size of its directories.
+    ///
+    /// Each open directory holds one dirent buffer of `buffer_size`, or of the default size, that
+    /// is never enlarged. A directory that is closed early holds about `max_backlog` bytes of
+    /// names at most, see [`Stats::max_backlog`]. Options that remember entries beyond their
+    /// directory panic when the walk starts: sorting, breadth first and prioritized walks,
+    /// `dedup_hardlinks`, `visit_dirs_once`, `cache_links` and `collect_denied`. Splitting the
+    /// walk still reads its open directories to the end.
+    pub fn bounded_memory(mut self, yes: bool) -> Self {
+        self.config.bounded_memory = yes;
         self
     }
 
-    pub fn build(mut self) -> IntoIter {
-        self.config.assert_consistent();
-        let first_item = self.initial_closed();
-
-        IntoIter {
-            config: self.config,
-            stack: vec![WorkItem::Closed(first_item)],
-            open_budget: 128,
-            stats: Stats::default(),
-        }
+    /// Continue reading the root directory after one of its entries.
+    ///
+    /// The offset is that of an entry read directly from the root, see [`DirEntry::offset`]. The
+    /// root itself is still yielded, but only the entries after that one are. Offsets saved by
+    /// an earlier walk stay valid as long as the directory is not modified in between.
+    pub fn resume_at(mut self, offset: i64) -> Self {
+        self.config.root_offset = Some(offset);
+        self
     }
 
-    fn initial_closed(&mut self) -> Closed {
-        let backlog = Backlog {
-            file_path: core::mem::take(&mut self.path),
-            // We do not _know_ this file type yet, recover and check on iteration.
-            file_type: None,
-        };
+    pub fn follow_links(mut self, yes: bool) -> Self {
+        self.config.follow_links = yes;
+        self
+    }
 
-        Closed {
-            depth: 0,
-            children: vec![backlog],
-            as_parent: None,
-        }
+    /// Yield the entries of each directory in the order of the comparator.
+    ///
+    /// Each directory is read completely when it is opened, then its entries are put in order as
+    /// they are yielded. The first entry of a large directory does not wait for all others to be
+    /// sorted. The order only applies to siblings, a directory is still followed by its contents.
+    pub fn sort_by<F>(mut self, cmp: F) -> Self where
+        F: FnMut(&DirEntry, &DirEntry) -> core::cmp::Ordering + Send + Sync + 'static,
+    {
+        self.config.sorter = Some(Arc::new(Mutex::new(cmp)));
+        self
     }
-}
 
-impl Configuration {
-    fn assert_consistent(&self) {
-        assert!(self.min_depth <= self.max_depth);
-        assert!(self.max_open > 0);
-        assert!(!self.follow_links, "Unsupported");
-        assert!(!self.same_file_system , "Unsupported");
+    /// Yield the entries of each directory ordered by a key of their meta data.
+    ///
+    /// Like [`sort_by`](Self::sort_by), with each entry inspected once relative to its open
+    /// directory. The meta data stays cached on the yielded entry, see
+    /// [`DirEntry::metadata_cached`]. Entries that can not be inspected come after all others.
+    pub fn sort_by_key_meta<K, F>(self, mut key: F) -> Self where
+        K: Ord,
+        F: FnMut(&DirEntry, &Metadata) -> K + Send + Sync + 'static,
+    {
+        self.sort_by(move |a, b| match (a.metadata_cached(), b.metadata_cached()) {
+            (Ok(meta_a), Ok(meta_b)) => key(a, meta_a).cmp(&key(b, meta_b)),
+            (Ok(_), Err(_)) => core::cmp::Ordering::Less,
+            (Err(_), Ok(_)) => core::cmp::Ordering::Greater,
+            (Err(_), Err(_)) => core::cmp::Ordering::Equal,
+        })
     }
-}
 
-impl Default for Configuration {
-    fn default() -> Self {
-        Configuration {
-            min_depth: 0,
-            max_depth: usize::MAX,
-            max_open: 10,
-            follow_links: false,
-            contents_first: false,
-            same_file_system: false,
-        }
+    /// Yield the entries of each directory ordered by a key, see [`sort_by`](Self::sort_by).
+    pub fn sort_by_key<K, F>(self, mut key: F) -> Self where
+        K: Ord,
+        F: FnMut(&DirEntry) -> K + Send + Sync + 'static,
+    {
+        self.sort_by(move |a, b| key(a).cmp(&key(b)))
     }
-}
 
-impl IntoIter {
-    pub fn skip_current_dir(&mut self) {
-        todo!()
+    /// Yield the entries of each directory ordered by the bytes of their file names.
+    pub fn sort_by_file_name(self) -> Self {
+        self.sort_order(SortOrder::Bytes)
     }
 
-    pub fn filter_entry<P>(self, predicate: P) -> FilterEntry<Self, P> where
-        P: FnMut(&DirEntry) -> bool,
-    {
-        todo!()
+    /// Yield the siblings in the order of the bytes of their names, on any file system.
+    ///
+    /// Directories list their entries in an order that depends on the file system and its
+    /// history. This sorts each directory like [`sort_by_file_name`](Self::sort_by_file_name),
+    /// instead of any other order, so walks of the same tree are alike on all machines. The order
+    /// is kept when directories are closed early and reopened, or when a saved walk is resumed.
+    /// The parallel walks still yield entries in no particular order.
+    pub fn deterministic(mut self, yes: bool) -> Self {
+        self.config.deterministic = yes;
+        self
     }
 
-    pub fn stats(&self) -> &dyn core::fmt::Debug {
-        &self.stats
+    /// Continue a walk after an entry that an earlier walk yielded last.
+    ///
+    /// The walk is [`deterministic`](Self::deterministic) and yields directories before their
+    /// contents, `contents_first` and the other orders have no effect. Everything up to and
+    /// including the path is skipped, without opening the directories that only contain earlier
+    /// entries. The path is given as the walk yields it, it need not exist anymore.
+    pub fn start_after(mut self, path: impl AsRef<Path>) -> Self {
+        self.config.start_after = Some(path.as_ref().to_owned());
+        self.config.deterministic = true;
+        self.config.contents_first = false;
+        self.config.breadth_first = false;
+        self.config.priority = None;
+        self.config.sibling_order = SiblingOrder::Mixed;
+        self
     }
-}
 
-pub struct FilterEntry<I, P> {
-    unused: core::marker::PhantomData<(I, P)>,
-}
+    /// Yield the directories of each directory before or after its other entries.
+    ///
+    /// With [`SiblingOrder::FilesFirst`] the files of a directory can be processed while the walk
+    /// descends further. Each directory is read completely when it is opened, like with
+    /// [`sort_by`](Self::sort_by), whose order then applies within both groups. Links count as
+    /// files, even when they are followed.
+    pub fn sibling_order(mut self, order: SiblingOrder) -> Self {
+        self.config.sibling_order = order;
+        self
+    }
 
-impl FileType {
-    pub fn is_dir(&self) -> bool {
-        self.inner == Some(FileTypeInner::Directory)
+    /// Yield the entries of each directory in a built-in order of their file names.
+    ///
+    /// The names are compared as raw bytes, without converting them to strings.
+    pub fn sort_order(self, order: SortOrder) -> Self {
+        match order {
+            SortOrder::Bytes => {
+                self.sort_by(|a, b| a.file_name().as_bytes().cmp(b.file_name().as_bytes()))
+            }
+            SortOrder::Natural => {
+                self.sort_by(|a, b| natural_cmp(a.file_name().as_bytes(), b.file_name().as_bytes()))
+            }
+        }
     }
 
-    pub fn is_file(&self) -> bool {
-        self.inner == Some(FileTypeInner::File)
+    /// Inspect the meta data of every entry before it is yielded.
+    ///
+    /// Each entry is inspected with `fstatat` relative to its directory while that is open, which
+    /// resolves only its name instead of its whole path. The result is available from
+    /// [`DirEntry::metadata_cached`], also to the predicate of [`IntoIter::filter_entry`]. An
+    /// entry that can not be inspected is replaced by an error.
+    pub fn with_metadata(mut self, yes: bool) -> Self {
+        self.config.with_metadata = yes;
+        self
     }
 
-    pub fn is_symlink(&self) -> bool {
-        self.inner == Some(FileTypeInner::SymbolicLink)
+    /// Only yield entries of these types.
+    ///
+    /// Directories are still descended into when they are not yielded themselves, and the
+    /// predicate of [`IntoIter::filter_entry`] still decides about that. When the file system
+    /// reports the type of other entries, they are skipped while reading the dirent buffer
+    /// without allocating for them. A followed link has the type of its target. The roots are
+    /// filtered as well.
+    pub fn types(mut self, types: &[FileType]) -> Self {
+        self.config.types = types.iter().fold(TypeSet::default(), |set, ty| set.with(ty.inner));
+        self
     }
 
-    fn set(&mut self, inner: FileTypeInner) {
-        self.inner = Some(inner);
+    /// Only yield regular files, see [`types`](Self::types).
+    pub fn files_only(self) -> Self {
+        self.types(&[FileTypeInner::File.into()])
     }
-}
 
-impl DirEntry {
-    // TODO: enable `openat`?
+    /// Only yield directories, see [`types`](Self::types).
+    pub fn dirs_only(self) -> Self {
+        self.types(&[FileTypeInner::Directory.into()])
+    }
 
-    /// Inspect the path of this entry.
-    pub fn path(&self) -> &Path {
-        self.full_path.get_or_init(|| {
-            self.file_name.make_path()
-        })
+    /// Only yield entries whose file name bytes are accepted by the predicate.
+    ///
+    /// The predicate runs on the raw name in the dirent buffer, before any memory is allocated
+    /// for the entry, so skipping entries this way is very cheap. Rejected directories are not
+    /// descended into. The root is never checked. When called multiple times, an entry must be
+    /// accepted by all predicates.
+    pub fn filter_name<F>(mut self, predicate: F) -> Self where
+        F: Fn(&[u8]) -> bool + Send + Sync + 'static,
+    {
+        self.config.name_filter.predicates.push(Arc::new(predicate));
+        self
     }
 
-    pub fn path_is_symlink(&self) -> bool {
-        self.file_type.is_symlink()
+    /// Skip hidden entries, those whose name starts with a dot, and do not descend into them.
+    ///
+    /// Like [`filter_name`](Self::filter_name) this is checked on the raw name, before memory is
+    /// allocated for the entry. The roots are never skipped.
+    pub fn skip_hidden(mut self, yes: bool) -> Self {
+        self.config.name_filter.skip_hidden = yes;
+        self
     }
 
-    /// Read the full meta data.
-    pub fn metadata(&self) -> io::Result<std::fs::Metadata> {
-        std::fs::metadata(self.path())
+    /// Only yield entries whose file name has one of the extensions.
+    ///
+    /// The extension is the part after the last dot, unless the name starts with it. Like
+    /// [`filter_name`](Self::filter_name) this is checked on the raw name, before memory is
+    /// allocated for the entry, for all entries that are not directories by their reported type.
+    /// Directories are still descended into but only yielded when they match. The roots are never
+    /// checked. When called multiple times, an entry must have an extension of each call.
+    pub fn with_extensions<S: AsRef<OsStr>>(mut self, extensions: &[S]) -> Self {
+        let extensions: Vec<Box<[u8]>> = extensions
+            .iter()
+            .map(|extension| extension.as_ref().as_bytes().into())
+            .collect();
+        let predicate = move |name: &[u8]| has_extension(&extensions, name);
+        self.config.yielded_names.push(Arc::new(predicate));
+        self
     }
 
-    /// Convert the entry into a path
+    /// Only yield entries whose file name matches the regular expression.
     ///
-    /// Potentially more efficient than `as_path().to_owned()`.
-    pub fn into_path(self) -> PathBuf {
-        let file_name = self.file_name;
-        self.full_path.into_inner().unwrap_or_else(|| {
-            file_name.make_path()
-        })
+    /// The expression is matched on the raw name, before memory is allocated for the entry. With
+    /// `prune`, directories that do not match are not descended into, as with
+    /// [`filter_name`](Self::filter_name). Otherwise they are still descended into but only
+    /// yielded when they match, as with [`with_extensions`](Self::with_extensions).
+    #[cfg(feature = "regex")]
+    pub fn name_regex(mut self, regex: regex::bytes::Regex, prune: bool) -> Self {
+        if prune {
+            return self.filter_name(move |name| regex.is_match(name));
+        }
+
+        self.config.yielded_names.push(Arc::new(move |name: &[u8]| regex.is_match(name)));
+        self
     }
 
-    pub fn file_type(&self) -> FileType {
-        self.file_type
+    /// Only yield entries that match a glob pattern.
+    ///
+    /// A pattern containing a `/` is matched against the path relative to the root, others
+    /// against the file name. Directories that do not match are still descended into, so their
+    /// contents can match. The roots are always yielded. When called multiple times, an entry
+    /// must match any of the patterns.
+    #[cfg(feature = "globset")]
+    pub fn include_glob(mut self, pattern: &str) -> Result<Self, globset::Error> {
+        self.config.globs.include(Pattern::new(pattern)?);
+        Ok(self)
     }
 
-    /// Return the filename of this entry.
-    pub fn file_name(&self) -> &OsStr {
-        match &self.file_name {
-            EntryPath::Full(buf) => buf.file_name().unwrap(),
-            EntryPath::Name { name, .. } => name,
+    /// Skip entries that match a glob pattern, excluded directories are not opened at all.
+    ///
+    /// Patterns are matched like those of [`include_glob`](Self::include_glob). A pattern of
+    /// only the file name is checked like [`filter_name`](Self::filter_name), before memory is
+    /// allocated for the entry. The roots are never excluded.
+    #[cfg(feature = "globset")]
+    pub fn exclude_glob(mut self, pattern: &str) -> Result<Self, globset::Error> {
+        let pattern = Pattern::new(pattern)?;
+        if pattern.is_relative() {
+            self.config.globs.exclude(pattern);
+            return Ok(self);
         }
+
+        Ok(self.filter_name(move |name| !pattern.matches_name(Path::new(OsStr::from_bytes(name)))))
     }
 
-    /// The depth at which this entry is in the directory tree.
+    /// Skip entries matched by `.gitignore` and `.ignore` files, and the global excludes of git.
     ///
-    /// When iterating items in depth-first order and following symbolic links then this is not
-    /// necessarily the smallest depth at which it might appear.
-    pub fn depth(&self) -> usize {
-        self.depth
+    /// The ignore files of a directory are read when it is opened, and apply to everything below
+    /// it. A `.ignore` file takes precedence over a `.gitignore` file, and the files of inner
+    /// directories over those of outer ones. Ignored directories are not opened. Only the roots
+    /// and the directories below them are searched for ignore files, not their parents, and the
+    /// roots are never ignored. A walk resumed from a saved state does not know the ignore files
+    /// of directories that were opened before.
+    #[cfg(feature = "ignore")]
+    pub fn ignore_files(mut self, yes: bool) -> Self {
+        self.config.ignore_files = if yes { Some(IgnoreFiles::new()) } else { None };
+        self
     }
-}
 
-impl Open {
-    fn openat_os(&self, path: &OsStr, stats: &mut Stats) -> io::Result<Self> {
-        let bytes = path.as_bytes().to_owned();
-        let cstr = CString::new(bytes).unwrap();
-        self.openat(&cstr, stats)
+    pub fn contents_first(mut self, yes: bool) -> Self {
+        self.config.contents_first = yes;
+        self
     }
 
-    fn openat(&self, path: &CStr, stats: &mut Stats) -> io::Result<Self> {
-        stats.nr_openat += 1;
-        let fd = self.fd.openat(path)?;
-        let filename = OsStr::from_bytes(path.to_bytes()).to_owned();
+    /// Yield all entries of one depth before those of the next, in level order.
+    ///
+    /// Directories are queued when they are yielded and only opened once all directories before
+    /// them in the queue were read, so at most one of them is open at a time. Their contents can
+    /// not be yielded first, which makes this ignore `contents_first`.
+    pub fn breadth_first(mut self, yes: bool) -> Self {
+        self.config.breadth_first = yes;
+        self
+    }
 
-        Ok(Open {
-            fd,
-            buffer: DirentBuf::with_size(1 << 14),
-            depth: self.depth + 1,
-            as_parent: Arc::new(Node {
-                path: EntryPath::Name {
-                    name: filename,
-                    parent: self.as_parent.clone(),
+    /// Open the pending directory of the highest priority first.
+    ///
+    /// Like [`breadth_first`](Self::breadth_first), directories are queued when they are yielded
+    /// and each is read completely before the next one is opened. The next one is the queued
+    /// directory with the highest priority, of those the one queued first. Everything is still
+    /// visited eventually, an interactive search could explore likely matches first. Ignores
+    /// `contents_first` and `breadth_first`.
+    pub fn descend_priority<F>(mut self, priority: F) -> Self where
+        F: FnMut(&DirEntry) -> u32 + Send + 'static,
+    {
+        self.config.priority = Some(Arc::new(Mutex::new(priority)));
+        self
+    }
+
+    /// Never open a directory outside of the root, for walking a tree that can not be trusted.
+    ///
+    /// Directories are opened with `openat2`, resolved beneath the root and without following
+    /// any symbolic link. A tree that is modified during the walk can then not lead it outside,
+    /// for example by replacing a directory with a link or moving it elsewhere. Such directories
+    /// are reported as errors instead. Links are never followed, with `follow_links` each link to
+    /// a directory is an error. The root itself is opened as usual and kept open for the whole
+    /// walk. Requires Linux 5.6 or later.
+    pub fn sandbox(mut self, yes: bool) -> Self {
+        self.config.sandbox = yes;
+        self
+    }
+
+    /// Yield paths relative to the root, instead of paths that start with the root.
+    ///
+    /// The root itself has an empty path. This saves stripping the root from each path, which
+    /// would need another allocation. Directories are still opened and inspected by their full
+    /// path when necessary.
+    pub fn relative_paths(mut self, yes: bool) -> Self {
+        self.config.relative_paths = yes;
+        self
+    }
+
+    pub fn same_file_system(mut self, yes: bool) -> Self {
+        self.config.same_file_system = yes;
+        self
+    }
+
+    /// Yield each file only once, even if there are several hard links to it.
+    ///
+    /// Files are identified by their device and inode, so all yielded files are remembered.
+    /// Directories are not affected. The inode is reported when reading the directory and the
+    /// device is that of the directory, which is inspected once when it is opened. Entries
+    /// without a directory, like the roots, and followed links are inspected on their own. The
+    /// halves of a split walk and the threads of a parallel walk share the files they yielded.
+    pub fn dedup_hardlinks(mut self, yes: bool) -> Self {
+        self.config.dedup_hardlinks = yes;
+        self
+    }
+
+    /// Check each directory against its ancestors, even without following links.
+    ///
+    /// Bind mounts can make a directory appear again below itself. Each opened directory is
+    /// inspected for its device and inode, and one that is also an ancestor is not descended
+    /// into. By default it is reported with an error, see [`Error::loop_ancestor`]. This is
+    /// always done when following links.
+    pub fn detect_loops(mut self, yes: bool) -> Self {
+        self.config.detect_loops = yes;
+        self
+    }
+
+    /// Descend into each directory only once, even if it is reached through several links.
+    ///
+    /// Each opened directory is identified by its device and inode. When the same directory is
+    /// found again, for example through another followed link or bind mount, it is yielded but
+    /// its contents are not, as if it was empty. The halves of a split walk and the threads of a
+    /// parallel walk share the directories they visited. Implies
+    /// [`detect_loops`](Self::detect_loops).
+    pub fn visit_dirs_once(mut self, yes: bool) -> Self {
+        self.config.visit_dirs_once = yes;
+        self
+    }
+
+    /// Remember the target of each followed link, for when its directory is walked again.
+    ///
+    /// The same directory can be reached through several links, for example the packages that
+    /// many others link to. Its links are then resolved only once, the next walks through it
+    /// reuse the meta data of their targets. Links are identified by the device and inode of
+    /// their directory, and their name. Targets that change during the walk are not noticed.
+    /// The halves of a split walk and the threads of a parallel walk share the targets.
+    pub fn cache_links(mut self, yes: bool) -> Self {
+        self.config.cache_links = yes;
+        self
+    }
+
+    /// Skip directories that are their own ancestor, instead of reporting an error.
+    ///
+    /// The directory is yielded but its contents are not, as if it was empty. This also applies
+    /// to loops through followed links, and implies [`detect_loops`](Self::detect_loops).
+    pub fn skip_loops(mut self, yes: bool) -> Self {
+        self.config.skip_loops = yes;
+        self
+    }
+
+    /// Choose how to handle directories that are removed while the tree is walked.
+    ///
+    /// A directory read from its parent may be gone by the time it is opened, which fails with
+    /// `ENOENT`, or `ESTALE` on network file systems. The same applies to directories that are
+    /// reopened to continue reading them. Other errors are handled by
+    /// [`on_error`](Self::on_error), as are those of vanished directories that are yielded. The
+    /// policy also applies to the roots.
+    pub fn vanished(mut self, policy: Vanished) -> Self {
+        self.config.vanished = policy;
+        self
+    }
+
+    /// Choose how to handle errors, instead of yielding all of them.
+    ///
+    /// Errors that are left out do not change the walk, a directory that can not be read is
+    /// skipped either way. Ending the walk forgets all directories that were not yet visited,
+    /// including deferred ones.
+    pub fn on_error(mut self, policy: ErrorPolicy) -> Self {
+        let fixed = |decision: Decision| -> ErrorCallback {
+            Arc::new(Mutex::new(move |_: &Error| decision))
+        };
+
+        self.config.on_error = match policy {
+            ErrorPolicy::Ignore => Some(fixed(Decision::Ignore)),
+            ErrorPolicy::Yield => None,
+            ErrorPolicy::Abort => Some(fixed(Decision::Abort)),
+            ErrorPolicy::Decide(decide) => Some(Arc::new(Mutex::new(decide))),
+        };
+        self
+    }
+
+    /// Leave out the errors of directories that may not be opened, and collect their paths.
+    ///
+    /// Opening a directory without permission fails with `EACCES`. Instead of yielding an error
+    /// for it, its path is remembered and can be retrieved with [`IntoIter::denied`] or
+    /// [`ParallelIter::denied`] once the walk is done. The directory itself is still yielded.
+    pub fn collect_denied(mut self, yes: bool) -> Self {
+        self.config.collect_denied = yes;
+        self
+    }
+
+    /// Call a function with the progress of the walk, each time a directory was read completely.
+    ///
+    /// It is called while the walk advances, before the next entry is yielded. Only the
+    /// sequential walk reports its progress, the parallel one does not.
+    pub fn on_progress<F>(mut self, callback: F) -> Self where
+        F: FnMut(&Progress) + Send + 'static,
+    {
+        self.config.progress = Some(Arc::new(Mutex::new(callback)));
+        self
+    }
+
+    /// Also report the progress after every `n` entries that were yielded, `0` for never.
+    ///
+    /// Takes effect with [`on_progress`](Self::on_progress).
+    pub fn progress_every(mut self, n: usize) -> Self {
+        self.config.progress_every = n;
+        self
+    }
+
+    /// Read the next entries of a directory on a helper thread, while the current ones are yielded.
+    ///
+    /// Each open directory gets a second buffer, that is filled while the walk works through the
+    /// first one. This overlaps the latency of reading a directory, for example on network file
+    /// systems, with the work of the consumer. The thread is shared by all walks of this
+    /// configuration. Sorted directories are read completely when they are opened instead.
+    pub fn read_ahead(mut self, yes: bool) -> Self {
+        self.config.read_ahead = if yes { Some(Arc::default()) } else { None };
+        self
+    }
+
+    /// Retry opening and reading a directory at most this many times when interrupted by a signal.
+    ///
+    /// Calls fail with `EINTR` if a signal arrives while they block, for example on network file
+    /// systems. Walks in a process that receives many signals, from timers or a profiler, would
+    /// then report spurious errors. The default is 16 retries. Closing a directory is never
+    /// retried, its descriptor is released even when interrupted.
+    pub fn eintr_retries(mut self, n: usize) -> Self {
+        self.config.eintr_retries = n;
+        self
+    }
+
+    /// Fail if the root is not a directory.
+    ///
+    /// By default a root that is a file is yielded as the only entry, at depth 0. With this
+    /// option the walk instead yields a single `ENOTDIR` error.
+    pub fn require_dir_root(mut self, yes: bool) -> Self {
+        self.config.require_dir_root = yes;
+        self
+    }
+
+    /// Cross-check every `n`-th file type reported by the file system with `stat`.
+    ///
+    /// This is a debugging aid for file systems, such as some FUSE implementations, that might
+    /// report wrong types. Mismatches are counted in the statistics and the entry gets the type
+    /// from `stat` instead. Zero, the default, disables the check.
+    pub fn verify_file_types(mut self, n: usize) -> Self {
+        self.config.verify_types = n;
+        self
+    }
+
+    /// The number of threads for a parallel walk.
+    ///
+    /// Zero, the default, uses one thread for each available core.
+    pub fn threads(mut self, n: usize) -> Self {
+        self.config.threads = n;
+        self
+    }
+
+    /// Walk the tree with a pool of threads.
+    ///
+    /// Directories are distributed between the threads, so entries are yielded in no particular
+    /// order and a directory does not necessarily precede its contents. The options concerning the
+    /// order, `contents_first` and `sort_by`, have no effect.
+    pub fn into_parallel_iter(self) -> ParallelIter {
+        ParallelIter::new(self)
+    }
+
+    /// Walk the tree as a rayon parallel iterator.
+    ///
+    /// The walk is divided into pieces that rayon distributes between its threads. The options
+    /// apply to each piece but the pieces are not ordered relative to each other, so a directory
+    /// may come after some of its contents even without `contents_first`. The `threads` option has
+    /// no effect, the work runs on the current rayon thread pool.
+    #[cfg(feature = "rayon")]
+    pub fn par_entries(self) -> ParEntries {
+        ParEntries::new(self.build())
+    }
+
+    /// Walk the tree as an async stream, on the blocking pool of the current tokio runtime.
+    ///
+    /// The entries are read ahead of the stream until a bounded number of them waits to be polled,
+    /// so the walk never blocks the runtime and keeps up with a slow consumer. Panics when called
+    /// outside of a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn into_stream(self) -> WalkStream {
+        WalkStream::new(self.build())
+    }
+
+    /// Walk the tree on a thread of its own, that sends each entry into a channel.
+    ///
+    /// The walk overlaps with the work of the receiver, while a bounded channel keeps it from
+    /// running too far ahead. It ends after the last entry, or once the receiver is dropped. The
+    /// thread returns the statistics of the walk.
+    pub fn spawn_into(self, sender: mpsc::SyncSender<Result<DirEntry, Error>>)
+        -> thread::JoinHandle<Stats>
+    {
+        let mut it = self.build();
+        thread::spawn(move || {
+            for entry in it.by_ref() {
+                if sender.send(entry).is_err() {
+                    break;
+                }
+            }
+
+            it.stats().clone()
+        })
+    }
+
+    /// Walk the tree as events that enter and leave each directory, like `fts(3)`.
+    ///
+    /// A directory is entered before its contents and left after them, all other entries are
+    /// files. The events are always in depth-first order, so `contents_first` and `breadth_first`
+    /// have no effect. Directories that are not descended into, for example at the maximum depth,
+    /// are still entered and left. A directory that can not be opened is neither, its error is
+    /// passed on instead.
+    pub fn into_events(mut self) -> Events {
+        self.config.contents_first = false;
+        self.config.breadth_first = false;
+        self.config.priority = None;
+        Events::new(self.build())
+    }
+
+    /// Sum up the size of each directory and its contents, like `du(1)`.
+    ///
+    /// Each directory is yielded after all of its contents, with the lengths and the disk usage of
+    /// itself and every entry below it. The entries are inspected relative to their open parent.
+    /// With `count_links_once`, files with several hard links are only counted where they are
+    /// found first. Symbolic links are counted themselves, unless the walk follows them.
+    pub fn aggregate_sizes(self, count_links_once: bool) -> Sizes {
+        Sizes::new(self.into_events(), count_links_once)
+    }
+
+    /// Walk the tree with a visitor, that keeps a state for each directory.
+    ///
+    /// The visitor sees the same events as [`into_events`](Self::into_events), the state of a
+    /// directory is passed to each of its contents and handed to its parent when it is left.
+    /// Returns the error with which the visitor ended the walk, if any.
+    pub fn visit<V: Visitor>(self, visitor: &mut V) -> Result<(), Error> {
+        visit::visit(self.into_events(), visitor)
+    }
+
+    pub fn build(self) -> IntoIter {
+        self.config.assert_consistent();
+        let mut stats = Stats::default();
+        let (roots, preopen_budget) = match self.preopen() {
+            Some((roots, budget)) => (Some(roots), budget),
+            None => (None, 0),
+        };
+        let first_item = match roots {
+            // The roots are added as they are opened.
+            Some(_) => Closed {
+                depth: 0,
+                children: Backlog::default(),
+                as_parent: None,
+                resume: None,
+            },
+            None => self.initial_closed(&mut stats),
+        };
+        let start_after = self.config.start_after.clone();
+        let yielded_files = self.config.shared_files.clone().unwrap_or_default();
+        let link_targets = self.config.shared_links.clone().unwrap_or_default();
+
+        IntoIter {
+            open_budget: self.config.max_open - preopen_budget,
+            buffers: BufferPool::new(self.config.max_open),
+            config: self.config.finish(),
+            stack: vec![WorkItem::Closed(first_item)],
+            stats,
+            unverified: 0,
+            root_device: None,
+            deferred: vec![],
+            queue: BinaryHeap::new(),
+            nr_queued: 0,
+            queued_last: None,
+            yielded_files,
+            visited_dirs: FileIds::default(),
+            link_targets,
+            denied: Vec::new(),
+            skipped: Vec::new(),
+            start_after,
+            cancelled: Arc::default(),
+            nr_yielded: 0,
+            nr_finished: 0,
+            roots,
+            preopened: Vec::new(),
+            preopen_budget,
+        }
+    }
+
+    /// Continue a walk from its saved state, see [`IntoIter::save_state`].
+    ///
+    /// The state knows its own directories, so the roots of this walk are not visited again. Its
+    /// configuration should be the same as that of the saved walk. Directories are reopened by
+    /// their path to read their remaining entries, relative to the first root of this walk if
+    /// the paths are relative to their root.
+    pub fn resume(self, state: WalkState) -> IntoIter {
+        self.config.assert_consistent();
+        let base = match &self.config.root_base {
+            Some(base) => Some(base.clone()),
+            None => self.roots.first().and_then(|root| self.config.new_base(root)),
+        };
+        let base = base.as_ref();
+        let stack = state.dirs
+            .into_iter()
+            .map(|dir| WorkItem::Closed(Closed::from_state(dir, base)))
+            .collect();
+        let deferred = state.deferred
+            .into_iter()
+            .map(|(depth, entry)| DirEntry::from_state(depth, entry, base))
+            .collect();
+        let queue: BinaryHeap<_> = state.queue
+            .into_iter()
+            .map(|(depth, entry)| DirEntry::from_state(depth, entry, base))
+            .zip(0..)
+            .map(|(dir, seq)| Queued { priority: self.config.priority_of(&dir), seq, dir })
+            .collect();
+        let nr_queued = queue.len() as u64;
+        let start_after = self.config.start_after.clone();
+        let yielded_files = self.config.shared_files.clone().unwrap_or_default();
+        let link_targets = self.config.shared_links.clone().unwrap_or_default();
+
+        IntoIter {
+            open_budget: self.config.max_open,
+            buffers: BufferPool::new(self.config.max_open),
+            config: self.config.finish(),
+            stack,
+            stats: Stats::default(),
+            unverified: 0,
+            root_device: state.root_device,
+            deferred,
+            queue,
+            nr_queued,
+            queued_last: None,
+            yielded_files,
+            visited_dirs: FileIds::default(),
+            link_targets,
+            denied: Vec::new(),
+            skipped: Vec::new(),
+            start_after,
+            cancelled: Arc::default(),
+            nr_yielded: 0,
+            nr_finished: 0,
+            roots: None,
+            preopened: Vec::new(),
+            preopen_budget: 0,
+        }
+    }
+
+    /// Determine the type of the root itself, without following a symbolic link.
+    ///
+    /// Errors are reported when the root is visited, where we have to stat it again.
+    fn stat_root(&self, path: &Path) -> Option<FileTypeInner> {
+        let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+        let stat = fstatat(self.config.dir(), &path, libc::AT_SYMLINK_NOFOLLOW).ok()?;
+        FileTypeInner::from_mode(stat.st_mode)
+    }
+
+    /// Start opening the roots on helper threads, if they are pre-opened.
+    ///
+    /// Also returns the descriptors that the helpers may hold, out of the budget of the walk. The
+    /// walk keeps at least one for itself, with a single one the helpers only inspect the roots.
+    fn preopen(&self) -> Option<(Preopen<OpenedRoot>, usize)> {
+        // Roots relative to another walk are already open.
+        if !self.config.preopen_roots || self.roots.len() < 2 || self.config.root_base.is_some() {
+            return None;
+        }
+
+        let config = self.config.clone();
+        let root_type = self.root_type;
+        let threads = (config.max_open - 1).max(1);
+        // Queued roots are opened much later, each helper would hold on to one meanwhile.
+        let open_dirs = config.max_open > 1 && !config.queues_dirs();
+        let roots = Preopen::start(self.roots.clone(), threads, move |path| {
+            let nr_stat = usize::from(root_type.is_none());
+            let file_type = root_type.or_else(|| {
+                let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+                let stat = fstatat(config.dir(), &path, libc::AT_SYMLINK_NOFOLLOW).ok()?;
+                FileTypeInner::from_mode(stat.st_mode)
+            });
+            let is_dir = matches!(
+                file_type,
+                Some(FileTypeInner::Directory) | Some(FileTypeInner::SymbolicLink)
+            );
+
+            let mut stats = Stats::default();
+            let fd = match open_dirs && is_dir {
+                true => {
+                    let root = config.root_path(path.clone());
+                    stats.syscall(Syscall::Open, 0);
+                    DirFd::open_in(root.base(), &root.make_path(), &mut stats).ok()
+                }
+                false => None,
+            };
+            OpenedRoot { path, file_type, fd, nr_stat, nr_open: stats.nr_open }
+        });
+
+        let budget = if open_dirs { roots.helpers() } else { 0 };
+        Some((roots, budget))
+    }
+
+    /// The roots, as the entries of a directory without a parent.
+    fn initial_closed(&self, stats: &mut Stats) -> Closed {
+        let mut backlog = Backlog::default();
+        // Entries are taken from the back.
+        for path in self.roots.iter().rev() {
+            let root_type = match self.root_type {
+                Some(root_type) => Some(root_type),
+                None => {
+                    stats.syscall(Syscall::Stat, 0);
+                    self.stat_root(path)
+                }
+            };
+
+            // If we do not _know_ this file type yet, recover and check on iteration.
+            backlog.push(path.as_os_str(), root_type, None, None);
+        }
+
+        Closed {
+            depth: 0,
+            children: backlog,
+            as_parent: None,
+            resume: None,
+        }
+    }
+}
+
+impl Configuration {
+    /// If directories are queued when yielded, instead of being descended into right away.
+    fn queues_dirs(&self) -> bool {
+        self.breadth_first || self.priority.is_some()
+    }
+
+    /// The priority of a directory that is queued.
+    fn priority_of(&self, dir: &DirEntry) -> u32 {
+        match &self.priority {
+            Some(priority) => (*priority.lock().unwrap_or_else(|err| err.into_inner()))(dir),
+            None => 0,
+        }
+    }
+
+    fn assert_consistent(&self) {
+        assert!(self.min_depth <= self.max_depth);
+        assert!(self.max_open > 0);
+        if self.bounded_memory {
+            let remembers = self.sorter.is_some() || self.deterministic || self.queues_dirs()
+                || self.dedup_hardlinks || self.visit_dirs_once || self.cache_links
+                || self.collect_denied;
+            assert!(!remembers, "bounded_memory with an option that remembers entries");
+        }
+    }
+
+    /// Derive the settings that depend on several options, for a walk that starts.
+    fn finish(mut self) -> Self {
+        if self.bounded_memory {
+            let size = *self.buffer_size.get_or_insert(DEFAULT_BUFFER_SIZE);
+            self.max_buffer_size = size;
+        }
+        // A wrong type could hide a directory, so they must all be checked.
+        if self.verify_types == 0 {
+            let mut descended = TypeSet::default().with(Some(FileTypeInner::Directory));
+            if self.follow_links {
+                descended = descended.with(Some(FileTypeInner::SymbolicLink));
+            }
+            self.name_filter.skipped = self.types.union(descended).complement();
+            self.name_filter.descended = descended.with(None);
+        } else {
+            self.name_filter.descended = TypeSet::ALL;
+        }
+        self.name_filter.yielded = self.yielded_names.clone();
+        if self.deterministic {
+            let by_bytes = |a: &DirEntry, b: &DirEntry| {
+                a.file_name().as_bytes().cmp(b.file_name().as_bytes())
+            };
+            self.sorter = Some(Arc::new(Mutex::new(by_bytes)));
+        }
+        if self.sibling_order != SiblingOrder::Mixed {
+            let dirs_first = self.sibling_order == SiblingOrder::DirsFirst;
+            let within = self.sorter.take();
+            let by_kind = move |a: &DirEntry, b: &DirEntry| {
+                let kind = is_dir_sibling(a).cmp(&is_dir_sibling(b));
+                let kind = if dirs_first { kind.reverse() } else { kind };
+                kind.then_with(|| match &within {
+                    Some(within) => (*within.lock().unwrap_or_else(|err| err.into_inner()))(a, b),
+                    None => core::cmp::Ordering::Equal,
+                })
+            };
+            self.sorter = Some(Arc::new(Mutex::new(by_kind)));
+        }
+        self
+    }
+
+    /// If entries of this type are yielded.
+    pub(crate) fn yields_type(&self, file_type: FileType) -> bool {
+        self.types.contains(file_type.inner)
+    }
+
+    /// If the entry is yielded, when it is deep enough.
+    pub(crate) fn yields(&self, entry: &DirEntry) -> bool {
+        // Like for other name filters, the roots are not checked.
+        if entry.depth > 0 && !self.yielded_names.is_empty() {
+            let name = entry.file_name().as_bytes();
+            if !self.yielded_names.iter().all(|predicate| predicate(name)) {
+                return false;
+            }
+        }
+        #[cfg(feature = "globset")]
+        if !self.globs.includes(entry) {
+            return false;
+        }
+        self.yields_type(entry.file_type)
+    }
+
+    /// If the entry is skipped along with its contents.
+    #[cfg_attr(not(feature = "globset"), allow(unused_variables))]
+    pub(crate) fn excludes(&self, entry: &DirEntry) -> bool {
+        #[cfg(feature = "globset")]
+        if self.globs.excludes(entry) {
+            return true;
+        }
+        false
+    }
+
+    /// If entries are matched against patterns after they are read.
+    fn matches_patterns(&self) -> bool {
+        if !self.yielded_names.is_empty() {
+            return true;
+        }
+        #[cfg(feature = "globset")]
+        if !self.globs.is_empty() {
+            return true;
+        }
+        #[cfg(feature = "ignore")]
+        if self.ignore_files.is_some() {
+            return true;
+        }
+        false
+    }
+
+    /// The limit at which the walk ends, if one was reached.
+    pub(crate) fn limit_reached(&self, nr_yielded: usize) -> Option<Limit> {
+        if nr_yielded >= self.max_entries {
+            return Some(Limit::Entries(nr_yielded));
+        }
+
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Some(Limit::Deadline),
+            _ => None,
+        }
+    }
+
+    /// What to do with an error that would be yielded.
+    pub(crate) fn decide(&self, err: &Error) -> Decision {
+        match &self.on_error {
+            Some(decide) => (*decide.lock().unwrap())(err),
+            None => Decision::Yield,
+        }
+    }
+
+    /// If directories are compared to their ancestors before descending.
+    pub(crate) fn checks_loops(&self) -> bool {
+        self.follow_links || self.detect_loops || self.skip_loops || self.visit_dirs_once
+    }
+
+    /// The directory that the roots are relative to.
+    fn dir(&self) -> libc::c_int {
+        self.base_dir.as_ref().map_or(libc::AT_FDCWD, |dir| dir.0)
+    }
+
+    /// The shared state of a new root, if its entries are not simply resolved by their path.
+    fn new_base(&self, root: &Path) -> Option<Arc<Base>> {
+        if self.base_dir.is_none() && !self.relative_paths && !self.sandbox {
+            return None;
+        }
+
+        Some(Arc::new(Base {
+            dir: self.base_dir.clone(),
+            root: root.to_owned(),
+            relative: self.relative_paths,
+            sandbox: if self.sandbox { Some(OnceCell::new()) } else { None },
+        }))
+    }
+
+    /// The path of the entry for a root.
+    fn root_path(&self, root: PathBuf) -> EntryPath {
+        if let Some(base) = &self.root_base {
+            return EntryPath::At { path: root, base: base.clone() };
+        }
+
+        match self.new_base(&root) {
+            Some(base) => EntryPath::At {
+                path: if base.relative { PathBuf::new() } else { root },
+                base,
+            },
+            None => EntryPath::Full(root),
+        }
+    }
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Configuration {
+            min_depth: 0,
+            max_depth: usize::MAX,
+            max_open: default_max_open(),
+            max_entries: usize::MAX,
+            deadline: None,
+            follow_links: false,
+            contents_first: false,
+            breadth_first: false,
+            priority: None,
+            same_file_system: false,
+            dedup_hardlinks: false,
+            shared_files: None,
+            detect_loops: false,
+            visit_dirs_once: false,
+            cache_links: false,
+            shared_links: None,
+            skip_loops: false,
+            vanished: Vanished::YieldError,
+            on_error: None,
+            collect_denied: false,
+            progress: None,
+            progress_every: 0,
+            types: TypeSet::ALL,
+            with_metadata: false,
+            eintr_retries: DEFAULT_EINTR_RETRIES,
+            require_dir_root: false,
+            verify_types: 0,
+            name_filter: NameFilter::default(),
+            yielded_names: Vec::new(),
+            #[cfg(feature = "globset")]
+            globs: GlobFilter::default(),
+            #[cfg(feature = "ignore")]
+            ignore_files: None,
+            sorter: None,
+            deterministic: false,
+            sibling_order: SiblingOrder::Mixed,
+            start_after: None,
+            buffer_size: None,
+            max_buffer_size: DEFAULT_MAX_BUFFER_SIZE,
+            max_backlog: DEFAULT_MAX_BACKLOG,
+            bounded_memory: false,
+            read_ahead: None,
+            root_offset: None,
+            base_dir: None,
+            relative_paths: false,
+            sandbox: false,
+            root_base: None,
+            root_dir: None,
+            threads: 0,
+            preopen_roots: false,
+        }
+    }
+}
+
+impl NameFilter {
+    /// If an entry with the name is yielded, checked on all entries that are not descended into.
+    fn yields(&self, name: &[u8]) -> bool {
+        self.yielded.iter().all(|predicate| predicate(name))
+    }
+
+    fn accepts(&self, name: &[u8]) -> bool {
+        if self.skip_hidden && name.first() == Some(&b'.') {
+            return false;
+        }
+        self.predicates.iter().all(|predicate| predicate(name))
+    }
+}
+
+/// If the file name has one of the extensions, see `Path::extension`.
+fn has_extension(extensions: &[Box<[u8]>], name: &[u8]) -> bool {
+    match name.iter().rposition(|&b| b == b'.') {
+        Some(0) | None => false,
+        Some(dot) => extensions.iter().any(|extension| **extension == name[dot + 1..]),
+    }
+}
+
+impl TypeSet {
+    const ALL: Self = TypeSet(!0);
+
+    fn bit(kind: Option<FileTypeInner>) -> u8 {
+        1 << kind.map_or(0, |kind| kind as u8)
+    }
+
+    fn with(self, kind: Option<FileTypeInner>) -> Self {
+        TypeSet(self.0 | Self::bit(kind))
+    }
+
+    fn contains(self, kind: Option<FileTypeInner>) -> bool {
+        self.0 & Self::bit(kind) != 0
+    }
+
+    fn union(self, other: Self) -> Self {
+        TypeSet(self.0 | other.0)
+    }
+
+    /// All other types, except for the unknown type.
+    fn complement(self) -> Self {
+        TypeSet(!self.0 & !Self::bit(None))
+    }
+}
+
+impl IntoIter {
+    /// Do not descend further into the directory of the last yielded entry.
+    ///
+    /// If that entry was a directory then none of its contents are yielded. Otherwise, the
+    /// remaining entries of its parent directory are skipped. The walk continues in the parent.
+    pub fn skip_current_dir(&mut self) {
+        if self.queued_last.take().is_none() {
+            self.pop_item();
+        }
+    }
+
+    /// Never visit the contents of the directory at this path, for the rest of the walk.
+    ///
+    /// Unlike [`skip_current_dir`](Self::skip_current_dir) this works for any directory, for
+    /// example one that a user excluded while the walk runs. This also applies if the walk is
+    /// inside of that directory already, or if its entries were read into memory before. The
+    /// directory itself is still yielded, unless it was before. Paths are compared by their
+    /// components, as the walk yields them.
+    pub fn skip_descendants_of(&mut self, path: impl AsRef<Path>) {
+        self.skipped.push(path.as_ref().to_owned());
+    }
+
+    /// Advance like `next` but without allocating for entries that are not descended into.
+    ///
+    /// Such entries borrow their name directly from the buffer of their directory, so it must be
+    /// dropped before the walk continues. Directories, and entries whose type has to be checked,
+    /// are the same as returned by `next`.
+    pub fn next_borrowed(&mut self) -> Option<Result<EntryRef<'_>, Error>> {
+        if !self.next_is_plain() {
+            let entry = self.next()?;
+            return Some(entry.map(|entry| EntryRef { inner: EntryRefInner::Owned(entry) }));
+        }
+
+        self.flush_queued();
+        let open = match self.stack.last_mut() {
+            Some(WorkItem::Open(open)) => open,
+            _ => unreachable!("Plain entries are read from an open directory"),
+        };
+
+        let Open { buffer, as_parent, depth, nr_entries, .. } = open;
+        let entry = buffer.drain().next().map(Open::okay)?;
+        *nr_entries += 1;
+        self.nr_yielded += 1;
+
+        Some(Ok(EntryRef {
+            inner: EntryRefInner::Borrowed {
+                name: entry.file_name(),
+                file_type: entry.file_type(),
+                depth: *depth,
+                ino: entry.ino(),
+                offset: entry.offset(),
+                parent: as_parent,
+            },
+        }))
+    }
+
+    /// Append up to `max` entries to `chunk`, returning how many were appended.
+    ///
+    /// Returns `Ok(0)` only when the walk is done, or `max` is zero. An error stops the chunk
+    /// early, the entries before it are still appended and the walk continues with the next call.
+    pub fn next_chunk(&mut self, chunk: &mut Vec<DirEntry>, max: usize) -> Result<usize, Error> {
+        chunk.reserve(max);
+        for count in 0..max {
+            match self.next() {
+                Some(Ok(entry)) => chunk.push(entry),
+                Some(Err(err)) => return Err(err),
+                None => return Ok(count),
+            }
+        }
+
+        Ok(max)
+    }
+
+    /// Yield only entries accepted by the predicate.
+    ///
+    /// A rejected directory is not opened, so none of its descendants are yielded either.
+    pub fn filter_entry<P>(self, predicate: P) -> FilterEntry<Self, P> where
+        P: FnMut(&DirEntry) -> bool,
+    {
+        FilterEntry {
+            it: self,
+            predicate,
+        }
+    }
+
+    /// The system calls made by this walk so far.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// A handle to end the walk, for example from another thread.
+    ///
+    /// Once it is cancelled, the iterator closes all its directories and ends with its next
+    /// call, without yielding the entries that were left. The iterators of a
+    /// [`split`](Self::split) share their token.
+    pub fn cancel_token(&self) -> CancelToken {
+        CancelToken {
+            cancelled: self.cancelled.clone(),
+        }
+    }
+
+    /// The directories that could not be opened for lack of permission, in the order they were
+    /// found.
+    ///
+    /// Only collected with [`WalkDir::collect_denied`], otherwise they are yielded as errors. The
+    /// iterators of a [`split`](Self::split) collect them separately.
+    pub fn denied(&self) -> &[PathBuf] {
+        &self.denied
+    }
+
+    /// Stop the walk and return the roots of all subtrees that were not yet visited.
+    ///
+    /// Walking each of the returned paths yields exactly the entries this iterator would still
+    /// have yielded, with the returned paths themselves as the roots. Directories that are
+    /// currently open are read to the end and closed. Directories given to
+    /// [`skip_descendants_of`](Self::skip_descendants_of) are left out, with their contents.
+    ///
+    /// With `contents_first`, the directories that are yielded after their contents come last,
+    /// the innermost first. Only the directory itself remains of each, walk them with
+    /// `max_depth(0)`.
+    pub fn into_remaining(mut self) -> Result<Vec<PathBuf>, Error> {
+        self.flush_queued();
+        self.stop_preopen();
+        let mut remaining = vec![];
+
+        loop {
+            let item = match self.stack.pop() {
+                Some(item) => item,
+                // The contents of queued directories, they were yielded themselves.
+                None => match self.pop_queued() {
+                    Some(dir) if self.skipped_at(&dir).is_some() => continue,
+                    Some(dir) => {
+                        self.descend(&dir, None)?;
+                        continue;
+                    }
+                    None => break,
+                },
+            };
+
+            let mut closed = match item {
+                WorkItem::Open(open) => {
+                    self.open_budget += 1;
+                    let node = open.as_parent.clone();
+                    open.close(&self.config, &mut self.stats, &mut self.buffers, usize::MAX)
+                        .map_err(|err| Error::from_node(&node, Op::Read, err))?
+                }
+                WorkItem::Closed(closed) => closed,
+            };
+
+            self.read_remaining(&mut closed)?;
+
+            // Children of closed directories are visited from the back.
+            let base = closed.as_parent.as_deref().map(Node::path);
+            let children = &closed.children;
+            let paths = (0..children.len()).rev().map(|index| match base {
+                Some(base) => base.join(children.name(index)),
+                None => PathBuf::from(children.name(index)),
+            });
+            remaining.extend(paths);
+        }
+
+        let skipped = &self.skipped;
+        remaining.retain(|path| !skipped.iter().any(|skipped| path.starts_with(skipped)));
+        // Yielded even inside of a skipped directory, they were found before.
+        remaining.extend(self.deferred.iter().rev().map(|dir| dir.path().to_owned()));
+
+        Ok(remaining)
+    }
+
+    /// Save the pending work of the walk, to continue it later with [`WalkDir::resume`].
+    ///
+    /// This walk continues unaffected. The state holds the entries that were read but not yet
+    /// yielded, and the offsets for reading the rest of each directory.
+    pub fn save_state(&mut self) -> Result<WalkState, Error> {
+        self.stop_preopen();
+        let IntoIter { stack, config, stats, .. } = self;
+        let mut dirs = vec![];
+        for item in stack.iter_mut() {
+            let dir = match item {
+                WorkItem::Open(open) => open.save_state(&config.name_filter, stats)
+                    .map_err(|err| Error::from_node(&open.as_parent, Op::Read, err))?,
+                WorkItem::Closed(closed) => closed.save_state(),
+            };
+            dirs.push(dir);
+        }
+
+        let save = |entry: &DirEntry| (entry.depth, entry.save_state(entry.path().as_os_str()));
+        let deferred = self.deferred.iter().map(save).collect();
+        let queue = self.queued_dirs().into_iter().map(save).collect();
+
+        Ok(WalkState {
+            dirs,
+            deferred,
+            queue,
+            root_device: self.root_device,
+        })
+    }
+
+    /// Divide the remaining walk into two independent iterators.
+    ///
+    /// Open directories are read to the end and closed, then the pending entries are distributed
+    /// between both halves. Each half has its own budget of file descriptors and its own
+    /// statistics, the second half starts with empty statistics. Together they yield exactly the
+    /// entries this iterator would still have yielded.
+    pub fn split(mut self) -> Result<(IntoIter, IntoIter), Error> {
+        let other = self.split_off()?;
+        Ok((self, other))
+    }
+}
+
+/// An iterator that prunes the entries rejected by a predicate.
+///
+/// Created by [`IntoIter::filter_entry`].
+pub struct FilterEntry<I, P> {
+    it: I,
+    predicate: P,
+}
+
+/// The state of a walk, passed to the callback of [`WalkDir::on_progress`].
+#[derive(Debug)]
+pub struct Progress<'a> {
+    entries: usize,
+    dirs: usize,
+    open: usize,
+    path: Option<&'a Path>,
+}
+
+/// Ends a walk from another thread.
+///
+/// Created by [`IntoIter::cancel_token`], all clones cancel the same walk.
+#[derive(Clone, Debug)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+/// An entry that may borrow its name from the buffer of its directory.
+///
+/// Returned by [`IntoIter::next_borrowed`].
+pub struct EntryRef<'a> {
+    inner: EntryRefInner<'a>,
+}
+
+enum EntryRefInner<'a> {
+    Borrowed {
+        name: &'a OsStr,
+        file_type: FileType,
+        depth: usize,
+        ino: u64,
+        offset: i64,
+        parent: &'a Arc<Node>,
+    },
+    Owned(DirEntry),
+}
+
+impl Progress<'_> {
+    /// The entries yielded so far.
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    /// The directories that were read completely.
+    pub fn dirs(&self) -> usize {
+        self.dirs
+    }
+
+    /// The directories that are open right now.
+    pub fn open(&self) -> usize {
+        self.open
+    }
+
+    /// The directory that is being read, or was just finished.
+    pub fn path(&self) -> Option<&Path> {
+        self.path
+    }
+}
+
+impl CancelToken {
+    /// End the walk, the iterator notices it before its next entry.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// If the walk was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl FileType {
+    pub fn is_dir(&self) -> bool {
+        self.inner == Some(FileTypeInner::Directory)
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.inner == Some(FileTypeInner::File)
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.inner == Some(FileTypeInner::SymbolicLink)
+    }
+
+    pub fn is_block_device(&self) -> bool {
+        self.inner == Some(FileTypeInner::BlockDevice)
+    }
+
+    pub fn is_char_device(&self) -> bool {
+        self.inner == Some(FileTypeInner::CharDevice)
+    }
+
+    pub fn is_fifo(&self) -> bool {
+        self.inner == Some(FileTypeInner::NamedPipe)
+    }
+
+    pub fn is_socket(&self) -> bool {
+        self.inner == Some(FileTypeInner::UnixSocket)
+    }
+
+    /// If the file system did not report the type, or reported one that is not known.
+    pub fn is_unknown(&self) -> bool {
+        self.inner.is_none()
+    }
+
+    /// The kind of file, if it is known.
+    pub fn kind(&self) -> Option<FileTypeInner> {
+        self.inner
+    }
+
+    pub(crate) fn from_mode(mode: libc::mode_t) -> Self {
+        FileType {
+            inner: FileTypeInner::from_mode(mode),
+        }
+    }
+
+    pub(crate) fn from_kind(inner: Option<FileTypeInner>) -> Self {
+        FileType { inner }
+    }
+}
+
+impl From<FileTypeInner> for FileType {
+    fn from(kind: FileTypeInner) -> Self {
+        FileType { inner: Some(kind) }
+    }
+}
+
+impl From<std::fs::FileType> for FileType {
+    fn from(ty: std::fs::FileType) -> Self {
+        FileType { inner: FileTypeInner::from_std(ty) }
+    }
+}
+
+impl Stats {
+    /// Directories that were closed.
+    pub fn nr_close(&self) -> usize {
+        self.nr_close
+    }
+
+    /// Calls to `getdents64`, each filling the buffer of one directory.
+    pub fn nr_getdent(&self) -> usize {
+        self.nr_getdent
+    }
+
+    /// Directories opened by their full path.
+    pub fn nr_open(&self) -> usize {
+        self.nr_open
+    }
+
+    /// Directories opened relative to their open parent.
+    pub fn nr_openat(&self) -> usize {
+        self.nr_openat
+    }
+
+    /// Calls to `stat` and its variants.
+    pub fn nr_stat(&self) -> usize {
+        self.nr_stat
+    }
+
+    /// Reported file types that were checked against `stat`.
+    pub fn nr_verify(&self) -> usize {
+        self.nr_verify
+    }
+
+    /// Reported file types that differed from the `stat` result.
+    pub fn nr_type_mismatch(&self) -> usize {
+        self.nr_type_mismatch
+    }
+
+    /// Dirent buffers that were enlarged for a directory with many entries.
+    pub fn nr_buffer_grow(&self) -> usize {
+        self.nr_buffer_grow
+    }
+
+    /// Calls to `lseek`, to continue reading a directory that was reopened.
+    pub fn nr_seek(&self) -> usize {
+        self.nr_seek
+    }
+
+    /// The most bytes of names held in memory for a directory that was closed early.
+    ///
+    /// See [`WalkDir::max_backlog`]. Directories that are sorted or split are read to the end.
+    pub fn max_backlog(&self) -> usize {
+        self.max_backlog
+    }
+
+    /// Heap allocations made by the walker itself.
+    #[cfg(feature = "alloc-stats")]
+    pub fn nr_alloc(&self) -> usize {
+        let AllocStats { buffers, names, nodes, paths } = self.allocs;
+        buffers + names + nodes + paths
+    }
+
+    /// Histograms of the system calls, see [`Histograms`].
+    #[cfg(feature = "histogram-stats")]
+    pub fn histograms(&self) -> &Histograms {
+        &self.histograms
+    }
+
+    /// The counts since an earlier snapshot of the same walk.
+    pub fn since(&self, earlier: &Stats) -> Stats {
+        Stats {
+            nr_close: self.nr_close.saturating_sub(earlier.nr_close),
+            nr_getdent: self.nr_getdent.saturating_sub(earlier.nr_getdent),
+            nr_open: self.nr_open.saturating_sub(earlier.nr_open),
+            nr_openat: self.nr_openat.saturating_sub(earlier.nr_openat),
+            nr_stat: self.nr_stat.saturating_sub(earlier.nr_stat),
+            nr_verify: self.nr_verify.saturating_sub(earlier.nr_verify),
+            nr_type_mismatch: self.nr_type_mismatch.saturating_sub(earlier.nr_type_mismatch),
+            nr_buffer_grow: self.nr_buffer_grow.saturating_sub(earlier.nr_buffer_grow),
+            nr_seek: self.nr_seek.saturating_sub(earlier.nr_seek),
+            // Not a count, the largest so far.
+            max_backlog: self.max_backlog,
+            #[cfg(feature = "alloc-stats")]
+            allocs: AllocStats {
+                buffers: self.allocs.buffers.saturating_sub(earlier.allocs.buffers),
+                names: self.allocs.names.saturating_sub(earlier.allocs.names),
+                nodes: self.allocs.nodes.saturating_sub(earlier.allocs.nodes),
+                paths: self.allocs.paths.saturating_sub(earlier.allocs.paths),
+            },
+            #[cfg(feature = "histogram-stats")]
+            histograms: self.histograms.since(&earlier.histograms),
+        }
+    }
+}
+
+#[cfg(feature = "histogram-stats")]
+impl Histograms {
+    /// Directories by the number of entries read from them, including `.` and `..`.
+    ///
+    /// Directories that were skipped count only the entries read up to then.
+    pub fn entries_per_dir(&self) -> &[usize] {
+        &self.entries_per_dir
+    }
+
+    /// Calls to `getdents64` by the number of bytes they returned.
+    pub fn getdent_bytes(&self) -> &[usize] {
+        &self.getdent_bytes
+    }
+
+    /// The system calls concerning files at each depth.
+    ///
+    /// Reading and closing a directory count at the depth of the directory, opening and stat at
+    /// the depth of the entry. These statistics have no histograms of their own.
+    pub fn by_depth(&self) -> &[Stats] {
+        &self.by_depth
+    }
+
+    /// Directories that were closed before they were read to the end, for lack of descriptors.
+    ///
+    /// Their subdirectories are later opened by their full path.
+    pub fn nr_spill(&self) -> usize {
+        self.nr_spill
+    }
+}
+
+impl WorkItem {
+    /// The depth of the entries of this work item.
+    fn depth(&self) -> usize {
+        match self {
+            WorkItem::Open(open) => open.depth,
+            WorkItem::Closed(closed) => closed.depth,
+        }
+    }
+
+    /// The directory this work item belongs to, if any.
+    fn node(&self) -> Option<&Arc<Node>> {
+        match self {
+            WorkItem::Open(open) => Some(&open.as_parent),
+            WorkItem::Closed(closed) => closed.as_parent.as_ref(),
+        }
+    }
+}
+
+impl DirEntry {
+    /// Inspect the path of this entry.
+    pub fn path(&self) -> &Path {
+        self.full_path.get_or_init(|| {
+            self.file_name.make_path()
+        })
+    }
+
+    /// Whether the path of this entry is a symbolic link.
+    ///
+    /// This is also true for links that were followed, whose file type is that of their target.
+    pub fn path_is_symlink(&self) -> bool {
+        self.file_type.is_symlink() || self.follow_link
+    }
+
+    /// Read the full meta data.
+    ///
+    /// Symbolic links are only followed if the walk follows them. The entry is found relative to
+    /// its directory like [`DirEntry::stat`], but opened with `O_PATH` to inspect it. The standard
+    /// type can not be created from a `fstatat` alone.
+    pub fn metadata(&self) -> io::Result<std::fs::Metadata> {
+        let mut flags = libc::O_PATH | libc::O_CLOEXEC;
+        if !self.follow_link {
+            flags |= libc::O_NOFOLLOW;
+        }
+
+        let (dir, name) = self.resolve();
+        let dirfd = dir.as_ref().map_or(libc::AT_FDCWD, |dir| dir.0);
+        let result = unsafe { libc::openat(dirfd, name.as_ptr(), flags) };
+
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: a new descriptor that nothing else owns.
+        unsafe { File::from_raw_fd(result) }.metadata()
+    }
+
+    /// Read the full meta data, relative to the directory of the entry.
+    ///
+    /// Symbolic links are only followed if the walk follows them. While the walk keeps the
+    /// directory of this entry open this is a single `fstatat` relative to it, otherwise the full
+    /// path is resolved again.
+    pub fn stat(&self) -> io::Result<Metadata> {
+        let flags = if self.follow_link { 0 } else { libc::AT_SYMLINK_NOFOLLOW };
+        let (dir, name) = self.resolve();
+        let dirfd = dir.as_ref().map_or(libc::AT_FDCWD, |dir| dir.0);
+        fstatat(dirfd, &name, flags).map(Metadata::new)
+    }
+
+    /// The meta data like [`DirEntry::stat`], inspected only once.
+    ///
+    /// Later calls return the same meta data, even if the file changed in between. The walk
+    /// leaves the meta data here if it already had to inspect the entry, to determine its type.
+    pub fn metadata_cached(&self) -> io::Result<&Metadata> {
+        self.metadata
+            .get_or_try_init(|| self.stat().map(Box::new))
+            .map(|metadata| &**metadata)
+    }
+
+    /// The size of the file in bytes, from the cached meta data.
+    ///
+    /// Only inspects the file if the meta data is not cached yet, see
+    /// [`DirEntry::metadata_cached`].
+    // Named like the standard method, a file is not a collection.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> io::Result<u64> {
+        self.metadata_cached().map(Metadata::len)
+    }
+
+    /// The time of the last modification, from the cached meta data like [`DirEntry::len`].
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        self.metadata_cached()?.modified()
+    }
+
+    /// Read the meta data of the entry itself, never following a symbolic link.
+    ///
+    /// Like [`DirEntry::stat`] this is relative to the directory while it is open.
+    pub fn symlink_metadata(&self) -> io::Result<Metadata> {
+        let (dir, name) = self.resolve();
+        let dirfd = dir.as_ref().map_or(libc::AT_FDCWD, |dir| dir.0);
+        fstatat(dirfd, &name, libc::AT_SYMLINK_NOFOLLOW).map(Metadata::new)
+    }
+
+    /// Read the target of a symbolic link.
+    ///
+    /// Uses `readlinkat` relative to the directory while it is open. Fails if the entry is not a
+    /// symbolic link, for followed links this is still the target of the link itself.
+    pub fn read_link(&self) -> io::Result<PathBuf> {
+        let (dir, name) = self.resolve();
+        let dirfd = dir.as_ref().map_or(libc::AT_FDCWD, |dir| dir.0);
+
+        // Targets are usually short, retry with more space when it fills the buffer.
+        let mut buf = Vec::<u8>::with_capacity(256);
+        loop {
+            let result = unsafe {
+                libc::readlinkat(
+                    dirfd,
+                    name.as_ptr(),
+                    buf.as_mut_ptr() as *mut libc::c_char,
+                    buf.capacity(),
+                )
+            };
+
+            let len = match usize::try_from(result) {
+                Ok(len) => len,
+                Err(_) => return Err(io::Error::last_os_error()),
+            };
+
+            if len < buf.capacity() {
+                // SAFETY: the call initialized this many bytes.
+                unsafe { buf.set_len(len) };
+                return Ok(PathBuf::from(OsString::from_vec(buf)));
+            }
+
+            buf.reserve(buf.capacity() * 2);
+        }
+    }
+
+    /// Open the entry for reading.
+    ///
+    /// See [`DirEntry::open_with`].
+    pub fn open(&self) -> io::Result<File> {
+        self.open_with(OpenOptions::new().read(true))
+    }
+
+    /// Open the entry with the given options.
+    ///
+    /// While the walk keeps the directory of this entry open the file is opened relative to it,
+    /// otherwise by its full path. Symbolic links are followed.
+    pub fn open_with(&self, options: &OpenOptions) -> io::Result<File> {
+        let flags = options.flags()?;
+        let mode = libc::c_uint::from(options.file_mode());
+
+        let (dir, name) = self.resolve();
+        let dirfd = dir.as_ref().map_or(libc::AT_FDCWD, |dir| dir.0);
+        let result = unsafe { libc::openat(dirfd, name.as_ptr(), flags, mode) };
+
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: a new descriptor that nothing else owns.
+        Ok(unsafe { File::from_raw_fd(result) })
+    }
+
+    /// Remove the entry from its directory, a directory has to be empty.
+    ///
+    /// Uses `unlinkat` relative to the directory of the entry. With `contents_first` each
+    /// directory is yielded after its contents, so a walk can remove a whole tree. If the walk has
+    /// closed the directory of the entry already, it is opened again without following a link in
+    /// its place, beneath the root of a sandboxed walk. The roots are removed by their path. A
+    /// followed link is removed itself, not its target.
+    pub fn remove(&self) -> io::Result<()> {
+        let (dir, name) = match &self.file_name {
+            EntryPath::Name { name, parent } => {
+                let dir = match parent.fd.upgrade() {
+                    Some(dir) => dir,
+                    None => Arc::new(parent.reopen()?),
+                };
+                (Some(dir), CString::new(name.as_os_str().as_bytes()).unwrap())
+            }
+            _ => self.resolve(),
+        };
+        let dirfd = dir.as_ref().map_or(libc::AT_FDCWD, |dir| dir.0);
+
+        let unlink = |flags| match unsafe { libc::unlinkat(dirfd, name.as_ptr(), flags) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        };
+
+        if self.follow_link {
+            return unlink(0);
+        }
+
+        match (self.file_type.is_dir(), self.file_type.is_unknown()) {
+            (true, _) => unlink(libc::AT_REMOVEDIR),
+            // Without a reported type, a directory can only be told from its error.
+            (false, true) => match unlink(0) {
+                Err(err) if err.raw_os_error() == Some(libc::EISDIR) => {
+                    unlink(libc::AT_REMOVEDIR)
+                }
+                result => result,
+            },
+            (false, false) => unlink(0),
+        }
+    }
+
+    /// Convert the entry into a path
+    ///
+    /// Potentially more efficient than `as_path().to_owned()`.
+    pub fn into_path(self) -> PathBuf {
+        let file_name = self.file_name;
+        self.full_path.into_inner().unwrap_or_else(|| {
+            file_name.make_path()
+        })
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    /// The type of this entry, with a stat if it is not known yet.
+    ///
+    /// The walk already inspects entries whose type the file system does not report, so this is
+    /// only for entries of a kind that is not known. The type found is kept for later calls.
+    pub fn file_type_resolved(&mut self) -> io::Result<FileType> {
+        if self.file_type.is_unknown() {
+            self.file_type = self.metadata_cached()?.file_type();
+        }
+        Ok(self.file_type)
+    }
+
+    /// Return the filename of this entry.
+    pub fn file_name(&self) -> &OsStr {
+        match &self.file_name {
+            EntryPath::Full(buf) => buf.file_name().unwrap(),
+            // The root has an empty path when paths are relative to it.
+            EntryPath::At { path, base } => path
+                .file_name()
+                .or_else(|| base.root.file_name())
+                .unwrap(),
+            EntryPath::Name { name, .. } => name.as_os_str(),
+        }
+    }
+
+    /// The depth at which this entry is in the directory tree.
+    ///
+    /// When iterating items in depth-first order and following symbolic links then this is not
+    /// necessarily the smallest depth at which it might appear.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The inode number of this entry, as listed in its directory.
+    ///
+    /// This is the inode of the link itself for symbolic links that were followed. Returns `None`
+    /// for the root of the walk, which is not read from a directory.
+    pub fn ino(&self) -> Option<u64> {
+        self.ino
+    }
+
+    /// The position after this entry in the directory it was read from.
+    ///
+    /// This is the offset the kernel reports for continuing to read the directory with the next
+    /// entry, pass it to [`WalkDir::resume_at`] to do so in a later walk. It is only meaningful
+    /// for this directory and only while the directory is not modified, depending on the file
+    /// system. Returns `None` for the root of the walk.
+    pub fn offset(&self) -> Option<i64> {
+        self.offset
+    }
+
+    /// Start a new walk with this entry as its root.
+    ///
+    /// The walk has the default configuration, which can be changed as usual before iterating.
+    /// The file type of this entry is reused so that the root need not be inspected again. Entries
+    /// of a walk from [`WalkDir::new_at`] or with `relative_paths` start walks whose paths are
+    /// relative to the same directory.
+    ///
+    /// While the walk keeps the directory of this entry open, the new walk holds on to it and
+    /// opens its root relative to it instead of resolving the path again.
+    pub fn walk(&self) -> WalkDir {
+        let mut walk = WalkDir::from_path_buf(self.path().to_owned());
+        walk.root_type = self.file_type.inner;
+        walk.config.root_base = self.file_name.base().cloned();
+        walk.config.root_dir = self.in_parent();
+        walk
+    }
+
+    /// A handle to the directory containing this entry.
+    ///
+    /// Returns `None` for the root of the walk.
+    pub fn parent(&self) -> Option<Parent> {
+        match &self.file_name {
+            EntryPath::Full(_) | EntryPath::At { .. } => None,
+            EntryPath::Name { parent, .. } => Some(Parent { node: parent.clone() }),
+        }
+    }
+}
+
+impl EntryRef<'_> {
+    pub fn file_name(&self) -> &OsStr {
+        match &self.inner {
+            EntryRefInner::Borrowed { name, .. } => name,
+            EntryRefInner::Owned(entry) => entry.file_name(),
+        }
+    }
+
+    pub fn file_type(&self) -> FileType {
+        match &self.inner {
+            EntryRefInner::Borrowed { file_type, .. } => *file_type,
+            EntryRefInner::Owned(entry) => entry.file_type(),
+        }
+    }
+
+    /// The depth at which this entry is in the directory tree, see [`DirEntry::depth`].
+    pub fn depth(&self) -> usize {
+        match &self.inner {
+            EntryRefInner::Borrowed { depth, .. } => *depth,
+            EntryRefInner::Owned(entry) => entry.depth(),
+        }
+    }
+
+    /// The inode number of this entry, see [`DirEntry::ino`].
+    pub fn ino(&self) -> Option<u64> {
+        match &self.inner {
+            EntryRefInner::Borrowed { ino, .. } => Some(*ino),
+            EntryRefInner::Owned(entry) => entry.ino(),
+        }
+    }
+
+    /// The offset after this entry in its directory, see [`DirEntry::offset`].
+    pub fn offset(&self) -> Option<i64> {
+        match &self.inner {
+            EntryRefInner::Borrowed { offset, .. } => Some(*offset),
+            EntryRefInner::Owned(entry) => entry.offset(),
+        }
+    }
+
+    /// Allocate the path of the entry.
+    pub fn to_path_buf(&self) -> PathBuf {
+        match &self.inner {
+            EntryRefInner::Borrowed { name, parent, .. } => parent.path().join(name),
+            EntryRefInner::Owned(entry) => entry.path().to_owned(),
+        }
+    }
+
+    /// Convert into an entry that no longer borrows from the walk.
+    pub fn into_owned(self) -> DirEntry {
+        match self.inner {
+            EntryRefInner::Borrowed { name, file_type, depth, ino, offset, parent } => DirEntry {
+                file_name: EntryPath::Name {
+                    name: FileName::new(name),
+                    parent: parent.clone(),
+                },
+                file_type,
+                depth,
+                full_path: OnceCell::new(),
+                metadata: OnceCell::new(),
+                follow_link: false,
+                ino: Some(ino),
+                offset: Some(offset),
+            },
+            EntryRefInner::Owned(entry) => entry,
+        }
+    }
+}
+
+impl Parent {
+    /// Allocate the path of the directory.
+    pub fn path(&self) -> PathBuf {
+        self.node.make_path()
+    }
+}
+
+impl PartialEq for Parent {
+    fn eq(&self, other: &Parent) -> bool {
+        Arc::ptr_eq(&self.node, &other.node)
+    }
+}
+
+impl Eq for Parent {}
+
+impl core::hash::Hash for Parent {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.node).hash(state)
+    }
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Queued) -> bool {
+        self.cmp(other) == core::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Queued) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The greatest is opened first, ties are opened in the order they were queued.
+impl Ord for Queued {
+    fn cmp(&self, other: &Queued) -> core::cmp::Ordering {
+        let key = |queued: &Queued| (queued.priority, core::cmp::Reverse(queued.seq));
+        key(self).cmp(&key(other))
+    }
+}
+
+/// Entries are compared by their paths.
+///
+/// Links to the same file are different entries, compare [`DirEntry::ino`] and the device of the
+/// metadata for that.
+impl PartialEq for DirEntry {
+    fn eq(&self, other: &DirEntry) -> bool {
+        self.path() == other.path()
+    }
+}
+
+impl Eq for DirEntry {}
+
+impl core::hash::Hash for DirEntry {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        self.path().hash(state)
+    }
+}
+
+impl PartialOrd for DirEntry {
+    fn partial_cmp(&self, other: &DirEntry) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Sorted by the components of their paths, the contents of a directory right after it.
+impl Ord for DirEntry {
+    fn cmp(&self, other: &DirEntry) -> core::cmp::Ordering {
+        self.path().cmp(other.path())
+    }
+}
+
+impl Open {
+    fn openat_os(&self, path: &OsStr, buffer: DirentBuf, config: &Configuration, stats: &mut Stats)
+        -> io::Result<Self>
+    {
+        let bytes = path.as_bytes().to_owned();
+        let cstr = CString::new(bytes).unwrap();
+        stats.alloc(Alloc::Path, 1);
+        self.openat(&cstr, buffer, config, stats)
+    }
+
+    /// Open a directory by its full path, when its parent is no longer open.
+    fn open_path(entry: &DirEntry, buffer: DirentBuf, config: &Configuration, stats: &mut Stats)
+        -> io::Result<Self>
+    {
+        let path = entry.file_name.make_path();
+        stats.alloc(Alloc::Path, 1);
+        stats.syscall(Syscall::Open, entry.depth);
+        let base = entry.file_name.base();
+        let fd = retry(config.eintr_retries, || DirFd::open_in(base, &path, stats))?;
+        Ok(Self::from_path(entry, path, fd, buffer, stats))
+    }
+
+    /// A root that was already opened, see [`WalkDir::preopen_roots`].
+    fn with_fd(entry: &DirEntry, fd: DirFd, buffer: DirentBuf, stats: &mut Stats) -> Self {
+        let path = entry.file_name.make_path();
+        stats.alloc(Alloc::Path, 1);
+        Self::from_path(entry, path, fd, buffer, stats)
+    }
+
+    fn from_path(entry: &DirEntry, path: PathBuf, fd: DirFd, buffer: DirentBuf, stats: &mut Stats)
+        -> Self
+    {
+        let base = entry.file_name.base();
+        let fd = Arc::new(fd);
+        stats.alloc(Alloc::Node, 1);
+
+        Open {
+            buffer,
+            depth: entry.depth + 1,
+            as_parent: Arc::new(Node {
+                depth: entry.depth + 1,
+                path: EntryPath::full(path, base),
+                full_path: OnceCell::new(),
+                id: None,
+                fd: Arc::downgrade(&fd),
+                #[cfg(feature = "ignore")]
+                ignores: None,
+            }),
+            fd,
+            sorted: None,
+            nr_entries: 0,
+            nr_fills: 0,
+            ahead: None,
+            spare: None,
+        }
+    }
+
+    fn openat(&self, path: &CStr, buffer: DirentBuf, config: &Configuration, stats: &mut Stats)
+        -> io::Result<Self>
+    {
+        stats.syscall(Syscall::Openat, self.depth);
+        let fd = retry(config.eintr_retries, || match config.sandbox {
+            true => self.fd.open_beneath(path),
+            false => self.fd.openat(path),
+        })?;
+        let fd = Arc::new(fd);
+        let filename = FileName::new(OsStr::from_bytes(path.to_bytes()));
+        stats.alloc_name(&filename);
+        stats.alloc(Alloc::Node, 1);
+
+        Ok(Open {
+            buffer,
+            depth: self.depth + 1,
+            as_parent: Arc::new(Node {
+                path: EntryPath::Name {
+                    name: filename,
+                    parent: self.as_parent.clone(),
                 },
+                full_path: OnceCell::new(),
                 depth: self.depth + 1,
+                id: None,
+                fd: Arc::downgrade(&fd),
+                #[cfg(feature = "ignore")]
+                ignores: None,
             }),
+            fd,
+            sorted: None,
+            nr_entries: 0,
+            nr_fills: 0,
+            ahead: None,
+            spare: None,
+        })
+    }
+
+    /// The buffer size for a directory, based on its `st_size` if we already know it.
+    ///
+    /// Most file systems report a size that grows with the number of entries, we only use it as
+    /// a rough estimate. Unknown directories get a generic buffer.
+    fn buffer_size(size_hint: Option<u64>) -> usize {
+        match size_hint {
+            None => DEFAULT_BUFFER_SIZE,
+            // The records returned by the kernel are larger than most on-disk formats.
+            Some(size) => usize::try_from(size.saturating_mul(2))
+                .ok()
+                .and_then(usize::checked_next_power_of_two)
+                .unwrap_or(MAX_BUFFER_SIZE)
+                .clamp(MIN_BUFFER_SIZE, MAX_BUFFER_SIZE),
+        }
+    }
+
+
+    /// Get the next item from this directory.
+    fn pop(&mut self) -> Option<Entry<'_>> {
+        let entry = self.buffer.drain().next().map(Self::okay);
+        self.nr_entries += usize::from(entry.is_some());
+        entry
+    }
+
+    /// If the next entry is yielded as it is, see `IntoIter::next_is_plain`.
+    fn next_is_plain(&mut self, filter: &NameFilter, follow_links: bool) -> bool {
+        loop {
+            let file_type = match self.buffer.iter().next() {
+                None => return false,
+                Some(entry) => match Self::sub_entry(Self::okay(entry), filter) {
+                    Some(entry) => entry.kind(),
+                    None => {
+                        self.pop();
+                        continue;
+                    }
+                },
+            };
+
+            return match file_type {
+                None | Some(FileTypeInner::Directory) => false,
+                Some(FileTypeInner::SymbolicLink) => !follow_links,
+                Some(_) => true,
+            };
+        }
+    }
+
+    fn ready_entry(&mut self, filter: &NameFilter, stats: &mut Stats) -> Option<DirEntry> {
+        if let Some(sorted) = &mut self.sorted {
+            return sorted.pop();
+        }
+
+        let depth = self.depth;
+        let parent = self.as_parent.clone();
+        let entry = loop {
+            let entry = self.pop()?;
+            if let Some(entry) = Self::sub_entry(entry, filter) {
+                break entry;
+            }
+        };
+
+        let name = FileName::new(entry.file_name());
+        stats.alloc_name(&name);
+
+        Some(DirEntry {
+            file_name: EntryPath::Name {
+                name,
+                parent,
+            },
+            depth,
+            file_type: entry.file_type(),
+            full_path: OnceCell::new(),
+            metadata: OnceCell::new(),
+            follow_link: false,
+            ino: Some(entry.ino()),
+            offset: Some(entry.offset()),
+        })
+    }
+
+    /// Read all entries of the directory and order them.
+    fn sort(&mut self, config: &Configuration, sorter: &Comparator, stats: &mut Stats)
+        -> io::Result<()>
+    {
+        let mut entries = vec![];
+        loop {
+            while let Some(entry) = self.ready_entry(&config.name_filter, stats) {
+                entries.push(entry);
+            }
+
+            match self.fill_buffer(config, stats)? {
+                More::Blocked => unreachable!("Just drained buffer is blocked"),
+                More::More => {},
+                More::Done => break,
+            }
+        }
+
+        self.sorted = Some(Sorted::new(entries, sorter));
+        Ok(())
+    }
+
+    /// Read more entries, enlarging the buffer up to `max_size` if this takes many calls.
+    fn fill_buffer(&mut self, config: &Configuration, stats: &mut Stats) -> io::Result<More> {
+        if let Some(ahead) = self.ahead.take() {
+            return self.take_ahead(ahead, stats);
+        }
+
+        let max_size = config.max_buffer_size;
+        let size = self.buffer.capacity();
+        if self.nr_fills >= GROW_AFTER && size < max_size && self.buffer.is_empty() {
+            let size = size.saturating_mul(2).min(max_size);
+            stats.alloc(Alloc::Buffer, 1);
+            stats.nr_buffer_grow += 1;
+            self.buffer = DirentBuf::with_size(size);
+            self.nr_fills = 0;
+        }
+
+        stats.syscall(Syscall::Getdent, self.depth - 1);
+        let before = self.buffer.len();
+        let fd = self.fd.0;
+        let more = retry(config.eintr_retries, || self.buffer.fill_buf(fd))?;
+        stats.getdent_bytes(self.buffer.len() - before);
+        if let More::More = more {
+            self.nr_fills += 1;
+        }
+        Ok(more)
+    }
+
+    /// Start filling the spare buffer on the helper thread, if the walk reads ahead.
+    fn read_ahead(&mut self, config: &Configuration, stats: &mut Stats) {
+        let worker = match &config.read_ahead {
+            Some(worker) if self.ahead.is_none() && self.sorted.is_none() => worker,
+            _ => return,
+        };
+
+        let max_size = config.max_buffer_size;
+        let mut size = self.buffer.capacity();
+        if self.nr_fills >= GROW_AFTER && size < max_size {
+            size = size.saturating_mul(2).min(max_size);
+            stats.nr_buffer_grow += 1;
+            self.nr_fills = 0;
+        }
+
+        let mut buffer = match self.spare.take() {
+            Some(spare) if spare.capacity() >= size => spare,
+            _ => {
+                stats.alloc(Alloc::Buffer, 1);
+                DirentBuf::with_size(size)
+            }
+        };
+
+        let fd = Arc::clone(&self.fd);
+        let retries = config.eintr_retries;
+        self.ahead = Some(worker.run(move || {
+            let more = retry(retries, || buffer.fill_buf(fd.0));
+            // The descriptor must only be closed by the walk.
+            drop(fd);
+            (buffer, more)
+        }));
+    }
+
+    /// Continue with the entries that were read ahead, the current buffer is drained.
+    fn take_ahead(&mut self, ahead: Pending<Filled>, stats: &mut Stats) -> io::Result<More> {
+        let (buffer, more) = ahead.wait();
+        stats.syscall(Syscall::Getdent, self.depth - 1);
+        stats.getdent_bytes(buffer.len());
+        self.spare = Some(mem::replace(&mut self.buffer, buffer));
+        let more = more?;
+        if let More::More = more {
+            self.nr_fills += 1;
+        }
+        Ok(more)
+    }
+
+    /// Forcibly close this directory entry.
+    /// Returns the remaining backlog items, there are none if it was already finished.
+    ///
+    /// Stops reading once the names of the backlog reach the limit, the directory is then resumed
+    /// at the current offset after the backlog was visited.
+    fn close(
+        mut self,
+        config: &Configuration,
+        stats: &mut Stats,
+        buffers: &mut BufferPool,
+        mut limit: usize,
+    ) -> io::Result<Closed> {
+        let filter = &config.name_filter;
+        let mut backlog = Backlog::default();
+        let mut resume = None;
+
+        // Keeps the order, closed directories are also visited from the back.
+        if let Some(sorted) = self.sorted.take() {
+            for entry in sorted.into_reversed() {
+                backlog.push(entry.file_name(), entry.file_type.inner, entry.ino, entry.offset);
+            }
+            // All entries were read already.
+            limit = usize::MAX;
+        }
+
+        loop {
+            let mut read = 0;
+            let entries = self.buffer
+                .drain()
+                .inspect(|_| read += 1)
+                .map(Self::okay)
+                .filter_map(|entry| Self::sub_entry(entry, filter));
+            for entry in entries {
+                let (ino, offset) = (Some(entry.ino()), Some(entry.offset()));
+                backlog.push(entry.file_name(), entry.kind(), ino, offset);
+            }
+            self.nr_entries += read;
+
+            // The buffer was drained, so the descriptor is positioned after its last entry. Unless
+            // more were read ahead already, these are taken like any other.
+            if backlog.names.len() >= limit && self.ahead.is_none() {
+                stats.syscall(Syscall::Seek, self.depth - 1);
+                resume = Some(self.fd.position()?);
+                break;
+            }
+
+            match self.fill_buffer(config, stats)? {
+                More::Blocked => unreachable!("Just drained buffer is blocked"),
+                More::More => {},
+                More::Done => break,
+            }
+        }
+
+        stats.max_backlog = stats.max_backlog.max(backlog.names.len());
+        let mut closed = Closed::from_backlog(&self, backlog);
+        closed.resume = resume;
+        stats.closed(&self);
+        buffers.put(self.buffer);
+        if let Some(spare) = self.spare {
+            buffers.put(spare);
+        }
+        DirFd::release(self.fd)?;
+        Ok(closed)
+    }
+
+    /// The entries that were not yet yielded, and the offset after them.
+    fn save_state(&mut self, filter: &NameFilter, stats: &mut Stats) -> io::Result<DirState> {
+        let (entries, offset) = match &self.sorted {
+            Some(sorted) => {
+                let entries = sorted.reversed().into_iter();
+                let entries = entries.map(|entry| entry.save_state(entry.file_name()));
+                (entries.collect(), None)
+            }
+            None => {
+                // The entries read ahead are in between, the descriptor is positioned after them.
+                let ahead = self.ahead.as_mut().map(|ahead| &ahead.get().0);
+                let buffered: Vec<_> = self.buffer
+                    .iter()
+                    .chain(ahead.into_iter().flat_map(DirentBuf::iter))
+                    .map(Self::okay)
+                    .filter_map(|entry| Self::sub_entry(entry, filter))
+                    .map(|entry| EntryState {
+                        name: entry.file_name().as_bytes().to_owned(),
+                        file_type: entry.kind(),
+                        ino: Some(entry.ino()),
+                        offset: Some(entry.offset()),
+                    })
+                    .collect();
+                // The buffer was read up to the current offset.
+                stats.syscall(Syscall::Seek, self.depth - 1);
+                let offset = self.fd.position()?;
+                (buffered.into_iter().rev().collect(), Some(offset))
+            }
+        };
+
+        Ok(DirState {
+            path: Some(self.as_parent.path().as_os_str().as_bytes().to_owned()),
+            depth: self.depth,
+            id: self.as_parent.id.map(FileId::save_state),
+            entries,
+            offset,
+        })
+    }
+
+    /// Filter an entry that we got from the internal buffer.
+    /// Handles kernel errors and setup faults which mustn't occur in regular operation.
+    fn okay(entry: Result<Entry<'_>, DirentErr>) -> Entry<'_> {
+        match entry {
+            Ok(entry) => entry,
+            Err(DirentErr::TooShort) => unreachable!("Inconsistent buffer state"),
+            Err(DirentErr::InvalidLength) => unreachable!("You must have hit a kernel bug!"),
+        }
+    }
+
+    /// The entries that were read and not yet yielded, without `.` and `..`.
+    fn buffered_len(&self, filter: &NameFilter) -> usize {
+        if let Some(sorted) = &self.sorted {
+            return sorted.len();
+        }
+
+        self.buffer
+            .iter()
+            .filter_map(Result::ok)
+            .filter_map(|entry| Self::sub_entry(entry, filter))
+            .count()
+    }
+
+    fn sub_entry<'a>(entry: Entry<'a>, filter: &NameFilter) -> Option<Entry<'a>> {
+        // Never recurse into current or parent directory.
+        match Path::new(entry.file_name()).components().next() {
+            Some(Component::CurDir) | Some(Component::ParentDir) => return None,
+            _ => {},
+        }
+
+        if filter.skipped.contains(entry.kind()) {
+            return None;
+        }
+
+        let name = entry.file_name().as_bytes();
+        if !filter.descended.contains(entry.kind()) && !filter.yields(name) {
+            return None;
+        }
+
+        if filter.accepts(name) {
+            Some(entry)
+        } else {
+            None
+        }
+    }
+}
+
+impl Base {
+    /// The directory and the path to pass to a system call, for a path of the walk.
+    fn locate(base: Option<&Self>, path: &Path) -> (libc::c_int, CString) {
+        let (dir, path) = match base {
+            Some(base) => (base.dir(), base.full_path(path)),
+            None => (libc::AT_FDCWD, Cow::Borrowed(path)),
+        };
+
+        let path = CString::new(path.as_os_str().as_bytes())
+            .expect("No interior NULL byte in Path");
+        (dir, path)
+    }
+
+    fn dir(&self) -> libc::c_int {
+        self.dir.as_ref().map_or(libc::AT_FDCWD, |dir| dir.0)
+    }
+
+    /// The path relative to the directory, for a path of the walk.
+    fn full_path<'p>(&'p self, path: &'p Path) -> Cow<'p, Path> {
+        match self.relative {
+            // Without a separator at the end, the root need not be a directory.
+            true if path.as_os_str().is_empty() => Cow::Borrowed(&self.root),
+            true => Cow::Owned(self.root.join(path)),
+            false => Cow::Borrowed(path),
+        }
+    }
+
+    /// The path relative to the root, for a path of the walk.
+    fn path_below_root<'p>(&self, path: &'p Path) -> Option<&'p Path> {
+        match self.relative {
+            true => Some(path),
+            false => path.strip_prefix(&self.root).ok(),
+        }
+    }
+}
+
+impl DirFd {
+    /// Open a directory by its path, relative to the base of the walk if it has one.
+    fn open(base: Option<&Base>, path: &Path) -> io::Result<Self> {
+        let (dir, path) = Base::locate(base, path);
+        Self::openat_raw(dir, &path)
+    }
+
+    fn openat(&self, path: &CStr) -> io::Result<Self> {
+        Self::openat_raw(self.0, path)
+    }
+
+    /// Open a directory by its path, beneath the root of a sandboxed walk.
+    fn open_in(base: Option<&Arc<Base>>, path: &Path, stats: &mut Stats) -> io::Result<Self> {
+        let (base, sandbox) = match base {
+            Some(base) => match &base.sandbox {
+                Some(sandbox) => (base, sandbox),
+                None => return Self::open(Some(base), path),
+            },
+            None => return Self::open(None, path),
+        };
+
+        let root = sandbox.get_or_try_init(|| {
+            stats.syscall(Syscall::Open, 0);
+            let root = CString::new(base.root.as_os_str().as_bytes())
+                .expect("No interior NULL byte in Path");
+            Self::openat_raw(base.dir(), &root)
+        })?;
+
+        let relative = base.path_below_root(path).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::PermissionDenied, "Directory outside of the sandbox")
+        })?;
+        let relative = match relative.as_os_str().as_bytes() {
+            b"" => b".",
+            relative => relative,
+        };
+        let relative = CString::new(relative).expect("No interior NULL byte in Path");
+        root.open_beneath(&relative)
+    }
+
+    /// Open a directory below this one, without leaving it or following links.
+    fn open_beneath(&self, path: &CStr) -> io::Result<Self> {
+        let how = OpenHow {
+            flags: (libc::O_RDONLY | libc::O_DIRECTORY) as u64,
+            mode: 0,
+            resolve: RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS,
+        };
+
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_openat2,
+                self.0,
+                path.as_ptr(),
+                &how as *const OpenHow,
+                mem::size_of::<OpenHow>(),
+            )
+        };
+
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(DirFd(result as libc::c_int))
+    }
+
+    fn openat_raw(dir: libc::c_int, path: &CStr) -> io::Result<Self> {
+        let result = unsafe {
+            libc::openat(dir, path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY)
+        };
+
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(DirFd(result))
+    }
+
+    /// Open a directory, failing if the last component of its path is a symbolic link.
+    fn openat_nofollow(dir: libc::c_int, path: &CStr) -> io::Result<Self> {
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW;
+        let result = unsafe { libc::openat(dir, path.as_ptr(), flags) };
+
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(DirFd(result))
+    }
+
+    /// Stat an entry of this directory.
+    fn stat_at(&self, name: &CStr, flags: libc::c_int) -> io::Result<libc::stat> {
+        fstatat(self.0, name, flags)
+    }
+
+    /// Stat the directory itself.
+    fn stat(&self) -> io::Result<libc::stat> {
+        let mut stat = mem::MaybeUninit::<libc::stat>::uninit();
+        let result = unsafe { libc::fstat(self.0, stat.as_mut_ptr()) };
+
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: initialized by the successful call.
+        Ok(unsafe { stat.assume_init() })
+    }
+
+    /// The current offset in the directory stream.
+    fn position(&self) -> io::Result<libc::off_t> {
+        self.seek(0, libc::SEEK_CUR)
+    }
+
+    fn seek(&self, offset: libc::off_t, whence: libc::c_int) -> io::Result<libc::off_t> {
+        match unsafe { libc::lseek(self.0, offset, whence) } {
+            -1 => Err(io::Error::last_os_error()),
+            offset => Ok(offset),
+        }
+    }
+
+    /// Close the descriptor, unless an entry is using it right now.
+    ///
+    /// In that case it is closed when the entry is done with it.
+    fn release(fd: Arc<Self>) -> io::Result<()> {
+        match Arc::try_unwrap(fd) {
+            Ok(fd) => fd.close(),
+            Err(_) => Ok(()),
+        }
+    }
+
+    fn close(self) -> io::Result<()> {
+        let fd = self.0;
+        // Closed below, even if that fails.
+        mem::forget(self);
+        match unsafe { libc::close(fd) } {
+            0 => Ok(()),
+            _ => match io::Error::last_os_error() {
+                // Linux frees the descriptor regardless, closing it again could hit another file.
+                err if err.kind() == io::ErrorKind::Interrupted => Ok(()),
+                err => Err(err),
+            },
+        }
+    }
+}
+
+impl Drop for DirFd {
+    /// Only reached when the walk is abandoned, errors can not be reported then.
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// An eighth of the limit of open files, so that the walk does not use up most of them.
+fn default_max_open() -> usize {
+    let mut limit = mem::MaybeUninit::<libc::rlimit>::uninit();
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, limit.as_mut_ptr()) } == -1 {
+        return DEFAULT_MAX_OPEN;
+    }
+
+    // SAFETY: initialized by the successful call.
+    let current = unsafe { limit.assume_init() }.rlim_cur;
+    if current == libc::RLIM_INFINITY {
+        return DEFAULT_MAX_OPEN;
+    }
+
+    usize::try_from(current / 8).unwrap_or(usize::MAX).clamp(1, DEFAULT_MAX_OPEN)
+}
+
+/// Where a path is in the deterministic order, relative to the entry a walk starts after.
+///
+/// `Equal` for that entry and the directories containing it.
+fn start_order(path: &Path, start: &Path) -> core::cmp::Ordering {
+    let (mut path, mut start) = (path.components(), start.components());
+    loop {
+        match (path.next(), start.next()) {
+            (None, _) => return core::cmp::Ordering::Equal,
+            // Contained in the entry.
+            (Some(_), None) => return core::cmp::Ordering::Greater,
+            (Some(a), Some(b)) if a == b => {}
+            (Some(a), Some(b)) => return a.as_os_str().as_bytes().cmp(b.as_os_str().as_bytes()),
+        }
+    }
+}
+
+/// If an entry is a directory, for the order of siblings.
+///
+/// Entries of unknown type are inspected relative to their open directory, the walk keeps the
+/// meta data for later.
+fn is_dir_sibling(entry: &DirEntry) -> bool {
+    let file_type = entry.file_type();
+    match file_type.is_unknown() {
+        true => entry.metadata_cached().is_ok_and(Metadata::is_dir),
+        false => file_type.is_dir(),
+    }
+}
+
+/// Call again while interrupted by a signal, at most `retries` more times.
+fn retry<T>(retries: usize, mut call: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut left = retries;
+    loop {
+        match call() {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted && left > 0 => left -= 1,
+            result => return result,
+        }
+    }
+}
+
+/// If opening failed because the process or the system has too many open files.
+fn out_of_descriptors(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+fn fstatat(fd: libc::c_int, name: &CStr, flags: libc::c_int) -> io::Result<libc::stat> {
+    let mut stat = mem::MaybeUninit::<libc::stat>::uninit();
+    let result = unsafe {
+        libc::fstatat(fd, name.as_ptr(), stat.as_mut_ptr(), flags)
+    };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: initialized by the successful call.
+    Ok(unsafe { stat.assume_init() })
+}
+
+impl Sorted {
+    fn new(entries: Vec<DirEntry>, sorter: &Comparator) -> Self {
+        let mut heap: Vec<_> = entries.into_iter().enumerate().collect();
+        let mut cmp = sorter.lock().unwrap_or_else(|err| err.into_inner());
+        for index in (0..heap.len() / 2).rev() {
+            Self::sift_down(&mut heap, index, &mut *cmp);
+        }
+        drop(cmp);
+
+        Sorted {
+            heap,
+            sorter: sorter.clone(),
+        }
+    }
+
+    /// Take the first of the remaining entries.
+    fn pop(&mut self) -> Option<DirEntry> {
+        let last = self.heap.len().checked_sub(1)?;
+        self.heap.swap(0, last);
+        let (_, entry) = self.heap.pop()?;
+        let mut cmp = self.sorter.lock().unwrap_or_else(|err| err.into_inner());
+        Self::sift_down(&mut self.heap, 0, &mut *cmp);
+        Some(entry)
+    }
+
+    fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    /// The remaining entries in reverse order, the next one last.
+    fn reversed(&self) -> Vec<&DirEntry> {
+        let mut cmp = self.sorter.lock().unwrap_or_else(|err| err.into_inner());
+        let mut entries: Vec<_> = self.heap.iter().collect();
+        entries.sort_unstable_by(|a, b| Self::order(b, a, &mut *cmp));
+        entries.into_iter().map(|(_, entry)| entry).collect()
+    }
+
+    /// The remaining entries in reverse order, the next one last.
+    fn into_reversed(self) -> Vec<DirEntry> {
+        let mut cmp = self.sorter.lock().unwrap_or_else(|err| err.into_inner());
+        let mut entries = self.heap;
+        entries.sort_unstable_by(|a, b| Self::order(b, a, &mut *cmp));
+        entries.into_iter().map(|(_, entry)| entry).collect()
+    }
+
+    fn order(
+        (a_read, a): &(usize, DirEntry),
+        (b_read, b): &(usize, DirEntry),
+        cmp: &mut dyn FnMut(&DirEntry, &DirEntry) -> core::cmp::Ordering,
+    ) -> core::cmp::Ordering {
+        cmp(a, b).then(a_read.cmp(b_read))
+    }
+
+    /// Move the entry at the index down, until it is not after either of its children.
+    fn sift_down(
+        heap: &mut [(usize, DirEntry)],
+        mut index: usize,
+        cmp: &mut dyn FnMut(&DirEntry, &DirEntry) -> core::cmp::Ordering,
+    ) {
+        loop {
+            let left = 2 * index + 1;
+            let right = left + 1;
+            if left >= heap.len() {
+                return;
+            }
+
+            let child = match heap.get(right) {
+                Some(right_entry) if Self::order(right_entry, &heap[left], cmp).is_lt() => right,
+                _ => left,
+            };
+            if Self::order(&heap[child], &heap[index], cmp).is_ge() {
+                return;
+            }
+            heap.swap(child, index);
+            index = child;
+        }
+    }
+}
+
+impl Closed {
+    fn from_backlog(open: &Open, children: Backlog) -> Self {
+        Closed {
+            depth: open.depth,
+            children,
+            as_parent: Some(open.as_parent.clone()),
+            resume: None,
+        }
+    }
+
+    /// Move half of the children into a new work item for the same directory.
+    fn split_off(&mut self) -> Self {
+        let at = self.children.len() / 2;
+        Closed {
+            depth: self.depth,
+            children: self.children.split_off(at),
+            as_parent: self.as_parent.clone(),
+            resume: None,
+        }
+    }
+
+    fn save_state(&self) -> DirState {
+        let children = &self.children;
+        let entries = children.items.iter().enumerate().map(|(index, item)| EntryState {
+            name: children.name(index).as_bytes().to_owned(),
+            file_type: item.file_type,
+            ino: item.ino,
+            offset: item.offset,
+        });
+
+        let node = self.as_parent.as_deref();
+        DirState {
+            path: node.map(|node| node.path().as_os_str().as_bytes().to_owned()),
+            depth: self.depth,
+            id: node.and_then(|node| node.id).map(FileId::save_state),
+            entries: entries.collect(),
+            offset: self.resume,
+        }
+    }
+
+    /// A directory of a saved walk, it is reopened to read the entries that are left.
+    fn from_state(state: DirState, base: Option<&Arc<Base>>) -> Self {
+        let DirState { path, depth, id, entries, offset } = state;
+        let mut children = Backlog::default();
+        for entry in entries {
+            let name = OsStr::from_bytes(&entry.name);
+            children.push(name, entry.file_type, entry.ino, entry.offset);
+        }
+
+        let as_parent = path.map(|path| Arc::new(Node {
+            depth,
+            path: EntryPath::full(PathBuf::from(OsString::from_vec(path)), base),
+            full_path: OnceCell::new(),
+            id: id.map(FileId::from_state),
+            fd: Weak::new(),
+            #[cfg(feature = "ignore")]
+            ignores: None,
+        }));
+
+        Closed {
+            depth,
+            children,
+            as_parent,
+            resume: offset,
+        }
+    }
+
+    /// Reopen the directory to continue reading where it was closed.
+    ///
+    /// Entries keep the parent node whose descriptor is gone, so they resolve themselves by path.
+    fn resume(&mut self, buffer: DirentBuf, config: &Configuration, stats: &mut Stats)
+        -> io::Result<Open>
+    {
+        let offset = self.resume.take().expect("Directory was read completely");
+        let node = self.as_parent.clone().expect("Only directories with a parent are resumed");
+
+        stats.syscall(Syscall::Open, self.depth - 1);
+        let opened = retry(config.eintr_retries, || {
+            DirFd::open_in(node.path.base(), node.path(), stats)
+        });
+        let fd = match opened {
+            Ok(fd) => fd,
+            Err(err) => {
+                // Tried again once a descriptor was freed.
+                if out_of_descriptors(&err) {
+                    self.resume = Some(offset);
+                }
+                return Err(err);
+            }
+        };
+        stats.syscall(Syscall::Seek, self.depth - 1);
+        fd.seek(offset, libc::SEEK_SET)?;
+
+        Ok(Open {
+            buffer,
+            depth: self.depth,
+            as_parent: node,
+            fd: Arc::new(fd),
+            sorted: None,
+            nr_entries: 0,
+            nr_fills: 0,
+            ahead: None,
+            spare: None,
+        })
+    }
+
+    fn ready_entry(&mut self, config: &Configuration, stats: &mut Stats) -> Option<DirEntry> {
+        let index = self.children.len().checked_sub(1)?;
+        let name = self.children.name(index);
+
+        let file_name = match &self.as_parent {
+            Some(parent) => {
+                let name = FileName::new(name);
+                stats.alloc_name(&name);
+                EntryPath::Name {
+                    name,
+                    parent: parent.clone(),
+                }
+            }
+            None => {
+                stats.alloc(Alloc::Path, 1);
+                config.root_path(PathBuf::from(name))
+            }
+        };
+
+        let backlog = self.children.pop();
+        Some(DirEntry {
+            file_name,
+            file_type: FileType {
+                inner: backlog.file_type
+            },
+            depth: self.depth,
+            full_path: OnceCell::new(),
+            metadata: OnceCell::new(),
+            follow_link: false,
+            ino: backlog.ino,
+            offset: backlog.offset,
         })
     }
+}
+
+impl FileId {
+    fn save_state(self) -> (libc::dev_t, libc::ino_t) {
+        (self.dev, self.ino)
+    }
+
+    fn from_state((dev, ino): (libc::dev_t, libc::ino_t)) -> Self {
+        FileId { dev, ino }
+    }
+}
+
+impl Backlog {
+    fn push(
+        &mut self,
+        name: &OsStr,
+        file_type: Option<FileTypeInner>,
+        ino: Option<u64>,
+        offset: Option<i64>,
+    ) {
+        self.items.push(BacklogItem {
+            start: self.names.len(),
+            file_type,
+            ino,
+            offset,
+        });
+        self.names.extend_from_slice(name.as_bytes());
+    }
+
+    fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    fn name(&self, index: usize) -> &OsStr {
+        let start = self.items[index].start;
+        let end = self.items.get(index + 1).map_or(self.names.len(), |next| next.start);
+        OsStr::from_bytes(&self.names[start..end])
+    }
+
+    /// Remove the last item and its name.
+    fn pop(&mut self) -> BacklogItem {
+        let item = self.items.pop().expect("Popped an empty backlog");
+        self.names.truncate(item.start);
+        item
+    }
+
+    /// Move all items of another backlog in front of these, so they are visited last.
+    fn prepend(&mut self, mut front: Backlog) {
+        let offset = front.names.len();
+        for item in &mut self.items {
+            item.start += offset;
+        }
+
+        front.names.append(&mut self.names);
+        front.items.append(&mut self.items);
+        *self = front;
+    }
+
+    /// Move the items from `at` onwards into a new backlog.
+    fn split_off(&mut self, at: usize) -> Self {
+        let start = self.items.get(at).map_or(self.names.len(), |item| item.start);
+        let mut items = self.items.split_off(at);
+        for item in &mut items {
+            item.start -= start;
+        }
+
+        Backlog {
+            names: self.names.split_off(start),
+            items,
+        }
+    }
+}
+
+impl EntryPath {
+    fn make_path(&self) -> PathBuf {
+        match self {
+            EntryPath::Full(buf) | EntryPath::At { path: buf, .. } => buf.clone(),
+            EntryPath::Name { name, parent } => parent.path().join(name.as_os_str()),
+        }
+    }
+
+    /// A full path, relative to the base of the walk if it has one.
+    fn full(path: PathBuf, base: Option<&Arc<Base>>) -> Self {
+        match base {
+            Some(base) => EntryPath::At { path, base: base.clone() },
+            None => EntryPath::Full(path),
+        }
+    }
+
+    /// The directory that the full path is relative to, `None` for the working directory.
+    fn base(&self) -> Option<&Arc<Base>> {
+        let mut path = self;
+        loop {
+            match path {
+                EntryPath::Full(_) => return None,
+                EntryPath::At { base, .. } => return Some(base),
+                EntryPath::Name { parent, .. } => path = &parent.path,
+            }
+        }
+    }
+}
+
+impl Stats {
+    /// Record allocations, these are only counted with the `alloc-stats` feature.
+    fn alloc(&mut self, kind: Alloc, count: usize) {
+        #[cfg(feature = "alloc-stats")]
+        {
+            let counter = match kind {
+                Alloc::Buffer => &mut self.allocs.buffers,
+                Alloc::Name => &mut self.allocs.names,
+                Alloc::Node => &mut self.allocs.nodes,
+                Alloc::Path => &mut self.allocs.paths,
+            };
+            *counter += count;
+        }
+
+        #[cfg(not(feature = "alloc-stats"))]
+        let _ = (kind, count);
+    }
+
+    fn alloc_name(&mut self, name: &FileName) {
+        if let FileName::Heap(_) = name {
+            self.alloc(Alloc::Name, 1);
+        }
+    }
+
+    /// Record a system call concerning a file at the given depth.
+    fn syscall(&mut self, call: Syscall, depth: usize) {
+        self.count(call);
+
+        #[cfg(feature = "histogram-stats")]
+        {
+            let by_depth = &mut self.histograms.by_depth;
+            if by_depth.len() <= depth {
+                by_depth.resize_with(depth + 1, Stats::default);
+            }
+            by_depth[depth].count(call);
+        }
+
+        #[cfg(not(feature = "histogram-stats"))]
+        let _ = depth;
+    }
+
+    fn count(&mut self, call: Syscall) {
+        let counter = match call {
+            Syscall::Close => &mut self.nr_close,
+            Syscall::Getdent => &mut self.nr_getdent,
+            Syscall::Open => &mut self.nr_open,
+            Syscall::Openat => &mut self.nr_openat,
+            Syscall::Seek => &mut self.nr_seek,
+            Syscall::Stat => &mut self.nr_stat,
+        };
+        *counter += 1;
+    }
+
+    /// Record closing a directory, with the entries that were read from it.
+    fn closed(&mut self, open: &Open) {
+        self.syscall(Syscall::Close, open.depth - 1);
+        #[cfg(feature = "histogram-stats")]
+        Histograms::record(&mut self.histograms.entries_per_dir, open.nr_entries);
+    }
+
+    fn getdent_bytes(&mut self, bytes: usize) {
+        #[cfg(feature = "histogram-stats")]
+        Histograms::record(&mut self.histograms.getdent_bytes, bytes);
+        #[cfg(not(feature = "histogram-stats"))]
+        let _ = bytes;
+    }
+
+    /// Record a directory that was closed early, for lack of descriptors.
+    fn spilled(&mut self) {
+        #[cfg(feature = "histogram-stats")]
+        {
+            self.histograms.nr_spill += 1;
+        }
+    }
+}
+
+#[cfg(feature = "histogram-stats")]
+impl Histograms {
+    fn record(buckets: &mut Vec<usize>, value: usize) {
+        let bucket = (usize::BITS - value.leading_zeros()) as usize;
+        if buckets.len() <= bucket {
+            buckets.resize(bucket + 1, 0);
+        }
+        buckets[bucket] += 1;
+    }
+
+    fn since(&self, earlier: &Histograms) -> Histograms {
+        fn buckets(now: &[usize], earlier: &[usize]) -> Vec<usize> {
+            now.iter()
+                .enumerate()
+                .map(|(idx, n)| n.saturating_sub(earlier.get(idx).copied().unwrap_or(0)))
+                .collect()
+        }
+
+        let default = Stats::default();
+        Histograms {
+            entries_per_dir: buckets(&self.entries_per_dir, &earlier.entries_per_dir),
+            getdent_bytes: buckets(&self.getdent_bytes, &earlier.getdent_bytes),
+            by_depth: self.by_depth
+                .iter()
+                .enumerate()
+                .map(|(idx, stats)| stats.since(earlier.by_depth.get(idx).unwrap_or(&default)))
+                .collect(),
+            nr_spill: self.nr_spill.saturating_sub(earlier.nr_spill),
+        }
+    }
+}
 
-    /// Get the next item from this directory.
-    fn pop(&mut self) -> Option<Entry<'_>> {
-        self.buffer.drain().next().map(Self::okay)
+impl BufferPool {
+    fn new(limit: usize) -> Self {
+        BufferPool {
+            buffers: vec![],
+            limit,
+        }
     }
 
-    fn ready_entry(&mut self) -> Option<DirEntry> {
-        let depth = self.depth;
-        let parent = self.as_parent.clone();
-        let entry = self.pop()?;
+    /// Reuse a buffer of exactly this size, or allocate a new one.
+    fn get(&mut self, size: usize, stats: &mut Stats) -> DirentBuf {
+        match self.buffers.iter().position(|buffer| buffer.capacity() == size) {
+            Some(idx) => self.buffers.swap_remove(idx),
+            None => {
+                stats.alloc(Alloc::Buffer, 1);
+                DirentBuf::with_size(size)
+            }
+        }
+    }
+
+    fn put(&mut self, mut buffer: DirentBuf) {
+        if self.buffers.len() < self.limit {
+            // Entries of a directory that was left early must not show up in the next one.
+            buffer.clear();
+            self.buffers.push(buffer);
+        }
+    }
+}
+
+impl Node {
+    /// The complete path, shared by all entries of the directory.
+    fn path(&self) -> &Path {
+        match &self.path {
+            EntryPath::Full(buf) | EntryPath::At { path: buf, .. } => buf,
+            EntryPath::Name { .. } => self.full_path.get_or_init(|| self.path.make_path()),
+        }
+    }
+
+    /// Allocate a path buffer for the path described.
+    fn make_path(&self) -> PathBuf {
+        self.path().to_owned()
+    }
 
-        let entry = match Self::sub_entry(entry) {
-            None => return self.ready_entry(),
-            Some(entry) => entry,
+    /// Open the directory again after the walk closed it.
+    ///
+    /// Fails if a link took the place of the directory, or a directory other than the one with
+    /// the identity determined by the walk.
+    fn reopen(&self) -> io::Result<DirFd> {
+        let base = self.path.base();
+        let dir = match base {
+            Some(base) if base.sandbox.is_some() => {
+                DirFd::open_in(Some(base), self.path(), &mut Stats::default())?
+            }
+            _ => {
+                let (dir, path) = Base::locate(base.map(Arc::as_ref), self.path());
+                DirFd::openat_nofollow(dir, &path)?
+            }
         };
 
-        Some(DirEntry {
-            file_name: EntryPath::Name {
-                name: entry.file_name().to_owned(),
-                parent,
-            },
-            depth,
+        if let Some(id) = self.id {
+            let stat = dir.stat()?;
+            if (FileId { dev: stat.st_dev, ino: stat.st_ino }) != id {
+                return Err(io::Error::other("Directory was replaced during the walk"));
+            }
+        }
+
+        Ok(dir)
+    }
+}
+
+impl DirEntry {
+    fn save_state(&self, name: &OsStr) -> EntryState {
+        EntryState {
+            name: name.as_bytes().to_owned(),
+            file_type: self.file_type.inner,
+            ino: self.ino,
+            offset: self.offset,
+        }
+    }
+
+    /// A deferred directory of a saved walk.
+    fn from_state(depth: usize, state: EntryState, base: Option<&Arc<Base>>) -> Self {
+        DirEntry {
             file_type: FileType {
-                inner: entry.file_type(),
+                inner: state.file_type,
             },
+            depth,
+            file_name: EntryPath::full(PathBuf::from(OsString::from_vec(state.name)), base),
             full_path: OnceCell::new(),
-        })
+            metadata: OnceCell::new(),
+            follow_link: false,
+            ino: state.ino,
+            offset: state.offset,
+        }
+    }
+
+    /// The directory to resolve this entry in and its name relative to that.
+    ///
+    /// This is its directory while the walk keeps it open. Otherwise it is the full path relative
+    /// to the base of the walk, or to the working directory if there is no base.
+    fn resolve(&self) -> (Option<Arc<DirFd>>, CString) {
+        if let Some((dir, name)) = self.in_parent() {
+            return (Some(dir), name);
+        }
+
+        let base = self.file_name.base();
+        let (_, path) = Base::locate(base.map(Arc::as_ref), self.path());
+        (base.and_then(|base| base.dir.clone()), path)
     }
 
-    fn fill_buffer(&mut self, stats: &mut Stats) -> io::Result<More> {
-        stats.nr_getdent += 1;
-        self.buffer.fill_buf(self.fd.0)
+    /// The directory of this entry and its name in it, while the walk keeps it open.
+    fn in_parent(&self) -> Option<(Arc<DirFd>, CString)> {
+        let EntryPath::Name { name, parent } = &self.file_name else { return None };
+        let dir = parent.fd.upgrade()?;
+        Some((dir, CString::new(name.as_os_str().as_bytes()).unwrap()))
     }
+}
 
-    /// Forcibly close this directory entry.
-    /// Returns None if its already finished and Some with the remaining backlog items otherwise.
-    fn close(mut self, stats: &mut Stats) -> io::Result<Option<Closed>> {
-        let mut backlog = vec![];
-        let base = self.as_parent.make_path();
+impl IntoIter {
+    /// Remember the file of an entry, returns if it was already yielded.
+    #[allow(clippy::unnecessary_cast)] // `ino_t` is not 64 bit on every target.
+    fn is_yielded_file(&mut self, entry: &DirEntry) -> Result<bool, Error> {
+        let dev = match &entry.file_name {
+            EntryPath::Name { parent, .. } => parent.id.map(|id| id.dev),
+            _ => None,
+        };
 
-        loop {
-            let entries = self.buffer
-                .drain()
-                .map(Self::okay)
-                .filter_map(Self::sub_entry)
-                .map(|entry| Self::backlog(&base, entry));
-            backlog.extend(entries);
-            stats.nr_getdent += 1;
-            match self.buffer.fill_buf(self.fd.0)? {
-                More::Blocked => unreachable!("Just drained buffer is blocked"),
-                More::More => {},
-                More::Done => break,
+        let id = match (dev, entry.ino) {
+            (Some(dev), Some(ino)) if !entry.follow_link => FileId {
+                dev,
+                ino: ino as libc::ino_t,
+            },
+            _ => {
+                let stat = self.stat_entry(entry, entry.follow_link)
+                    .map_err(|err| Error::from_entry(entry, Op::Stat, err))?;
+                FileId {
+                    dev: stat.st_dev,
+                    ino: stat.st_ino,
+                }
+            }
+        };
+
+        let mut yielded = self.yielded_files.lock().unwrap_or_else(|err| err.into_inner());
+        Ok(!yielded.insert(id))
+    }
+
+    /// Stat the target of a link, unless the same link was followed before.
+    fn stat_link(&mut self, entry: &DirEntry) -> io::Result<libc::stat> {
+        // The directory is only identified when it was checked for loops.
+        let (dir, name) = match (&entry.file_name, self.config.cache_links) {
+            (EntryPath::Name { name, parent }, true) => match parent.id {
+                Some(dir) => (dir, name.as_os_str()),
+                None => return self.stat_entry(entry, true),
+            },
+            _ => return self.stat_entry(entry, true),
+        };
+
+        let cached = self.link_targets
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .get(&dir)
+            .and_then(|links| links.get(name).copied());
+        if let Some(stat) = cached {
+            return Ok(stat);
+        }
+
+        let stat = self.stat_entry(entry, true)?;
+        self.link_targets
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .entry(dir)
+            .or_default()
+            .insert(name.to_owned(), stat);
+        Ok(stat)
+    }
+
+    /// Stat an entry that was just taken from the top of the stack.
+    ///
+    /// Relative to its directory if that is still open, by its full path otherwise.
+    fn stat_entry(&mut self, entry: &DirEntry, follow: bool) -> io::Result<libc::stat> {
+        let flags = if follow { 0 } else { libc::AT_SYMLINK_NOFOLLOW };
+        self.stats.syscall(Syscall::Stat, entry.depth);
+        match self.stack.last() {
+            Some(WorkItem::Open(open)) => {
+                let name = CString::new(entry.file_name().as_bytes()).unwrap();
+                open.fd.stat_at(&name, flags)
+            }
+            _ => {
+                self.stats.alloc(Alloc::Path, 1);
+                let path = entry.file_name.make_path();
+                let base = entry.file_name.base().map(Arc::as_ref);
+                let (dir, path) = Base::locate(base, &path);
+                fstatat(dir, &path, flags)
+            }
+        }
+    }
+
+    /// Inspect an entry for its meta data, unless that already happened.
+    fn prefetch_metadata(&mut self, entry: &DirEntry) -> Result<(), Error> {
+        if entry.metadata.get().is_some() {
+            return Ok(());
+        }
+
+        let stat = self.stat_entry(entry, entry.follow_link)
+            .map_err(|err| Error::from_entry(entry, Op::Stat, err))?;
+        let _ = entry.metadata.set(Box::new(Metadata::new(stat)));
+        Ok(())
+    }
+
+    /// If the entry is matched by the ignore files of its directory. The roots never are.
+    #[cfg_attr(not(feature = "ignore"), allow(unused_variables))]
+    fn is_ignored(&self, entry: &DirEntry) -> bool {
+        #[cfg(feature = "ignore")]
+        if let (Some(files), EntryPath::Name { parent, .. }) =
+            (&self.config.ignore_files, &entry.file_name)
+        {
+            let is_dir = entry.file_type.is_dir();
+            return files.is_ignored(parent.ignores.as_ref(), entry.path(), is_dir);
+        }
+        false
+    }
+
+    /// Check where a newly opened directory is, returns if it should be descended into.
+    ///
+    /// Fails if the directory is one of its own ancestors, which can only happen when following
+    /// links. Records the identity of the directory for checking its own descendants. Also
+    /// determines if the directory is on the same file system as the root.
+    fn check_identity(&mut self, entry: &DirEntry, open: &mut Open) -> Result<bool, Error> {
+        self.stats.syscall(Syscall::Stat, entry.depth);
+        let stat = open.fd.stat().map_err(|err| Error::from_entry(entry, Op::Stat, err))?;
+        let id = FileId {
+            dev: stat.st_dev,
+            ino: stat.st_ino,
+        };
+
+        Arc::get_mut(&mut open.as_parent)
+            .expect("Node of a new directory is not shared")
+            .id = Some(id);
+
+        let ancestor = self.stack
+            .iter()
+            .filter_map(WorkItem::node)
+            .find(|node| node.id == Some(id));
+
+        if let Some(ancestor) = ancestor {
+            if self.config.skip_loops {
+                return Ok(false);
+            }
+            return Err(Error::from_loop(entry, ancestor.make_path()));
+        }
+
+        if self.config.visit_dirs_once {
+            let mut visited = self.visited_dirs.lock().unwrap_or_else(|err| err.into_inner());
+            if !visited.insert(id) {
+                return Ok(false);
+            }
+        }
+
+        // Directories are only descended into on the file system of their root, so that is the
+        // one of their parent. Directories without a parent compare to the latest root.
+        if entry.depth == 0 {
+            self.root_device = Some(id.dev);
+        }
+        let parent = match &entry.file_name {
+            EntryPath::Name { parent, .. } => parent.id,
+            _ => None,
+        };
+        let root_device = match parent {
+            Some(parent) => parent.dev,
+            None => *self.root_device.get_or_insert(id.dev),
+        };
+        Ok(!self.config.same_file_system || root_device == id.dev)
+    }
+
+    /// The number of entries that were read and are certain to be yielded.
+    ///
+    /// Each of them is yielded, or an error in its place, unless some are skipped as duplicates
+    /// or vanished directories. Directories deferred for their contents are yielded as well.
+    fn buffered_len(&self) -> usize {
+        let config = &self.config;
+        // Directories that were read may not be yielded themselves.
+        let skips = config.dedup_hardlinks || config.vanished == Vanished::Ignore;
+        if skips || config.types != TypeSet::ALL || config.matches_patterns() {
+            return 0;
+        }
+
+        let pending = self.stack
+            .iter()
+            .filter(|item| item.depth() >= config.min_depth)
+            .map(|item| match item {
+                WorkItem::Open(open) => open.buffered_len(&config.name_filter),
+                WorkItem::Closed(closed) => closed.children.len(),
+            })
+            .sum::<usize>();
+        pending + self.deferred.len()
+    }
+
+    /// Add the next root that a helper opened to the roots of the walk, waiting for it.
+    fn receive_root(&mut self) {
+        // The previous roots are done, including those that were not descended into.
+        self.release_preopened();
+        let root = self.roots.as_mut().and_then(Preopen::next);
+        if !self.roots.as_ref().is_some_and(Preopen::is_pending) {
+            self.roots = None;
+            self.open_budget += mem::take(&mut self.preopen_budget);
+        }
+        let Some(root) = root else { return };
+
+        for _ in 0..root.nr_stat {
+            self.stats.syscall(Syscall::Stat, 0);
+        }
+        for _ in 0..root.nr_open {
+            self.stats.syscall(Syscall::Open, 0);
+        }
+        // The descriptor now counts against the walk, unless it has none to spare.
+        if let (Some(fd), 1..) = (root.fd, self.open_budget) {
+            self.open_budget -= 1;
+            self.preopened.push((root.path.clone(), fd));
+        }
+        if let Some(WorkItem::Closed(closed)) = self.stack.first_mut() {
+            closed.children.push(root.path.as_os_str(), root.file_type, None, None);
+        }
+    }
+
+    /// Stop opening roots on helpers, without waiting for them.
+    ///
+    /// The roots that were not yet handed over are opened by their path when they are visited.
+    fn stop_preopen(&mut self) {
+        let Some(roots) = self.roots.take() else { return };
+        self.release_preopened();
+        self.open_budget += mem::take(&mut self.preopen_budget);
+        if let Some(WorkItem::Closed(closed)) = self.stack.first_mut() {
+            // Entries are taken from the back.
+            for path in roots.stop().iter().rev() {
+                closed.children.push(path.as_os_str(), None, None, None);
+            }
+        }
+    }
+
+    /// Close the roots that a helper opened but that were not descended into.
+    fn release_preopened(&mut self) {
+        self.open_budget += self.preopened.len();
+        self.preopened.clear();
+    }
+
+    /// The descriptor of a root that a helper already opened.
+    fn take_preopened(&mut self, entry: &DirEntry) -> Option<DirFd> {
+        let root = match &entry.file_name {
+            EntryPath::Full(path) => path,
+            EntryPath::At { base, .. } => &base.root,
+            EntryPath::Name { .. } => return None,
+        };
+        let index = self.preopened.iter().position(|(path, _)| path == root)?;
+        Some(self.preopened.swap_remove(index).1)
+    }
+
+    /// Open the root relative to the directory of the entry the walk started from.
+    ///
+    /// Falls back to opening the root by its path, which reports the error if there is one.
+    fn open_root_dir(&mut self) -> Option<DirFd> {
+        let (dir, name) = self.config.root_dir.take()?;
+        let sandboxed = self.config.root_base.as_ref().is_some_and(|base| base.sandbox.is_some());
+        self.stats.syscall(Syscall::Openat, 0);
+        retry(self.config.eintr_retries, || match sandboxed {
+            true => dir.open_beneath(&name),
+            false => dir.openat(&name),
+        }).ok()
+    }
+
+    /// Check if all contents of the innermost deferred directory were yielded.
+    fn has_finished_dir(&self) -> bool {
+        match self.deferred.last() {
+            None => false,
+            // The current directory is the deferred one, or one of its children.
+            Some(dir) => self.stack.last().is_none_or(|top| top.depth() <= dir.depth),
+        }
+    }
+
+    /// If the next entry is read from the current directory and yielded as it is.
+    ///
+    /// These are entries of a known type that is not a directory, nor a link that is followed.
+    /// Skips entries of the directory that are never yielded.
+    fn next_is_plain(&mut self) -> bool {
+        if !self.in_plain_dir() {
+            return false;
+        }
+
+        match self.stack.last_mut() {
+            Some(WorkItem::Open(open)) => {
+                open.next_is_plain(&self.config.name_filter, self.config.follow_links)
+            }
+            _ => false,
+        }
+    }
+
+    /// If the current directory is open and its entries may be yielded as they are read.
+    fn in_plain_dir(&self) -> bool {
+        let config = &self.config;
+        let inspects = config.verify_types > 0 || config.dedup_hardlinks || config.with_metadata;
+        // Each entry is counted, or checked against the deadline.
+        let limited = config.max_entries != usize::MAX || config.deadline.is_some()
+            || config.progress_every > 0;
+        let ends = limited || self.cancelled.load(Ordering::Relaxed);
+        let skips = !self.skipped.is_empty() || self.start_after.is_some();
+        if inspects || config.matches_patterns() || ends || skips || self.has_finished_dir() {
+            return false;
+        }
+
+        match self.stack.last() {
+            Some(WorkItem::Open(open)) => {
+                open.sorted.is_none() && open.depth >= self.config.min_depth
             }
+            _ => false,
         }
+    }
 
-        if backlog.is_empty() {
-            stats.nr_close += 1;
-            self.fd.close()?;
-            Ok(None)
+    /// Take the innermost deferred directory, if all its contents were yielded.
+    fn finished_dir(&mut self) -> Option<DirEntry> {
+        if self.has_finished_dir() {
+            self.deferred.pop()
         } else {
-            let closed = Closed::from_backlog(&self, backlog);
-            stats.nr_close += 1;
-            self.fd.close()?;
-            Ok(Some(closed))
+            None
+        }
+    }
+
+    /// Compare a sample of reported file types with the result of `stat`.
+    fn verify_type(&mut self, entry: &mut DirEntry) {
+        self.unverified += 1;
+        if self.unverified < self.config.verify_types {
+            return;
+        }
+
+        self.unverified = 0;
+        // The entry may be gone, nothing to verify then.
+        let actual = match self.stat_entry(entry, false) {
+            Ok(stat) => FileTypeInner::from_mode(stat.st_mode),
+            Err(_) => return,
+        };
+
+        self.stats.nr_verify += 1;
+        if actual != entry.file_type.inner {
+            self.stats.nr_type_mismatch += 1;
+            entry.file_type.inner = actual;
+        }
+    }
+
+    /// Determine the type of the newly found entry, and if we should descend into it.
+    ///
+    /// Also returns the size of the directory, if we had to stat it anyways.
+    fn classify(&mut self, entry: &mut DirEntry) -> Result<(bool, Option<u64>), Error> {
+        // The size of the directory, if we had to stat it anyways.
+        let mut size_hint = None;
+        // The meta data of the entry as it is yielded, if we had to stat it anyways.
+        let mut inspected = None;
+
+        if entry.file_type.inner.is_some() && self.config.verify_types > 0 {
+            self.verify_type(entry);
+        }
+
+        // The file system does not report types, relative to the parent if it is still open.
+        if entry.file_type.inner.is_none() {
+            let stat = self.stat_entry(entry, false)
+                .map_err(|err| Error::from_entry(entry, Op::Stat, err))?;
+            size_hint = u64::try_from(stat.st_size).ok();
+            entry.file_type.inner = FileTypeInner::from_mode(stat.st_mode);
+            inspected = Some(stat);
+        }
+
+        let is_dir = match entry.file_type.inner {
+            Some(FileTypeInner::Directory) => true,
+            // A root that is a symbolic link is always followed. Unless we follow all links, the
+            // entry still is the link.
+            Some(FileTypeInner::SymbolicLink) if self.config.follow_links || entry.depth == 0 => {
+                let stat = self.stat_link(entry)
+                    .map_err(|err| Error::from_entry(entry, Op::Stat, err))?;
+                size_hint = u64::try_from(stat.st_size).ok();
+                let target = FileTypeInner::from_mode(stat.st_mode);
+                if self.config.follow_links {
+                    entry.file_type.inner = target;
+                    entry.follow_link = true;
+                    inspected = Some(stat);
+                }
+                target == Some(FileTypeInner::Directory)
+            }
+            // Including modes that we do not know.
+            _ => false,
+        };
+
+        if !is_dir && entry.depth == 0 && self.config.require_dir_root {
+            let err = io::Error::from_raw_os_error(libc::ENOTDIR);
+            return Err(Error::from_entry(entry, Op::Open, err));
+        }
+
+        // Replaces meta data of a followed link that was inspected before, when sorting.
+        if let Some(stat) = inspected {
+            entry.metadata = OnceCell::with_value(Box::new(Metadata::new(stat)));
+        }
+
+        Ok((is_dir, size_hint))
+    }
+
+    /// Descend into a directory entry that was accepted.
+    ///
+    /// Returns if the directory is now the current one.
+    fn descend(&mut self, entry: &DirEntry, size_hint: Option<u64>) -> Result<bool, Error> {
+        // A root that a helper opened was charged to the budget when it was handed over.
+        let mut preopened = match entry.depth {
+            0 => self.take_preopened(entry),
+            _ => None,
+        };
+        let charged = preopened.is_some();
+        if self.open_budget == 0 && !charged {
+            self.spill()?;
+        }
+        if entry.depth == 0 && preopened.is_none() {
+            preopened = self.open_root_dir();
+        }
+
+        let buffer_size = self.config.buffer_size
+            .unwrap_or_else(|| Open::buffer_size(size_hint));
+
+        let mut next = loop {
+            let buffer = self.buffers.get(buffer_size, &mut self.stats);
+            let opened = match (self.stack.last(), preopened.take()) {
+                (_, Some(fd)) => Ok(Open::with_fd(entry, fd, buffer, &mut self.stats)),
+                (Some(WorkItem::Open(open)), None) => {
+                    open.openat_os(entry.file_name(), buffer, &self.config, &mut self.stats)
+                }
+                // Includes the queued directories of a breadth first walk.
+                (Some(WorkItem::Closed(_)) | None, None) => {
+                    Open::open_path(entry, buffer, &self.config, &mut self.stats)
+                }
+            };
+
+            match opened {
+                Err(err) if out_of_descriptors(&err) && self.has_open() => self.shrink_open()?,
+                opened => break opened.map_err(|err| Error::from_entry(entry, Op::Open, err))?,
+            }
+        };
+        if !charged {
+            self.open_budget -= 1;
+        }
+
+        if let (0, Some(offset)) = (entry.depth, self.config.root_offset) {
+            self.stats.syscall(Syscall::Seek, 0);
+            if let Err(err) = next.fd.seek(offset as libc::off_t, libc::SEEK_SET) {
+                self.close_open(next);
+                return Err(Error::from_entry(entry, Op::Read, err));
+            }
+        }
+
+        let config = &self.config;
+        if config.checks_loops() || config.same_file_system || config.dedup_hardlinks {
+            match self.check_identity(entry, &mut next) {
+                Ok(true) => {},
+                // Not descending after all.
+                other => {
+                    self.close_open(next);
+                    return other;
+                }
+            }
+        }
+
+        #[cfg(feature = "ignore")]
+        if let Some(files) = &self.config.ignore_files {
+            let parent = match &entry.file_name {
+                EntryPath::Name { parent, .. } => parent.ignores.as_ref(),
+                _ if entry.depth == 0 => files.root.as_ref(),
+                _ => None,
+            };
+            match files.read(next.fd.0, next.as_parent.path(), parent) {
+                Ok(ignores) => {
+                    Arc::get_mut(&mut next.as_parent)
+                        .expect("Node of a new directory is not shared")
+                        .ignores = ignores;
+                }
+                Err(err) => {
+                    self.close_open(next);
+                    return Err(Error::from_entry(entry, Op::Read, err));
+                }
+            }
+        }
+
+        if let Some(sorter) = &self.config.sorter {
+            let sorted = next.sort(&self.config, sorter, &mut self.stats);
+            if let Err(err) = sorted {
+                self.close_open(next);
+                return Err(Error::from_entry(entry, Op::Read, err));
+            }
+        }
+
+        // Visit the contents right away, so the directory is the current one.
+        next.read_ahead(&self.config, &mut self.stats);
+        self.stack.push(WorkItem::Open(next));
+
+        Ok(true)
+    }
+
+    /// Close the innermost open directory to free its file descriptor.
+    ///
+    /// Its remaining entries are read into memory, and it is then visited like any other closed
+    /// directory. Usually this is the parent of the directory we are about to open.
+    fn spill(&mut self) -> Result<(), Error> {
+        let index = self.stack
+            .iter()
+            .rposition(|item| matches!(item, WorkItem::Open(_)))
+            .expect("No budget left but no directory is open");
+        self.stats.spilled();
+        self.close_at(index, self.config.max_backlog)
+    }
+
+    /// Close the outermost open directory, after the process ran out of file descriptors.
+    ///
+    /// All other open directories were opened after it. From now on the walk keeps one directory
+    /// less open, unless it would keep none.
+    fn shrink_open(&mut self) -> Result<(), Error> {
+        let index = self.stack
+            .iter()
+            .position(|item| matches!(item, WorkItem::Open(_)))
+            .expect("Only closing when a directory is open");
+        self.stats.spilled();
+        self.close_at(index, self.config.max_backlog)?;
+        if self.open_budget > 1 {
+            self.open_budget -= 1;
+        }
+        Ok(())
+    }
+
+    /// If any directory on the stack is open.
+    fn has_open(&self) -> bool {
+        self.stack.iter().any(|item| matches!(item, WorkItem::Open(_)))
+    }
+
+    /// Read the open directory at this position of the stack and close it.
+    ///
+    /// Reading stops early when the names of its remaining entries reach the limit.
+    fn close_at(&mut self, index: usize, limit: usize) -> Result<(), Error> {
+        let item = &mut self.stack[index];
+
+        // Stays in place if reading the remaining entries fails.
+        let empty = match item {
+            WorkItem::Open(open) => Closed::from_backlog(open, Backlog::default()),
+            WorkItem::Closed(_) => unreachable!("Not an open directory"),
+        };
+
+        let open = match mem::replace(item, WorkItem::Closed(empty)) {
+            WorkItem::Open(open) => open,
+            WorkItem::Closed(_) => unreachable!("Not an open directory"),
+        };
+
+        // The descriptor is gone, even if reading failed.
+        self.open_budget += 1;
+        let node = open.as_parent.clone();
+        let closed = open.close(&self.config, &mut self.stats, &mut self.buffers, limit)
+            .map_err(|err| Error::from_node(&node, Op::Read, err))?;
+        *item = WorkItem::Closed(closed);
+        Ok(())
+    }
+
+    /// Reopen the closed directory on top of the stack to continue reading it.
+    fn resume(&mut self) -> Result<(), Error> {
+        if self.open_budget == 0 {
+            self.spill()?;
+        }
+
+        let buffer_size = self.config.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+
+        let mut open = loop {
+            let buffer = self.buffers.get(buffer_size, &mut self.stats);
+            let closed = match self.stack.last_mut() {
+                Some(WorkItem::Closed(closed)) => closed,
+                _ => unreachable!("Only closed directories are resumed"),
+            };
+
+            let node = closed.as_parent.clone().expect("Resumed directories have a parent");
+            match closed.resume(buffer, &self.config, &mut self.stats) {
+                Ok(open) => break open,
+                Err(err) if out_of_descriptors(&err) && self.has_open() => self.shrink_open()?,
+                Err(err) => return Err(Error::from_node(&node, Op::Open, err)),
+            }
+        };
+
+        self.open_budget -= 1;
+        open.read_ahead(&self.config, &mut self.stats);
+        *self.stack.last_mut().unwrap() = WorkItem::Open(open);
+        Ok(())
+    }
+
+    /// Read all entries of a closed directory that were left for reopening it.
+    fn read_remaining(&mut self, closed: &mut Closed) -> Result<(), Error> {
+        if closed.resume.is_none() {
+            return Ok(());
+        }
+
+        let buffer_size = self.config.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+        let buffer = self.buffers.get(buffer_size, &mut self.stats);
+        let node = closed.as_parent.clone().expect("Resumed directories have a parent");
+
+        let open = closed.resume(buffer, &self.config, &mut self.stats)
+            .map_err(|err| Error::from_node(&node, Op::Open, err))?;
+        let rest = open.close(&self.config, &mut self.stats, &mut self.buffers, usize::MAX)
+            .map_err(|err| Error::from_node(&node, Op::Read, err))?;
+        closed.children.prepend(rest.children);
+        Ok(())
+    }
+
+    /// Move half of the pending entries into a new iterator, see [`IntoIter::split`].
+    ///
+    /// When reading an open directory fails this iterator stays usable, without the entries that
+    /// could not be read.
+    pub(crate) fn split_off(&mut self) -> Result<IntoIter, Error> {
+        self.flush_queued();
+        self.stop_preopen();
+        for index in 0..self.stack.len() {
+            if let WorkItem::Open(_) = self.stack[index] {
+                self.close_at(index, usize::MAX)?;
+            }
+        }
+
+        let mut stack = mem::take(&mut self.stack);
+        let remaining = stack.iter_mut().try_for_each(|item| match item {
+            WorkItem::Closed(closed) => self.read_remaining(closed),
+            WorkItem::Open(_) => unreachable!("All directories were closed"),
+        });
+        self.stack = stack;
+        remaining?;
+
+        let mut left = vec![];
+        let mut right = vec![];
+
+        // From the bottom of the stack, so both halves keep the relative order.
+        for item in mem::take(&mut self.stack) {
+            let mut closed = match item {
+                WorkItem::Closed(closed) => closed,
+                WorkItem::Open(_) => unreachable!("All directories were closed"),
+            };
+
+            let other = closed.split_off();
+            if !closed.children.is_empty() {
+                left.push(WorkItem::Closed(closed));
+            }
+            if !other.children.is_empty() {
+                right.push(WorkItem::Closed(other));
+            }
+        }
+
+        // The directories queued last go to the other half.
+        let mut queued = mem::take(&mut self.queue).into_vec();
+        queued.sort_unstable_by_key(|queued| queued.seq);
+        let queue = queued.split_off(queued.len() / 2).into();
+        self.queue = queued.into();
+        let other = IntoIter {
+            config: self.config.clone(),
+            stack: right,
+            open_budget: self.config.max_open,
+            stats: Stats::default(),
+            unverified: 0,
+            root_device: self.root_device,
+            deferred: vec![],
+            queue,
+            nr_queued: self.nr_queued,
+            queued_last: None,
+            yielded_files: self.yielded_files.clone(),
+            visited_dirs: self.visited_dirs.clone(),
+            link_targets: self.link_targets.clone(),
+            buffers: BufferPool::new(self.config.max_open),
+            denied: Vec::new(),
+            skipped: self.skipped.clone(),
+            start_after: self.start_after.clone(),
+            cancelled: self.cancelled.clone(),
+            nr_yielded: 0,
+            nr_finished: 0,
+            roots: None,
+            preopened: Vec::new(),
+            preopen_budget: 0,
+        };
+
+        self.stack = left;
+        self.open_budget = self.config.max_open;
+        Ok(other)
+    }
+
+    /// If there is pending work that `split_off` could divide between two iterators.
+    #[cfg(feature = "rayon")]
+    pub(crate) fn is_divisible(&self) -> bool {
+        if self.roots.is_some() {
+            return true;
         }
+        let mut pending = self.queue.len() + usize::from(self.queued_last.is_some());
+        for item in &self.stack {
+            match item {
+                // Likely has more entries, we can not tell without reading them.
+                WorkItem::Open(_) => return true,
+                WorkItem::Closed(closed) if closed.resume.is_some() => return true,
+                WorkItem::Closed(closed) => pending += closed.children.len(),
+            }
+        }
+        pending > 1
     }
 
-    /// Filter an entry that we got from the internal buffer.
-    /// Handles kernel errors and setup faults which mustn't occur in regular operation.
-    fn okay(entry: Result<Entry<'_>, DirentErr>) -> Entry<'_> {
-        match entry {
-            Ok(entry) => entry,
-            Err(DirentErr::TooShort) => unreachable!("Inconsistent buffer state"),
-            Err(DirentErr::InvalidLength) => unreachable!("You must have hit a kernel bug!"),
+    /// Leave the current directory after all its entries were read.
+    fn finish_item(&mut self) {
+        // The list of roots is not a directory of its own.
+        if self.stack.last().and_then(WorkItem::node).is_some() {
+            self.nr_finished += 1;
+            self.report_progress();
         }
+        self.pop_item();
     }
 
-    fn sub_entry(entry: Entry<'_>) -> Option<Entry<'_>> {
-        // Never recurse into current or parent directory.
-        match Path::new(entry.file_name()).components().next() {
-            Some(Component::CurDir) | Some(Component::ParentDir) => None,
-            _ => Some(entry),
-        }
+    /// Call the progress callback, if there is one.
+    fn report_progress(&self) {
+        let callback = match &self.config.progress {
+            Some(callback) => callback,
+            None => return,
+        };
 
+        let progress = Progress {
+            entries: self.nr_yielded,
+            dirs: self.nr_finished,
+            open: self.stack.iter().filter(|item| matches!(item, WorkItem::Open(_))).count(),
+            path: self.stack.last().and_then(WorkItem::node).map(|node| node.path()),
+        };
+        (*callback.lock().unwrap())(&progress);
     }
 
-    fn backlog(base: &Path, entry: Entry<'_>) -> Backlog {
-        Backlog {
-            file_path: base.join(entry.file_name()),
-            file_type: entry.file_type(),
+    /// Leave the current directory, it is not visited any further.
+    fn pop_item(&mut self) {
+        if let Some(WorkItem::Open(open)) = self.stack.pop() {
+            self.close_open(open);
         }
     }
-}
 
-impl DirFd {
-    fn open(path: &Path) -> io::Result<Self> {
-        let raw_name = path.as_os_str().as_bytes().to_owned();
-        let unix_name = CString::new(raw_name).expect("No interior NULL byte in Path");
+    /// Apply the policy for vanished directories to an error of opening one.
+    ///
+    /// Returns the error to yield, if any.
+    fn vanished(&mut self, err: Error) -> Option<Error> {
+        if !err.is_vanished() {
+            return Some(err);
+        }
 
-        let result = unsafe {
-            libc::open(unix_name.as_c_str().as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY)
-        };
+        match self.config.vanished {
+            Vanished::Ignore => None,
+            Vanished::YieldError => Some(err),
+            Vanished::Abort => {
+                self.abort();
+                Some(err)
+            }
+        }
+    }
 
-        if result == -1 {
-            return Err(io::Error::last_os_error());
+    /// Remember a directory that could not be opened for lack of permission, if collecting them.
+    fn collect_denied(&mut self, err: &Error) -> bool {
+        if !self.config.collect_denied || !err.is_denied() {
+            return false;
         }
 
-        Ok(DirFd(result))
+        self.denied.extend(err.path().map(Path::to_owned));
+        true
     }
 
-    fn openat(&self, path: &CStr) -> io::Result<Self> {
-        let result = unsafe {
-            libc::openat(self.0, path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY)
-        };
+    /// Forget everything that was not yet visited, ending the walk.
+    fn abort(&mut self) {
+        while !self.stack.is_empty() {
+            self.pop_item();
+        }
+        self.deferred.clear();
+        self.queue.clear();
+        self.queued_last = None;
+        self.roots = None;
+        self.release_preopened();
+        self.open_budget += mem::take(&mut self.preopen_budget);
+    }
 
-        if result == -1 {
-            return Err(io::Error::last_os_error());
+    /// If an entry is in a skipped directory, or is one itself.
+    ///
+    /// Returns `Some(true)` for the contents of a skipped directory, `Some(false)` for the
+    /// directory itself.
+    fn skipped_at(&self, entry: &DirEntry) -> Option<bool> {
+        if self.skipped.is_empty() {
+            return None;
         }
 
-        Ok(DirFd(result))
+        let path = entry.path();
+        self.skipped
+            .iter()
+            .filter(|skipped| path.starts_with(skipped))
+            .map(|skipped| path != skipped)
+            .max()
     }
 
-    fn close(self) -> io::Result<()> {
-        match unsafe { libc::close(self.0) } {
-            0 => Ok(()),
-            _ => Err(io::Error::last_os_error()),
+    /// Queue a directory, to be opened after the current level or by its priority.
+    ///
+    /// A directory that is yielded next stays out of the queue until the walk advances.
+    fn push_queued(&mut self, dir: DirEntry, yielded: bool) {
+        self.flush_queued();
+        let queued = Queued {
+            priority: self.config.priority_of(&dir),
+            seq: self.nr_queued,
+            dir,
+        };
+        self.nr_queued += 1;
+        match yielded {
+            true => self.queued_last = Some(queued),
+            false => self.queue.push(queued),
         }
     }
-}
 
-impl Closed {
-    fn from_backlog(open: &Open, children: Vec<Backlog>) -> Self {
-        Closed {
-            depth: open.depth + 1,
-            children,
-            as_parent: None,
+    /// Queue the last yielded directory for good, it can no longer be skipped.
+    fn flush_queued(&mut self) {
+        if let Some(queued) = self.queued_last.take() {
+            self.queue.push(queued);
         }
     }
 
-    fn open(&self, backlog: &DirEntry, stats: &mut Stats) -> io::Result<Open> {
-        let path = backlog.file_name.make_path();
-        stats.nr_open += 1;
-        let fd = DirFd::open(&path)?;
+    /// Take the next directory to open from the queue, the first one of the highest priority.
+    fn pop_queued(&mut self) -> Option<DirEntry> {
+        self.flush_queued();
+        self.queue.pop().map(|queued| queued.dir)
+    }
 
-        Ok(Open {
-            fd,
-            buffer: DirentBuf::with_size(1 << 14),
-            depth: self.depth + 1,
-            as_parent: Arc::new(Node {
-                depth: self.depth + 1,
-                path: EntryPath::Full(path),
-            })
-        })
+    /// All queued directories, in the order they were queued.
+    fn queued_dirs(&self) -> Vec<&DirEntry> {
+        let mut queued: Vec<_> = self.queue.iter().chain(&self.queued_last).collect();
+        queued.sort_unstable_by_key(|queued| queued.seq);
+        queued.into_iter().map(|queued| &queued.dir).collect()
     }
 
-    fn ready_entry(&mut self) -> Option<DirEntry> {
-        let backlog = self.children.pop()?;
-        Some(DirEntry {
-            file_name: EntryPath::Full(backlog.file_path),
-            file_type: FileType {
-                inner: backlog.file_type
-            },
-            depth: self.depth,
-            full_path: OnceCell::new(),
-        })
+    /// Close a directory that we are done with and return its descriptor to the budget.
+    fn close_open(&mut self, open: Open) {
+        self.open_budget += 1;
+        self.stats.closed(&open);
+        self.buffers.put(open.buffer);
+        // The helper is done with the descriptor once it filled the buffer.
+        let ahead = open.ahead.map(|ahead| ahead.wait().0);
+        for spare in ahead.into_iter().chain(open.spare) {
+            self.buffers.put(spare);
+        }
+        // Nothing that we could do about it, the directory was only read.
+        let _ = DirFd::release(open.fd);
     }
-}
 
-impl EntryPath {
-    fn make_path(&self) -> PathBuf {
-        match self {
-            EntryPath::Full(buf) => buf.clone(),
-            EntryPath::Name { name, parent } => {
-                let mut buf = parent.make_path();
-                buf.push(&name);
-                buf
+    /// Advance to the next entry accepted by the predicate, or an error to yield.
+    fn next_filtered<P>(&mut self, predicate: &mut P) -> Option<Result<DirEntry, Error>> where
+        P: FnMut(&DirEntry) -> bool,
+    {
+        loop {
+            let item = self.next_visited(predicate)?;
+            if let Some(limit) = self.config.limit_reached(self.nr_yielded) {
+                self.abort();
+                return Some(Err(Error::truncated(limit)));
+            }
+
+            let err = match item {
+                Ok(entry) => {
+                    self.nr_yielded += 1;
+                    let every = self.config.progress_every;
+                    if every > 0 && self.nr_yielded.is_multiple_of(every) {
+                        self.report_progress();
+                    }
+                    return Some(Ok(entry));
+                }
+                Err(err) => err,
+            };
+
+            if self.collect_denied(&err) {
+                continue;
+            }
+
+            match self.config.decide(&err) {
+                Decision::Ignore => continue,
+                Decision::Yield => return Some(Err(err)),
+                Decision::Abort => {
+                    self.abort();
+                    return Some(Err(err));
+                }
             }
         }
     }
-}
 
-impl Node {
-    /// Allocate a path buffer for the path described.
-    fn make_path(&self) -> PathBuf {
-        self.path.make_path()
-    }
-}
+    /// Advance to the next entry accepted by the predicate.
+    ///
+    /// A rejected directory is never opened, so nothing beneath it is visited.
+    fn next_visited<P>(&mut self, predicate: &mut P) -> Option<Result<DirEntry, Error>> where
+        P: FnMut(&DirEntry) -> bool,
+    {
+        self.flush_queued();
+        loop {
+            if self.cancelled.load(Ordering::Relaxed) {
+                self.abort();
+                return None;
+            }
 
-impl IntoIter {
-    /// See if we should descend to the newly found entry.
-    fn iter_entry(&mut self, entry: &mut DirEntry) -> Result<(), Error> {
-        let is_dir = match entry.file_type.inner {
-            Some(FileTypeInner::Directory) => true,
-            Some(_) => false,
-            None => {
-                //can we make fstatat work?
-                self.stats.nr_stat += 1;
-                let meta = std::fs::metadata(entry.file_name.make_path())
-                    .map_err(Error::from_io)?
-                    .file_type();
-                if meta.is_dir() {
-                    entry.file_type.set(FileTypeInner::Directory);
-                    true
-                } else if meta.is_file() {
-                    entry.file_type.set(FileTypeInner::File);
-                    false
-                } else if meta.is_symlink() {
-                    entry.file_type.set(FileTypeInner::SymbolicLink);
-                    false
-                } else if meta.is_block_device() {
-                    entry.file_type.set(FileTypeInner::BlockDevice);
-                    false
-                } else if meta.is_char_device() {
-                    entry.file_type.set(FileTypeInner::CharDevice);
-                    false
-                } else if meta.is_fifo() {
-                    entry.file_type.set(FileTypeInner::File);
-                    false
-                } else if meta.is_socket() {
-                    entry.file_type.set(FileTypeInner::UnixSocket);
-                    false
-                } else {
-                    false
+            let mut entry = match self.next_entry() {
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => return Some(Err(err)),
+                None => return self.finished_dir().map(Ok),
+            };
+
+            // Its directory is skipped, so are the remaining entries read from it.
+            let skipped = self.skipped_at(&entry);
+            if let Some(true) = skipped {
+                if self.stack.last().and_then(WorkItem::node).is_some() {
+                    self.pop_item();
+                }
+                continue;
+            }
+
+            // Until the start, only the directories that contain it are descended into.
+            let mut before_start = false;
+            if let Some(start) = &self.start_after {
+                match start_order(entry.path(), start) {
+                    core::cmp::Ordering::Less => continue,
+                    core::cmp::Ordering::Equal => before_start = true,
+                    // All later entries come after it as well.
+                    core::cmp::Ordering::Greater => self.start_after = None,
                 }
             }
-        };
 
-        if is_dir {
-            // TODO: filter? min_depth? max_depth?
+            let (is_dir, size_hint) = match self.classify(&mut entry) {
+                Ok(classified) => classified,
+                Err(err) => return Some(Err(err)),
+            };
 
-            let can_open = self.open_budget > 0;
-            let mut next: WorkItem = match self.stack.last().unwrap() {
-                WorkItem::Open(open) if can_open => {
-                    open.openat_os(entry.file_name(), &mut self.stats)
-                        .map_err(Error::from_io)
-                        .map(WorkItem::Open)?
+            // Entries above the minimum depth are only passed through.
+            let yielded = entry.depth >= self.config.min_depth && !before_start;
+            if self.config.excludes(&entry) || self.is_ignored(&entry) {
+                continue;
+            }
+            let accepted = self.config.yields(&entry);
+            let checked = yielded && (accepted || is_dir);
+            if checked && self.config.with_metadata {
+                if let Err(err) = self.prefetch_metadata(&entry) {
+                    return Some(Err(err));
                 }
-                WorkItem::Open(open) => {
-                    if self.config.contents_first {
-                        // TODO: close and open the actual next.
-                    } else {
-                        // TODO: add the sub directory as a closed one.
-                    }
+            }
+            if checked && !predicate(&entry) {
+                continue;
+            }
+            // Directories of other types are still descended into.
+            let yielded = yielded && accepted;
 
-                    todo!()
+            if yielded && !is_dir && self.config.dedup_hardlinks {
+                match self.is_yielded_file(&entry) {
+                    Ok(true) => continue,
+                    Ok(false) => {},
+                    Err(err) => return Some(Err(err)),
                 }
-                WorkItem::Closed(closed) => {
-                    assert!(can_open, "No more budget but only closed work items");
-                    closed.open(entry, &mut self.stats)
-                        .map_err(Error::from_io)
-                        .map(WorkItem::Open)?
+            }
+
+            // The children would be too deep, don't even open the directory.
+            let mut defer = false;
+            let is_dir = is_dir && skipped.is_none();
+            if is_dir && entry.depth < self.config.max_depth && self.config.queues_dirs() {
+                self.push_queued(entry.clone(), yielded);
+            } else if is_dir && entry.depth < self.config.max_depth {
+                match self.descend(&entry, size_hint) {
+                    Ok(pushed) => defer = pushed && self.config.contents_first,
+                    Err(err) => match self.vanished(err) {
+                        // The directory is yielded without its contents.
+                        Some(err) if self.collect_denied(&err) => {},
+                        // The error replaces the directory.
+                        Some(err) => return Some(Err(err)),
+                        None => continue,
+                    },
                 }
-            };
+            }
+
+            if !yielded {
+                continue;
+            }
 
-            if !self.config.contents_first {
-                mem::swap(&mut next, self.stack.last_mut().unwrap());
+            if defer {
+                self.deferred.push(entry);
+                continue;
             }
 
-            self.stack.push(next);
+            return Some(Ok(entry));
         }
+    }
+
+    /// Find the next entry that has not been looked at.
+    ///
+    /// Returns `None` when the walk is done or a directory is finished that was deferred. A
+    /// directory that can not be read is reported once and then abandoned.
+    fn next_entry(&mut self) -> Option<Result<DirEntry, Error>> {
+        // Finished directories are left in a loop, there may be very many in a row.
+        loop {
+            if self.has_finished_dir() {
+                return None;
+            }
+
+            // The next level of a breadth first walk.
+            if self.stack.is_empty() {
+                let dir = self.pop_queued()?;
+                if self.skipped_at(&dir).is_some() {
+                    continue;
+                }
+                if let Err(err) = self.descend(&dir, None) {
+                    match self.vanished(err) {
+                        Some(err) => return Some(Err(err)),
+                        None => continue,
+                    }
+                }
+                continue;
+            }
 
-        Ok({})
+            let config = &self.config;
+            let filter = &config.name_filter;
+
+            // First try to get an item that is ripe for reaping.
+            match self.stack.last_mut()? {
+                WorkItem::Open(open) => match open.ready_entry(filter, &mut self.stats) {
+                    Some(entry) => return Some(Ok(entry)),
+                    // Sorted directories were read completely.
+                    None if open.sorted.is_some() => self.finish_item(),
+                    // No more items, try refilling.
+                    None => match open.fill_buffer(config, &mut self.stats) {
+                        Err(err) => {
+                            let err = Error::from_node(&open.as_parent, Op::Read, err);
+                            self.skip_current_dir();
+                            return Some(Err(err));
+                        }
+                        Ok(More::More) => open.read_ahead(config, &mut self.stats),
+                        Ok(More::Blocked) => unreachable!("Empty buffer blocked"),
+                        Ok(More::Done) => self.finish_item(),
+                    },
+                }
+                WorkItem::Closed(closed) => match closed.ready_entry(config, &mut self.stats) {
+                    Some(entry) => return Some(Ok(entry)),
+                    // Continue with the entries that were not read before closing.
+                    None if closed.resume.is_some() => {
+                        if let Err(err) = self.resume() {
+                            match self.vanished(err) {
+                                Some(err) => return Some(Err(err)),
+                                None => self.pop_item(),
+                            }
+                        }
+                    }
+                    // The next root, once a helper opened it.
+                    None if closed.as_parent.is_none() && self.roots.is_some() => {
+                        self.receive_root()
+                    }
+                    // Nothing to do, try the next entry.
+                    None => self.finish_item(),
+                }
+            }
+        }
     }
 }
 
@@ -630,80 +4994,357 @@ impl IntoIterator for WalkDir {
     }
 }
 
+impl IntoIterator for &WalkDir {
+    type IntoIter = IntoIter;
+    type Item = Result<DirEntry, Error>;
+    fn into_iter(self) -> IntoIter {
+        WalkDir::build(self.clone())
+    }
+}
+
 impl Iterator for IntoIter {
     type Item = Result<DirEntry, Error>;
     fn next(&mut self) -> Option<Self::Item> {
-        let mut current = self.stack.last_mut()?;
+        self.next_filtered(&mut |_| true)
+    }
 
-        // First try to get an item that is ripe for reaping.
-        let mut found = match &mut current {
-            WorkItem::Open(open) => match open.ready_entry() {
-                Some(entry) => entry,
-                // No more items, try refilling.
-                None => {
-                    match open.fill_buffer(&mut self.stats) {
-                        Err(err) => todo!(),
-                        Ok(More::More) => return self.next(),
-                        Ok(More::Blocked) => unreachable!("Empty buffer blocked"),
-                        Ok(More::Done) => {
-                            let _ = self.stack.pop();
-                            return self.next();
-                        }
+    /// At least the entries that were read but not yet yielded.
+    ///
+    /// Counts the entries in the buffers of open directories and those of closed directories,
+    /// without reading any more of them. There is no upper bound before the walk is done.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.stack.is_empty() && self.queue.is_empty() && self.queued_last.is_none() {
+            return (self.deferred.len(), Some(self.deferred.len()));
+        }
+
+        (self.buffered_len(), None)
+    }
+
+    // Only `fold` is specialized, the `Try` bound of `try_fold` can not be named on stable.
+    fn fold<B, F>(mut self, init: B, mut f: F) -> B where
+        F: FnMut(B, Self::Item) -> B,
+    {
+        let mut acc = init;
+        loop {
+            // Most entries are taken directly from the buffer of their directory.
+            if self.in_plain_dir() {
+                let IntoIter { stack, config, stats, nr_yielded, .. } = &mut self;
+                if let Some(WorkItem::Open(open)) = stack.last_mut() {
+                    while open.next_is_plain(&config.name_filter, config.follow_links) {
+                        let entry = open.ready_entry(&config.name_filter, stats)
+                            .expect("Plain entry is ready");
+                        *nr_yielded += 1;
+                        acc = f(acc, Ok(entry));
                     }
-                },
-            }
-            WorkItem::Closed(closed) => match closed.ready_entry() {
-                Some(entry) => entry,
-                None => {
-                    // Nothing to do, try the next entry.
-                    let _ = self.stack.pop();
-                    return self.next();
                 }
             }
+
+            match self.next() {
+                Some(item) => acc = f(acc, item),
+                None => return acc,
+            }
+        }
+    }
+}
+
+impl FusedIterator for IntoIter {}
+
+// Interfaces for the parallel walker.
+
+impl WalkDir {
+    pub(crate) fn config(&self) -> &Configuration {
+        &self.config
+    }
+
+    pub(crate) fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// The device and inode of a directory, following links.
+    ///
+    /// The path is that of an entry below the base, or of a root without one.
+    pub(crate) fn identify(&self, path: &Path, base: Option<&Base>)
+        -> io::Result<(libc::dev_t, libc::ino_t)>
+    {
+        let (dir, path) = match base {
+            Some(_) => Base::locate(base, path),
+            None => {
+                let path = CString::new(path.as_os_str().as_bytes())
+                    .expect("No interior NULL byte in Path");
+                (self.config.dir(), path)
+            }
         };
+        let stat = fstatat(dir, &path, 0)?;
+        Ok((stat.st_dev, stat.st_ino))
+    }
+
+    /// A walk over one directory and its direct children, a unit of work for the parallel walk.
+    ///
+    /// The directory itself is only yielded if it is the root of the whole walk. Depths are
+    /// relative to the directory. Checking the identity of the directory is left to the caller.
+    /// Other directories are resolved like the entries below the base of their root.
+    pub(crate) fn level(&self, path: PathBuf, base: Option<Arc<Base>>, is_root: bool) -> WalkDir {
+        let mut config = self.config.clone();
+        config.min_depth = if is_root { 0 } else { 1 };
+        config.max_depth = self.config.max_depth.min(1);
+        config.contents_first = false;
+        config.same_file_system = false;
+        // The caller needs the directories for their units.
+        config.types = config.types.with(Some(FileTypeInner::Directory));
+        config.yielded_names.clear();
+        // The caller decides about the errors, for the whole walk.
+        config.on_error = None;
+        config.collect_denied = false;
+        config.max_entries = usize::MAX;
+        config.deadline = None;
+        config.progress = None;
+        config.read_ahead = None;
+        config.priority = None;
+        // Relative paths are only known to the caller, which adds the depth of the level.
+        #[cfg(feature = "globset")]
+        {
+            config.globs = GlobFilter::default();
+        }
+        if !is_root {
+            config.root_offset = None;
+            config.root_base = base;
+            config.root_dir = None;
+        }
 
-        Some(self.iter_entry(&mut found).map(|_| found))
+        WalkDir {
+            config,
+            roots: vec![path],
+            root_type: if is_root { self.root_type } else { Some(FileTypeInner::Directory) },
+        }
+    }
+
+    /// Share the yielded files and followed links with the other levels of a parallel walk.
+    pub(crate) fn share_files(mut self, files: FileIds, links: LinkTargets) -> Self {
+        self.config.shared_files = Some(files);
+        self.config.shared_links = Some(links);
+        self
+    }
+
+    /// Set the ignore files that apply to the root of a level.
+    #[cfg(feature = "ignore")]
+    pub(crate) fn root_ignores(mut self, ignores: Option<Arc<Ignores>>) -> Self {
+        if let Some(files) = &mut self.config.ignore_files {
+            files.root = ignores;
+        }
+        self
     }
 }
 
-// Private implementation items.
+impl DirEntry {
+    pub(crate) fn add_depth(&mut self, depth: usize) {
+        self.depth += depth;
+    }
 
-impl Open {
+    pub(crate) fn base(&self) -> Option<Arc<Base>> {
+        self.file_name.base().cloned()
+    }
+
+    /// The ignore files that apply to this entry.
+    #[cfg(feature = "ignore")]
+    pub(crate) fn parent_ignores(&self) -> Option<Arc<Ignores>> {
+        match &self.file_name {
+            EntryPath::Name { parent, .. } => parent.ignores.clone(),
+            _ => None,
+        }
+    }
 }
 
 impl Error {
-    fn new() -> Self {
-        Error { _private: () }
+    pub(crate) fn add_depth(&mut self, depth: usize) {
+        self.depth += depth;
+    }
+
+    /// An entry whose meta data could not be read.
+    pub(crate) fn from_stat(entry: &DirEntry, err: io::Error) -> Self {
+        Error::from_entry(entry, Op::Stat, err)
+    }
+
+    /// The marker of a walk that ended at one of its limits.
+    pub(crate) fn truncated(limit: Limit) -> Self {
+        Error {
+            depth: 0,
+            inner: ErrorInner::Truncated(limit),
+        }
     }
 
+    /// A directory that is one of its own ancestors.
+    pub(crate) fn file_system_loop(child: PathBuf, ancestor: PathBuf, depth: usize) -> Self {
+        Error {
+            depth,
+            inner: ErrorInner::Loop {
+                ancestor,
+                child,
+            },
+        }
+    }
+}
+
+// Private implementation items.
+
+impl Open {
+}
+
+impl Error {
+    /// The path of the entry at which the error occurred, if known.
     pub fn path(&self) -> Option<&Path> {
-        todo!()
+        match &self.inner {
+            ErrorInner::Io { path, .. } => path.as_deref(),
+            ErrorInner::Loop { child, .. } => Some(child),
+            ErrorInner::Truncated(_) => None,
+        }
     }
 
+    /// The ancestor that a followed link leads back to, if this error is a loop.
     pub fn loop_ancestor(&self) -> Option<&Path> {
-        todo!()
+        match &self.inner {
+            ErrorInner::Loop { ancestor, .. } => Some(ancestor),
+            ErrorInner::Io { .. } | ErrorInner::Truncated(_) => None,
+        }
+    }
+
+    /// If the walk ended early at its [`max_entries`](WalkDir::max_entries) or
+    /// [`deadline`](WalkDir::deadline), instead of yielding the remaining entries.
+    pub fn is_truncated(&self) -> bool {
+        matches!(self.inner, ErrorInner::Truncated(_))
     }
 
+    /// The depth of the entry at which the error occurred, the root has depth `0`.
     pub fn depth(&self) -> usize {
-        todo!()
+        self.depth
+    }
+
+    /// If a directory could not be opened because it no longer exists.
+    pub(crate) fn is_vanished(&self) -> bool {
+        match &self.inner {
+            ErrorInner::Io { op: Op::Open, err, .. } => {
+                matches!(err.raw_os_error(), Some(libc::ENOENT) | Some(libc::ESTALE))
+            }
+            _ => false,
+        }
+    }
+
+    /// If a directory could not be opened for lack of permission.
+    pub(crate) fn is_denied(&self) -> bool {
+        match &self.inner {
+            ErrorInner::Io { op: Op::Open, err, .. } => err.raw_os_error() == Some(libc::EACCES),
+            _ => false,
+        }
     }
 
+    /// The underlying error, unless this error is a loop or a truncated walk.
     pub fn io_error(&self) -> Option<&std::io::Error> {
-        todo!()
+        match &self.inner {
+            ErrorInner::Io { err, .. } => Some(err),
+            ErrorInner::Loop { .. } | ErrorInner::Truncated(_) => None,
+        }
+    }
+
+    /// Convert into the underlying error, unless this error is a loop or a truncated walk.
+    pub fn into_io_error(self) -> Option<std::io::Error> {
+        match self.inner {
+            ErrorInner::Io { err, .. } => Some(err),
+            ErrorInner::Loop { .. } | ErrorInner::Truncated(_) => None,
+        }
+    }
+
+    fn from_entry(entry: &DirEntry, op: Op, err: io::Error) -> Self {
+        Error {
+            depth: entry.depth,
+            inner: ErrorInner::Io {
+                path: Some(entry.path().to_owned()),
+                op,
+                err,
+            },
+        }
+    }
+
+    /// An error while working on an opened directory.
+    fn from_node(node: &Node, op: Op, err: io::Error) -> Self {
+        Error {
+            // Nodes have the depth of their entries.
+            depth: node.depth - 1,
+            inner: ErrorInner::Io {
+                path: Some(node.make_path()),
+                op,
+                err,
+            },
+        }
+    }
+
+    fn from_loop(entry: &DirEntry, ancestor: PathBuf) -> Self {
+        Error::file_system_loop(entry.path().to_owned(), ancestor, entry.depth)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.inner {
+            ErrorInner::Io { path: Some(path), op, err } => {
+                write!(f, "failed to {} {}: {}", op.as_str(), path.display(), err)
+            }
+            ErrorInner::Io { path: None, op, err } => {
+                write!(f, "failed to {}: {}", op.as_str(), err)
+            }
+            ErrorInner::Loop { ancestor, child } => write!(
+                f,
+                "file system loop found: {} points to an ancestor {}",
+                child.display(),
+                ancestor.display(),
+            ),
+            ErrorInner::Truncated(Limit::Entries(n)) => {
+                write!(f, "walk truncated after {} entries", n)
+            }
+            ErrorInner::Truncated(Limit::Deadline) => write!(f, "walk truncated at its deadline"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.inner {
+            ErrorInner::Io { err, .. } => Some(err),
+            ErrorInner::Loop { .. } | ErrorInner::Truncated(_) => None,
+        }
     }
+}
 
-    pub fn into_io_error(&self) -> Option<std::io::Error> {
-        todo!()
+impl From<Error> for io::Error {
+    /// Keeps the underlying error if there is one, otherwise wraps the whole error.
+    fn from(err: Error) -> io::Error {
+        match err.inner {
+            ErrorInner::Io { err, .. } => err,
+            inner @ ErrorInner::Loop { .. } | inner @ ErrorInner::Truncated(_) => {
+                let err = Error { depth: err.depth, inner };
+                io::Error::other(err)
+            }
+        }
     }
+}
 
-    fn from_io(_: io::Error) -> Self {
-        Error::new()
+impl Op {
+    fn as_str(self) -> &'static str {
+        match self {
+            Op::Open => "open",
+            Op::Read => "read",
+            Op::Stat => "stat",
+        }
     }
 }
 
-impl<P> Iterator for FilterEntry<IntoIter, P> {
+impl<P> Iterator for FilterEntry<IntoIter, P> where
+    P: FnMut(&DirEntry) -> bool,
+{
     type Item = Result<DirEntry, Error>;
     fn next(&mut self) -> Option<Self::Item> {
-        unimplemented!()
+        self.it.next_filtered(&mut self.predicate)
     }
 }
+
+impl<P> FusedIterator for FilterEntry<IntoIter, P> where
+    P: FnMut(&DirEntry) -> bool,
+{}